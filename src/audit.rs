@@ -0,0 +1,47 @@
+use crate::{onion_service::OnionService, util::create_onion_service_dir};
+use anyhow::Result;
+use chrono::{SecondsFormat, Utc};
+use std::fs::{set_permissions, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Append-only log of security-relevant events, such as rejected connections
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Open (or create) the audit log at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            OpenOptions::new().create(true).append(true).open(path)?;
+            set_permissions(path, Permissions::from_mode(0o600))?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Open the audit log under the data directory for this onion service
+    pub fn for_onion_service(onion_service: &OnionService) -> Result<Self> {
+        let dir = create_onion_service_dir(onion_service.name())?;
+        Self::open(&Path::new(&dir).join("audit.log"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a timestamped entry to the log
+    pub fn record(&self, entry: &str) -> Result<()> {
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(
+            file,
+            "{} {}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            entry
+        )?;
+        Ok(())
+    }
+}
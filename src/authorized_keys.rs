@@ -0,0 +1,156 @@
+use crate::{
+    onion_service::OnionService,
+    util::{create_onion_service_dir, parse_service_id},
+};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use tor_client_lib::TorServiceId;
+
+/// Options for a key in the authorized keys file
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct KeyOptions {
+    /// Accept connections without asking the application (`auto-accept`). Otherwise, the
+    /// application has to confirm the connection (`require-confirmation`, the default).
+    pub auto_accept: bool,
+    /// Peer can connect and receive messages, but anything they send is dropped (`read-only`)
+    pub read_only: bool,
+}
+
+impl FromStr for KeyOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(options: &str) -> Result<Self> {
+        let mut key_options = KeyOptions::default();
+        for option in options.split(',') {
+            match option {
+                "auto-accept" => key_options.auto_accept = true,
+                "require-confirmation" => key_options.auto_accept = false,
+                "read-only" => key_options.read_only = true,
+                _ => return Err(anyhow!("Unknown option '{}'", option)),
+            }
+        }
+        Ok(key_options)
+    }
+}
+
+/// List of onion service IDs allowed to connect to us, in the style of SSH's
+/// `authorized_keys`. Each line is an optional comma-separated list of options, followed
+/// by an onion service ID and an optional comment:
+///
+/// ```text
+/// # Alice's laptop
+/// auto-accept <onion service ID> alice
+/// read-only,require-confirmation <onion service ID>
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct AuthorizedKeys {
+    keys: HashMap<TorServiceId, KeyOptions>,
+}
+
+impl AuthorizedKeys {
+    /// Read the authorized keys from `path`, which mustn't be writable by anyone but us
+    pub fn load(path: &Path) -> Result<Self> {
+        let mode = path.metadata()?.permissions().mode();
+        if mode & 0o022 != 0 {
+            return Err(anyhow!(
+                "Permissions on {} are too permissive!",
+                path.display()
+            ));
+        }
+        read_to_string(path)?.parse()
+    }
+
+    /// Read the `authorized_keys` file under the data directory for this onion service
+    pub fn for_onion_service(onion_service: &OnionService) -> Result<Self> {
+        let dir = create_onion_service_dir(onion_service.name())?;
+        Self::load(&Path::new(&dir).join("authorized_keys"))
+    }
+
+    /// Options for `id`, or `None` if they aren't allowed to connect
+    pub fn get(&self, id: &TorServiceId) -> Option<&KeyOptions> {
+        self.keys.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl FromStr for AuthorizedKeys {
+    type Err = anyhow::Error;
+
+    fn from_str(contents: &str) -> Result<Self> {
+        let mut keys = HashMap::new();
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let first = fields.next().unwrap_or_default();
+            let (options, id) = match parse_service_id(first) {
+                Some(id) => (KeyOptions::default(), id),
+                None => {
+                    let options = first
+                        .parse::<KeyOptions>()
+                        .map_err(|error| anyhow!("Line {}: {}", line_number + 1, error))?;
+                    match fields.next().and_then(parse_service_id) {
+                        Some(id) => (options, id),
+                        _ => {
+                            return Err(anyhow!(
+                                "Line {}: missing or invalid onion service ID",
+                                line_number + 1
+                            ))
+                        }
+                    }
+                }
+            };
+            keys.insert(id, options);
+        }
+        Ok(Self { keys })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let alice = TorServiceId::generate();
+        let bob = TorServiceId::generate();
+        let carol = TorServiceId::generate();
+        let contents = format!(
+            "# Friends\n\nauto-accept {} alice\n{}\nread-only,auto-accept {} carol\n",
+            alice, bob, carol
+        );
+        let keys = contents.parse::<AuthorizedKeys>()?;
+        assert_eq!(3, keys.len());
+        assert_eq!(
+            Some(&KeyOptions {
+                auto_accept: true,
+                read_only: false
+            }),
+            keys.get(&alice)
+        );
+        assert_eq!(Some(&KeyOptions::default()), keys.get(&bob));
+        assert!(keys.get(&carol).unwrap().read_only);
+        assert!(keys.get(&TorServiceId::generate()).is_none());
+
+        assert!(format!("sometimes {}", alice)
+            .parse::<AuthorizedKeys>()
+            .is_err());
+        assert!("auto-accept".parse::<AuthorizedKeys>().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{onion_service::OnionService, util::DATA_DIR};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs::{
+    read_dir, read_to_string, remove_dir, remove_file, rename, set_permissions, write, OpenOptions,
+    Permissions,
+};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tor_client_lib::control_connection::TorControlConnection;
+
+/// File in the onion service directory holding a burner's limits
+const BURNER_FILE: &str = "burner";
+
+/// Limits on a burner identity: a persistent onion service which is destroyed once it expires
+/// or has been started `max_sessions` times. The limits are kept alongside the service's key,
+/// and checked by `create_onion_service` each time the service is started.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Burner {
+    pub expires: Option<DateTime<Utc>>,
+    pub max_sessions: Option<u32>,
+    /// How many times the identity has been started
+    pub sessions: u32,
+}
+
+impl Burner {
+    pub fn new(expires: Option<DateTime<Utc>>, max_sessions: Option<u32>) -> Self {
+        Self {
+            expires,
+            max_sessions,
+            sessions: 0,
+        }
+    }
+
+    /// Limits for the persistent onion service `name`, or `None` if it isn't a burner
+    pub fn load(name: &str) -> Result<Option<Self>> {
+        Self::read(&onion_service_dir(name))
+    }
+
+    pub fn save(&self, name: &str) -> Result<()> {
+        self.write(&onion_service_dir(name))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= Utc::now())
+    }
+
+    /// Whether the identity has expired or used up its sessions, and should be destroyed
+    pub fn is_spent(&self) -> bool {
+        self.is_expired()
+            || self
+                .max_sessions
+                .is_some_and(|max_sessions| self.sessions >= max_sessions)
+    }
+
+    /// Count a new session for the onion service `name`
+    pub fn start_session(&mut self, name: &str) -> Result<()> {
+        if self.is_spent() {
+            return Err(anyhow!("Burner identity {} has been used up", name));
+        }
+        self.sessions += 1;
+        self.save(name)
+    }
+
+    fn read(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(BURNER_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&read_to_string(path)?)?))
+    }
+
+    // Written to a temporary file and renamed into place, so a crash can't leave the limits
+    // half-written and the identity unlimited
+    fn write(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(BURNER_FILE);
+        let tmp_path = path.with_extension("tmp");
+        write(&tmp_path, serde_json::to_string(self)?)?;
+        set_permissions(&tmp_path, Permissions::from_mode(0o600))?;
+        rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// Destroy a burner identity: its files under the data directory are overwritten and
+/// deleted, then the service is removed from Tor
+pub async fn burn(
+    control_connection: &mut TorControlConnection,
+    onion_service: &OnionService,
+) -> Result<()> {
+    // Get rid of the key first, so it's gone even if Tor doesn't cooperate
+    wipe(onion_service.name())?;
+    if let Err(error) = control_connection
+        .delete_onion_service(onion_service.service_id().as_str())
+        .await
+    {
+        return Err(anyhow!("Error removing onion service: {}", error));
+    }
+    Ok(())
+}
+
+/// Overwrite and delete the files for the persistent onion service `name`
+pub(crate) fn wipe(name: &str) -> Result<()> {
+    wipe_dir(&onion_service_dir(name))
+}
+
+fn onion_service_dir(name: &str) -> PathBuf {
+    Path::new(&*DATA_DIR).join(name)
+}
+
+// Overwrite each file with random data before removing it, so the key can't be recovered
+// from the disk. Journalling and copy-on-write filesystems may still keep old copies.
+fn wipe_dir(dir: &Path) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            wipe_dir(&path)?;
+            continue;
+        }
+        let length = path.metadata()?.len() as usize;
+        let mut noise = vec![0u8; length];
+        rand::thread_rng().fill_bytes(&mut noise);
+        let mut file = OpenOptions::new().write(true).open(&path)?;
+        file.write_all(&noise)?;
+        file.sync_all()?;
+        remove_file(&path)?;
+    }
+    remove_dir(dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chrono::Duration;
+
+    #[test]
+    fn test_burner() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let service_dir = dir.path().join("burner-service");
+        std::fs::create_dir(&service_dir)?;
+        std::fs::write(service_dir.join("ed25519_secret_key"), [7u8; 64])?;
+
+        assert_eq!(None, Burner::read(&service_dir)?);
+        let mut burner = Burner::new(None, Some(2));
+        burner.sessions = 1;
+        burner.write(&service_dir)?;
+        assert!(!service_dir.join(BURNER_FILE).with_extension("tmp").exists());
+        let mut burner = Burner::read(&service_dir)?.unwrap();
+        assert!(!burner.is_spent());
+        burner.sessions += 1;
+        assert!(burner.is_spent());
+
+        let burner = Burner::new(Some(Utc::now() - Duration::seconds(1)), None);
+        assert!(burner.is_expired());
+        assert!(!Burner::new(Some(Utc::now() + Duration::hours(1)), None).is_spent());
+
+        wipe_dir(&service_dir)?;
+        assert!(!service_dir.exists());
+
+        Ok(())
+    }
+}
@@ -1,12 +1,52 @@
-use chrono::{serde::ts_seconds, DateTime, SubsecRound, Utc};
+use crate::clock::{self, HybridTimestamp};
+use crate::group::GroupId;
+use crate::history::HistoryStore;
+use anyhow::{anyhow, Result};
+use chrono::{
+    serde::{ts_seconds, ts_seconds_option},
+    DateTime, SubsecRound, Utc,
+};
 use circular_queue::CircularQueue;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
 use tor_client_lib::TorServiceId;
 
+/// Unique identifier for a chat message, used for delivery acknowledgements and deduplication
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub struct MessageId([u8; 16]);
+
+impl MessageId {
+    pub fn generate() -> Self {
+        Self(rand::thread_rng().gen())
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct ChatMessage {
+    pub id: MessageId,
+    /// Sender's clock time when the message was sent (as claimed by the sender)
     #[serde(with = "ts_seconds")]
     pub date: DateTime<Utc>,
+    /// Hybrid logical clock timestamp, used to order messages. For messages we've received,
+    /// this is when we received them.
+    pub timestamp: HybridTimestamp,
+    /// Our clock time when we received the message. Not set for messages we sent.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "ts_seconds_option"
+    )]
+    pub received: Option<DateTime<Utc>>,
     pub sender: TorServiceId,
     pub recipient: TorServiceId,
     pub message: String,
@@ -15,8 +55,11 @@ pub struct ChatMessage {
 impl ChatMessage {
     pub fn new(sender: &TorServiceId, recipient: &TorServiceId, message: String) -> ChatMessage {
         ChatMessage {
+            id: MessageId::generate(),
             // Current DateTime rounded to second
             date: Utc::now().round_subsecs(0),
+            timestamp: clock::now(),
+            received: None,
             sender: sender.clone(),
             recipient: recipient.clone(),
             message,
@@ -24,26 +67,171 @@ impl ChatMessage {
     }
 }
 
+/// Disappearing-message timer for a conversation, which both sides agree on. When the two
+/// sides disagree, the most recently set timer wins.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ConversationTtl {
+    /// How long messages are kept, or `None` to keep them
+    pub ttl: Option<Duration>,
+    #[serde(with = "ts_seconds")]
+    pub set_at: DateTime<Utc>,
+}
+
+impl ConversationTtl {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            set_at: Utc::now().round_subsecs(0),
+        }
+    }
+
+    /// Whether this timer should replace `other`. Ties go to the shorter timer.
+    pub fn supersedes(&self, other: &ConversationTtl) -> bool {
+        match self.set_at.cmp(&other.set_at) {
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Less => false,
+            std::cmp::Ordering::Equal => match (self.ttl, other.ttl) {
+                (Some(ttl), Some(other_ttl)) => ttl < other_ttl,
+                (Some(_), None) => true,
+                (None, _) => false,
+            },
+        }
+    }
+}
+
+/// What a chat is with - either a single contact, or a group
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum ChatId {
+    Direct(TorServiceId),
+    Group(GroupId),
+}
+
+impl fmt::Display for ChatId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direct(id) => write!(f, "{}", id),
+            Self::Group(id) => write!(f, "{}", id),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Chat {
-    id: TorServiceId,
+    id: ChatId,
     messages: CircularQueue<ChatMessage>,
 }
 
+/// Default number of messages kept in memory for each chat
+const DEFAULT_CHAT_CAPACITY: usize = 200;
+
 impl Chat {
     pub fn new(id: &TorServiceId) -> Self {
+        Self::with_capacity(id, DEFAULT_CHAT_CAPACITY)
+    }
+
+    pub fn with_capacity(id: &TorServiceId, capacity: usize) -> Self {
+        Self {
+            id: ChatId::Direct(id.clone()),
+            messages: CircularQueue::with_capacity(capacity),
+        }
+    }
+
+    pub fn new_group(id: &GroupId) -> Self {
         Self {
-            id: id.clone(),
-            messages: CircularQueue::with_capacity(200), // TODO: Configure this
+            id: ChatId::Group(*id),
+            messages: CircularQueue::with_capacity(DEFAULT_CHAT_CAPACITY),
         }
     }
 
+    /// Add a message, keeping the messages in causal order
     pub fn add_message(&mut self, message: ChatMessage) {
-        self.messages.push(message);
+        let in_order = self
+            .messages
+            .iter()
+            .next()
+            .is_none_or(|newest| newest.timestamp <= message.timestamp);
+        if in_order {
+            self.messages.push(message);
+        } else {
+            let mut messages = self
+                .messages
+                .asc_iter()
+                .cloned()
+                .collect::<Vec<ChatMessage>>();
+            let index =
+                messages.partition_point(|existing| existing.timestamp <= message.timestamp);
+            messages.insert(index, message);
+            self.messages = CircularQueue::with_capacity(self.messages.capacity());
+            for message in messages {
+                self.messages.push(message);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Remove all messages sent before `cutoff`
+    pub fn expire(&mut self, cutoff: DateTime<Utc>) {
+        if self.messages.iter().all(|message| message.date >= cutoff) {
+            return;
+        }
+        let mut messages = CircularQueue::with_capacity(self.messages.capacity());
+        for message in self.messages.asc_iter() {
+            if message.date >= cutoff {
+                messages.push(message.clone());
+            }
+        }
+        self.messages = messages;
+    }
+
+    /// Load up to `count` messages older than the ones currently in the chat from the
+    /// history store, growing the chat to hold them. Returns the number of messages loaded.
+    /// Group chats aren't stored in the history, so nothing is loaded for them.
+    pub fn load_history(&mut self, history: &HistoryStore, count: usize) -> Result<usize> {
+        let id = match &self.id {
+            ChatId::Direct(id) => id,
+            ChatId::Group(_) => return Ok(0),
+        };
+        let older = history.load_page(id, self.messages.len(), count)?;
+        if older.is_empty() {
+            return Ok(0);
+        }
+        let mut messages = CircularQueue::with_capacity(self.messages.capacity() + older.len());
+        for message in older.iter().chain(self.messages.asc_iter()) {
+            messages.push(message.clone());
+        }
+        self.messages = messages;
+
+        Ok(older.len())
     }
 
     pub fn id(&self) -> String {
-        self.id.as_str().to_string()
+        self.id.to_string()
+    }
+
+    pub fn chat_id(&self) -> &ChatId {
+        &self.id
+    }
+
+    /// ID of the contact, for chats with a single contact
+    pub fn service_id(&self) -> Option<&TorServiceId> {
+        match &self.id {
+            ChatId::Direct(id) => Some(id),
+            ChatId::Group(_) => None,
+        }
+    }
+
+    pub fn group_id(&self) -> Option<&GroupId> {
+        match &self.id {
+            ChatId::Group(id) => Some(id),
+            ChatId::Direct(_) => None,
+        }
     }
 
     pub fn iter(&self) -> Box<dyn Iterator<Item = &ChatMessage> + '_> {
@@ -51,23 +239,177 @@ impl Chat {
     }
 }
 
+/// Per-chat settings kept by the UI
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChatSettings {
+    /// Pinned chats sort before all others
+    pub pinned: bool,
+    /// Muted chats still count unread messages, but aren't included in `ChatList::total_unread`
+    pub muted: bool,
+    /// Archived chats sort after all others
+    pub archived: bool,
+    /// Disappearing-message timer for the chat. Set with `ChatList::set_ttl`, which says who
+    /// the engine has to tell about it.
+    ttl: Option<Duration>,
+}
+
+impl ChatSettings {
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+}
+
+/// State of a chat in the chat list
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChatEntry {
+    pub unread: usize,
+    pub last_activity: Option<DateTime<Utc>>,
+    pub settings: ChatSettings,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChatListEvent {
+    UnreadChanged { id: ChatId, unread: usize },
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct ChatList {
-    list: Vec<TorServiceId>,
+    list: Vec<ChatId>,
+    entries: HashMap<ChatId, ChatEntry>,
     current_index: Option<usize>,
+    events: Vec<ChatListEvent>,
 }
 
 impl ChatList {
-    pub fn names(&self) -> &Vec<TorServiceId> {
+    pub fn names(&self) -> &Vec<ChatId> {
         &self.list
     }
 
-    pub fn add(&mut self, id: &TorServiceId) {
+    pub fn add(&mut self, id: &ChatId) {
         self.list.push(id.clone());
+        self.entries.entry(id.clone()).or_default();
         self.current_index = Some(self.list.len() - 1);
     }
 
-    pub fn remove(&mut self, id: &TorServiceId) {
+    pub fn entry(&self, id: &ChatId) -> Option<&ChatEntry> {
+        self.entries.get(id)
+    }
+
+    pub fn settings_mut(&mut self, id: &ChatId) -> Option<&mut ChatSettings> {
+        self.entries.get_mut(id).map(|entry| &mut entry.settings)
+    }
+
+    /// Set the disappearing-message timer for a chat. Returns the peer, who should be given
+    /// the same timer with `Engine::set_conversation_ttl`, so that the history is expired and
+    /// the peer is told. Group chats don't have timers.
+    pub fn set_ttl(&mut self, id: &ChatId, ttl: Option<Duration>) -> Result<TorServiceId> {
+        let entry = match self.entries.get_mut(id) {
+            Some(entry) => entry,
+            None => return Err(anyhow!("Unknown chat {}", id)),
+        };
+        let peer = match id {
+            ChatId::Direct(service_id) => service_id.clone(),
+            ChatId::Group(_) => return Err(anyhow!("Group chats don't have message timers")),
+        };
+        entry.settings.ttl = ttl;
+        Ok(peer)
+    }
+
+    /// Record a timer change which came from the engine, e.g. `NetworkEvent::TtlChanged`
+    pub fn ttl_changed(&mut self, id: &ChatId, ttl: Option<Duration>) {
+        if let Some(entry) = self.entries.get_mut(id) {
+            entry.settings.ttl = ttl;
+        }
+    }
+
+    pub fn unread(&self, id: &ChatId) -> usize {
+        self.entries.get(id).map_or(0, |entry| entry.unread)
+    }
+
+    /// Number of unread messages in all chats which aren't muted
+    pub fn total_unread(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| !entry.settings.muted)
+            .map(|entry| entry.unread)
+            .sum()
+    }
+
+    /// Record a message we received in chat `id`. It counts as unread unless its chat is
+    /// the current one. The chat should come from the engine's event (the connection or group
+    /// it arrived on), not the message's `sender`, which the peer can set to anything.
+    pub fn message_received(&mut self, id: &ChatId, message: &ChatMessage) {
+        let is_current = self.current() == Some(id);
+        let entry = match self.entries.get_mut(id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        entry.last_activity = Some(message.received.unwrap_or(message.date));
+        if !is_current {
+            entry.unread += 1;
+            self.events.push(ChatListEvent::UnreadChanged {
+                id: id.clone(),
+                unread: entry.unread,
+            });
+        }
+    }
+
+    /// Record a message we sent in chat `id`
+    pub fn message_sent(&mut self, id: &ChatId, message: &ChatMessage) {
+        if let Some(entry) = self.entries.get_mut(id) {
+            entry.last_activity = Some(message.date);
+        }
+    }
+
+    pub fn mark_read(&mut self, id: &ChatId) {
+        if let Some(entry) = self.entries.get_mut(id) {
+            if entry.unread > 0 {
+                entry.unread = 0;
+                self.events.push(ChatListEvent::UnreadChanged {
+                    id: id.clone(),
+                    unread: 0,
+                });
+            }
+        }
+    }
+
+    /// Events which have happened since the last call
+    pub fn take_events(&mut self) -> Vec<ChatListEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Sort the list with pinned chats first and archived chats last, and the most recently
+    /// active chats first within those. The current chat stays current.
+    pub fn sort_by_activity(&mut self) {
+        let current = self.current().cloned();
+        let entries = &self.entries;
+        self.list.sort_by_key(|id| {
+            let entry = entries.get(id);
+            let settings = entry.map(|entry| &entry.settings);
+            (
+                !settings.is_some_and(|settings| settings.pinned),
+                settings.is_some_and(|settings| settings.archived),
+                Reverse(entry.and_then(|entry| entry.last_activity)),
+            )
+        });
+        self.current_index =
+            current.and_then(|current| self.list.iter().position(|id| *id == current));
+    }
+
+    /// Make `id` the current chat, marking it read
+    pub fn set_current(&mut self, id: &ChatId) -> bool {
+        match self.list.iter().position(|existing| existing == id) {
+            Some(index) => {
+                self.current_index = Some(index);
+                self.mark_read(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: &ChatId) {
+        self.entries.remove(id);
         if let Some(index) = self.list.iter().position(|t| t == id) {
             self.list.swap_remove(index);
             if self.list.is_empty() {
@@ -91,7 +433,7 @@ impl ChatList {
         }
     }
 
-    pub fn current(&self) -> Option<&TorServiceId> {
+    pub fn current(&self) -> Option<&ChatId> {
         match self.current_index {
             Some(index) => self.list.get(index),
             None => None,
@@ -102,7 +444,7 @@ impl ChatList {
         self.current_index
     }
 
-    pub fn next_chat(&mut self) -> Option<&TorServiceId> {
+    pub fn next_chat(&mut self) -> Option<&ChatId> {
         match self.current_index {
             Some(index) => {
                 if index == self.list.len() - 1 {
@@ -110,13 +452,14 @@ impl ChatList {
                 } else {
                     self.current_index = Some(index + 1);
                 }
+                self.mark_current_read();
                 self.current()
             }
             None => None,
         }
     }
 
-    pub fn prev_chat(&mut self) -> Option<&TorServiceId> {
+    pub fn prev_chat(&mut self) -> Option<&ChatId> {
         match self.current_index {
             Some(index) => {
                 if index == 0 {
@@ -124,9 +467,98 @@ impl ChatList {
                 } else {
                     self.current_index = Some(index - 1);
                 }
+                self.mark_current_read();
                 self.current()
             }
             None => None,
         }
     }
+
+    fn mark_current_read(&mut self) {
+        if let Some(id) = self.current().cloned() {
+            self.mark_read(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unread() {
+        let me = TorServiceId::generate();
+        let alice = ChatId::Direct(TorServiceId::generate());
+        let bob = ChatId::Direct(TorServiceId::generate());
+        let sender = TorServiceId::generate();
+        let mut list = ChatList::default();
+        list.add(&alice);
+        list.add(&bob);
+
+        list.message_received(&alice, &ChatMessage::new(&sender, &me, "Hi".to_string()));
+        list.message_received(&bob, &ChatMessage::new(&sender, &me, "Hi".to_string()));
+        assert_eq!(1, list.unread(&alice));
+        assert_eq!(0, list.unread(&bob));
+        assert_eq!(
+            vec![ChatListEvent::UnreadChanged {
+                id: alice.clone(),
+                unread: 1
+            }],
+            list.take_events()
+        );
+
+        list.settings_mut(&alice).unwrap().muted = true;
+        assert_eq!(0, list.total_unread());
+
+        assert_eq!(Some(&alice), list.next_chat());
+        assert_eq!(0, list.unread(&alice));
+        assert_eq!(
+            vec![ChatListEvent::UnreadChanged {
+                id: alice.clone(),
+                unread: 0
+            }],
+            list.take_events()
+        );
+    }
+
+    #[test]
+    fn test_sort_by_activity() {
+        let me = TorServiceId::generate();
+        let alice = ChatId::Direct(TorServiceId::generate());
+        let bob = ChatId::Direct(TorServiceId::generate());
+        let carol = ChatId::Group(GroupId::generate());
+        let mut list = ChatList::default();
+        list.add(&alice);
+        list.add(&bob);
+        list.add(&carol);
+
+        let mut message = ChatMessage::new(&me, &me, "Hi".to_string());
+        message.date -= chrono::Duration::minutes(1);
+        list.message_sent(&bob, &message);
+        list.message_sent(&alice, &ChatMessage::new(&me, &me, "Hi".to_string()));
+        list.settings_mut(&carol).unwrap().pinned = true;
+
+        list.sort_by_activity();
+        assert_eq!(&vec![carol.clone(), alice, bob], list.names());
+        assert_eq!(Some(&carol), list.current());
+    }
+
+    #[test]
+    fn test_set_ttl() -> Result<()> {
+        let peer = TorServiceId::generate();
+        let direct = ChatId::Direct(peer.clone());
+        let group = ChatId::Group(GroupId::generate());
+        let mut list = ChatList::default();
+        list.add(&direct);
+        list.add(&group);
+
+        let ttl = Some(Duration::from_secs(60));
+        assert_eq!(peer, list.set_ttl(&direct, ttl)?);
+        assert_eq!(ttl, list.entry(&direct).unwrap().settings.ttl());
+
+        assert!(list.set_ttl(&group, ttl).is_err());
+        assert_eq!(None, list.entry(&group).unwrap().settings.ttl());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,375 @@
+use crate::{
+    config::TorAuthConfig, control_connection::prompt_for_password, onion_service::OnionService,
+};
+use anyhow::{anyhow, Result};
+use base32::Alphabet;
+use ed25519_dalek::pkcs8::spki::der::zeroize::Zeroize;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::read;
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpStream, ToSocketAddrs,
+};
+use tor_client_lib::{
+    base64,
+    control_connection::{OnionService as TorClientOnionService, OnionServiceMapping},
+    TorEd25519SigningKey, TorServiceId,
+};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// Tor writes client authorization keys as unpadded base32
+const BASE32: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// The private half of a client authorization key for a private onion service. Whoever
+/// holds it can reach the service, so it's only given to the contact it was made for.
+#[derive(Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ClientAuthKey([u8; 32]);
+
+impl ClientAuthKey {
+    pub fn generate() -> Self {
+        Self(rand::thread_rng().gen())
+    }
+
+    /// The public half, which the onion service registers
+    pub fn public_key(&self) -> ClientAuthPublicKey {
+        ClientAuthPublicKey(x25519(self.0, X25519_BASEPOINT_BYTES))
+    }
+
+    /// Base32 encoding, as used in Tor's `.auth_private` files
+    pub fn to_base32(&self) -> String {
+        base32::encode(BASE32, &self.0)
+    }
+
+    pub fn from_base32(encoded: &str) -> Result<Self> {
+        Ok(Self(decode_key(encoded)?))
+    }
+}
+
+impl fmt::Debug for ClientAuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientAuthKey")
+            .field("public_key", &self.public_key())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for ClientAuthKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// The public half of a client authorization key
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+pub struct ClientAuthPublicKey([u8; 32]);
+
+impl fmt::Display for ClientAuthPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", base32::encode(BASE32, &self.0))
+    }
+}
+
+impl FromStr for ClientAuthPublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self(decode_key(s)?))
+    }
+}
+
+fn decode_key(encoded: &str) -> Result<[u8; 32]> {
+    match base32::decode(BASE32, encoded.trim()) {
+        Some(bytes) => bytes
+            .try_into()
+            .map_err(|_| anyhow!("Client authorization keys are 32 bytes")),
+        None => Err(anyhow!("Invalid base32 in client authorization key")),
+    }
+}
+
+/// A connection to the Tor control port for the client authorization commands that
+/// `tor_client_lib` doesn't support.
+///
+/// Onion services added through this connection which aren't persistent are removed by Tor
+/// when it closes, so it should be kept open for as long as they're needed.
+pub struct ClientAuthConnection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    detached: HashSet<TorServiceId>,
+    /// Clients authorized for each of the services we've added
+    clients: HashMap<TorServiceId, Vec<ClientAuthPublicKey>>,
+}
+
+impl ClientAuthConnection {
+    pub async fn connect<A: ToSocketAddrs>(
+        control_address: A,
+        authentication: Option<TorAuthConfig>,
+        hashed_password: Option<String>,
+        cookie: Option<Vec<u8>>,
+    ) -> Result<Self> {
+        let (reader, writer) = match TcpStream::connect(control_address).await {
+            Ok(stream) => stream.into_split(),
+            Err(error) => {
+                return Err(anyhow!(
+                    "Error connecting to Tor control connection: {}",
+                    error
+                ));
+            }
+        };
+        let mut connection = Self {
+            reader: BufReader::new(reader),
+            writer,
+            detached: HashSet::new(),
+            clients: HashMap::new(),
+        };
+
+        let credentials = match authentication {
+            Some(TorAuthConfig::HashedPassword) => {
+                let password = match hashed_password {
+                    Some(password) => password,
+                    None => prompt_for_password()?,
+                };
+                format!(" {}", quote(&password))
+            }
+            // We use plain cookie authentication, since we already trust the control port
+            // enough to hand it our keys
+            Some(TorAuthConfig::SafeCookie) => {
+                let cookie = match cookie {
+                    Some(cookie) => cookie,
+                    None => connection.read_cookie().await?,
+                };
+                format!(" {}", hex::encode(cookie))
+            }
+            None => String::new(),
+        };
+        if let Err(error) = connection
+            .command(&format!("AUTHENTICATE{}", credentials))
+            .await
+        {
+            return Err(anyhow!(
+                "Error authenticating to Tor control connection: {}",
+                error
+            ));
+        }
+
+        Ok(connection)
+    }
+
+    /// Add an onion service which only the holders of `clients` can connect to. If no key
+    /// is given, Tor generates one.
+    pub async fn add_onion_service(
+        &mut self,
+        signing_key: Option<&TorEd25519SigningKey>,
+        ports: &[OnionServiceMapping],
+        persistent: bool,
+        clients: &[ClientAuthPublicKey],
+    ) -> Result<TorClientOnionService> {
+        if clients.is_empty() {
+            return Err(anyhow!(
+                "A private onion service needs at least one authorized client"
+            ));
+        }
+        let mut request = match signing_key {
+            Some(signing_key) => format!("ADD_ONION ED25519-V3:{}", signing_key.to_blob()),
+            None => "ADD_ONION NEW:ED25519-V3".to_string(),
+        };
+        request.push_str(if persistent {
+            " Flags=Detach,V3Auth"
+        } else {
+            " Flags=V3Auth"
+        });
+        for port in ports {
+            request.push_str(&format!(
+                " Port={},{}",
+                port.virt_port(),
+                port.listen_address()
+            ));
+        }
+        for client in clients {
+            request.push_str(&format!(" ClientAuthV3={}", client));
+        }
+
+        let mut service_id_returned = None;
+        let mut returned_key = None;
+        for line in self.command(&request).await? {
+            if let Some(id) = line.strip_prefix("ServiceID=") {
+                service_id_returned = Some(id.to_string());
+            } else if let Some(blob) = line.strip_prefix("PrivateKey=ED25519-V3:") {
+                returned_key = Some(TorEd25519SigningKey::from_blob(blob));
+            }
+        }
+        let signing_key = match (signing_key, returned_key) {
+            (Some(signing_key), _) => signing_key.clone(),
+            (None, Some(signing_key)) => signing_key,
+            (None, None) => return Err(anyhow!("Tor didn't return the onion service key")),
+        };
+        let service_id: TorServiceId = signing_key.verifying_key().into();
+        if service_id_returned.as_deref() != Some(service_id.as_str()) {
+            return Err(anyhow!(
+                "Service ID returned by Tor doesn't match the onion service key"
+            ));
+        }
+
+        if persistent {
+            self.detached.insert(service_id.clone());
+        }
+        self.clients.insert(service_id.clone(), clients.to_vec());
+        Ok(TorClientOnionService::new(service_id, signing_key, ports))
+    }
+
+    /// Replace the clients authorized to connect to one of our private onion services. If
+    /// Tor won't take the new clients, the service is put back with the old ones. The
+    /// service is left offline only if that fails too, or it wasn't added through this
+    /// connection, so we don't know the old clients.
+    pub async fn set_authorized_clients(
+        &mut self,
+        onion_service: &OnionService,
+        clients: &[ClientAuthPublicKey],
+    ) -> Result<()> {
+        if clients.is_empty() {
+            return Err(anyhow!(
+                "A private onion service needs at least one authorized client"
+            ));
+        }
+        // Tor can't change the clients of a running service, so we have to re-add it
+        self.command(&format!("DEL_ONION {}", onion_service.service_id()))
+            .await?;
+        let persistent = self.detached.remove(onion_service.service_id());
+        let previous = self.clients.remove(onion_service.service_id());
+        let error = match self
+            .add_onion_service(
+                Some(onion_service.signing_key()),
+                onion_service.ports(),
+                persistent,
+                clients,
+            )
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(error) => error,
+        };
+        if let Some(previous) = previous {
+            if let Err(restore_error) = self
+                .add_onion_service(
+                    Some(onion_service.signing_key()),
+                    onion_service.ports(),
+                    persistent,
+                    &previous,
+                )
+                .await
+            {
+                return Err(anyhow!(
+                    "{}, and the service couldn't be restored: {}",
+                    error,
+                    restore_error
+                ));
+            }
+        }
+        Err(error)
+    }
+
+    /// Remove an onion service, ignoring any error if Tor doesn't have it
+    pub async fn remove_onion_service(&mut self, id: &TorServiceId) {
+        let _ = self.command(&format!("DEL_ONION {}", id)).await;
+        self.detached.remove(id);
+        self.clients.remove(id);
+    }
+
+    /// Give Tor our key for someone else's private onion service, so we can connect to it
+    pub async fn add_client_auth(&mut self, id: &TorServiceId, key: &ClientAuthKey) -> Result<()> {
+        self.command(&format!(
+            "ONION_CLIENT_AUTH_ADD {} x25519:{}",
+            id,
+            base64::encode(&key.0)
+        ))
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove_client_auth(&mut self, id: &TorServiceId) -> Result<()> {
+        self.command(&format!("ONION_CLIENT_AUTH_REMOVE {}", id))
+            .await?;
+        Ok(())
+    }
+
+    // Find the cookie file from PROTOCOLINFO and read it
+    async fn read_cookie(&mut self) -> Result<Vec<u8>> {
+        for line in self.command("PROTOCOLINFO 1").await? {
+            if let Some((_, cookie_file)) = line.split_once("COOKIEFILE=") {
+                return Ok(read(cookie_file.trim_matches('"'))?);
+            }
+        }
+        Err(anyhow!("Tor didn't tell us where its cookie file is"))
+    }
+
+    /// Send a command and return the lines of the reply, without their status codes
+    async fn command(&mut self, command: &str) -> Result<Vec<String>> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if line.len() < 4 || !line.is_char_boundary(4) {
+                return Err(anyhow!("Unexpected reply from Tor: {}", line));
+            }
+            let (status, rest) = line.split_at(3);
+            if !status.starts_with('2') {
+                return Err(anyhow!("Tor returned error {}", line));
+            }
+            let (separator, reply) = rest.split_at(1);
+            lines.push(reply.to_string());
+            match separator {
+                " " => return Ok(lines),
+                // Data follows, up to a line with a single "."
+                "+" => loop {
+                    let data = self.read_line().await?;
+                    if data == "." {
+                        break;
+                    }
+                    lines.push(data);
+                },
+                _ => {}
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("Tor closed the control connection"));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+// Quote a string for the control protocol
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_keys() -> Result<()> {
+        let key = ClientAuthKey::generate();
+        let decoded = ClientAuthKey::from_base32(&key.to_base32())?;
+        assert_eq!(key.public_key(), decoded.public_key());
+
+        let public_key = key.public_key();
+        assert_eq!(52, public_key.to_string().len());
+        assert_eq!(public_key, public_key.to_string().parse()?);
+        assert!("ABCD".parse::<ClientAuthPublicKey>().is_err());
+        assert!(!format!("{:?}", key).contains(&key.to_base32()));
+
+        Ok(())
+    }
+}
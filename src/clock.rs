@@ -0,0 +1,182 @@
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// How far ahead of our own clock we let a peer's timestamp push our clock (in milliseconds)
+const MAX_DRIFT_MILLIS: i64 = 60_000;
+
+lazy_static! {
+    static ref CLOCK: Mutex<HybridClock> = Mutex::new(HybridClock::default());
+}
+
+/// Hybrid logical clock timestamp - wall clock time in milliseconds, plus a logical counter
+/// to order events which happen within the same millisecond, or which arrive from a peer
+/// whose clock is behind ours
+#[derive(
+    Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize,
+)]
+pub struct HybridTimestamp {
+    pub wall: i64,
+    pub logical: u32,
+}
+
+/// Hybrid logical clock, see <https://cse.buffalo.edu/tech-reports/2014-04.pdf>
+#[derive(Debug, Default)]
+pub struct HybridClock {
+    last: HybridTimestamp,
+}
+
+impl HybridClock {
+    /// Timestamp for a local event
+    pub fn now(&mut self) -> HybridTimestamp {
+        self.now_at(Utc::now().timestamp_millis())
+    }
+
+    /// Update the clock with a timestamp received from a peer, returning the timestamp of
+    /// the receive event
+    pub fn observe(&mut self, remote: &HybridTimestamp) -> HybridTimestamp {
+        self.observe_at(remote, Utc::now().timestamp_millis())
+    }
+
+    fn now_at(&mut self, physical: i64) -> HybridTimestamp {
+        if physical > self.last.wall {
+            self.last = HybridTimestamp {
+                wall: physical,
+                logical: 0,
+            };
+        } else {
+            self.last = self.last.next();
+        }
+        self.last
+    }
+
+    fn observe_at(&mut self, remote: &HybridTimestamp, physical: i64) -> HybridTimestamp {
+        // Don't let a peer with a fast clock drag ours into the future
+        if remote.wall > physical + MAX_DRIFT_MILLIS {
+            return self.now_at(physical);
+        }
+
+        let wall = physical.max(self.last.wall).max(remote.wall);
+        self.last = if wall == self.last.wall && wall == remote.wall {
+            self.last.max(*remote).next()
+        } else if wall == self.last.wall {
+            self.last.next()
+        } else if wall == remote.wall {
+            remote.next()
+        } else {
+            HybridTimestamp { wall, logical: 0 }
+        };
+        self.last
+    }
+}
+
+impl HybridTimestamp {
+    // The timestamp after this one. If the logical counter runs out, it carries over into
+    // the wall clock.
+    fn next(&self) -> Self {
+        match self.logical.checked_add(1) {
+            Some(logical) => Self {
+                wall: self.wall,
+                logical,
+            },
+            None => Self {
+                wall: self.wall + 1,
+                logical: 0,
+            },
+        }
+    }
+}
+
+/// Timestamp a local event using the process-wide clock
+pub fn now() -> HybridTimestamp {
+    CLOCK.lock().unwrap().now()
+}
+
+/// Update the process-wide clock with a timestamp received from a peer
+pub fn observe(remote: &HybridTimestamp) -> HybridTimestamp {
+    CLOCK.lock().unwrap().observe(remote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_events_are_ordered() {
+        let mut clock = HybridClock::default();
+        let first = clock.now_at(1000);
+        let second = clock.now_at(1000);
+        let third = clock.now_at(999);
+        let fourth = clock.now_at(1001);
+        assert!(first < second && second < third && third < fourth);
+        assert_eq!(
+            HybridTimestamp {
+                wall: 1000,
+                logical: 2
+            },
+            third
+        );
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut clock = HybridClock::default();
+        clock.now_at(1000);
+
+        // Peer is ahead of us, so anything we do afterward has to come after their event
+        let remote = HybridTimestamp {
+            wall: 5000,
+            logical: 3,
+        };
+        let received = clock.observe_at(&remote, 1001);
+        assert!(received > remote);
+        assert!(clock.now_at(1002) > remote);
+
+        // Peer is behind us
+        let remote = HybridTimestamp {
+            wall: 10,
+            logical: 0,
+        };
+        assert_eq!(
+            HybridTimestamp {
+                wall: 5000,
+                logical: 6
+            },
+            clock.observe_at(&remote, 1003)
+        );
+
+        // Peer is too far in the future, so we ignore their clock
+        let remote = HybridTimestamp {
+            wall: 1_000_000,
+            logical: 0,
+        };
+        assert_eq!(
+            HybridTimestamp {
+                wall: 5000,
+                logical: 7
+            },
+            clock.observe_at(&remote, 1004)
+        );
+    }
+
+    #[test]
+    fn test_logical_overflow() {
+        let mut clock = HybridClock::default();
+        clock.now_at(1000);
+        let remote = HybridTimestamp {
+            wall: 1000,
+            logical: u32::MAX,
+        };
+        let received = clock.observe_at(&remote, 1000);
+        assert!(received > remote);
+        assert_eq!(
+            HybridTimestamp {
+                wall: 1001,
+                logical: 0
+            },
+            received
+        );
+        assert!(clock.now_at(1000) > received);
+    }
+}
@@ -1,5 +1,7 @@
+use crate::history::RetentionPolicy;
 use crate::util::CONFIG_HOME;
 use anyhow::Result;
+use chrono::Duration;
 use clap::ValueEnum;
 use serde::Deserialize;
 use serde_with::{base64::Base64, serde_as};
@@ -13,6 +15,8 @@ use tor_client_lib::auth::TorAuthentication;
 pub struct Config {
     pub system: SystemConfig,
     pub tor: TorConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
 }
 
 impl Config {
@@ -20,6 +24,7 @@ impl Config {
         Self {
             system: self.system.update(other.system),
             tor: self.tor.update(other.tor),
+            history: self.history.update(other.history),
         }
     }
 }
@@ -48,6 +53,33 @@ impl SystemConfig {
     }
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryConfig {
+    /// Whether history is kept. Off unless a config file turns it on.
+    pub enabled: Option<bool>,
+    pub max_messages: Option<usize>,
+    pub max_age_days: Option<u32>,
+}
+
+impl HistoryConfig {
+    pub fn update(self, other: HistoryConfig) -> Self {
+        Self {
+            enabled: other.enabled.or(self.enabled),
+            max_messages: other.max_messages.or(self.max_messages),
+            max_age_days: other.max_age_days.or(self.max_age_days),
+        }
+    }
+}
+
+impl From<&HistoryConfig> for RetentionPolicy {
+    fn from(config: &HistoryConfig) -> RetentionPolicy {
+        RetentionPolicy {
+            max_messages: config.max_messages,
+            max_age: config.max_age_days.map(|days| Duration::days(days as i64)),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, ValueEnum)]
 pub enum TorAuthConfig {
     #[serde(alias = "hashed-password")]
@@ -165,4 +197,17 @@ mod tests {
         assert!(read_config_file(Some("./fixtures/bad_config.toml".to_string())).is_err());
         Ok(())
     }
+
+    #[test]
+    fn test_history_config_update() -> Result<()> {
+        let config = read_config_file(Some("./fixtures/config.toml".to_string()))?.unwrap();
+        let updated = config.history.update(HistoryConfig {
+            max_messages: Some(100),
+            ..Default::default()
+        });
+        assert_eq!(Some(true), updated.enabled);
+        assert_eq!(Some(100), updated.max_messages);
+        assert_eq!(Some(30), updated.max_age_days);
+        Ok(())
+    }
 }
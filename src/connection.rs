@@ -1,28 +1,102 @@
 use crate::{
+    chat::{ChatMessage, ConversationTtl, MessageId},
+    clock::{self, HybridTimestamp},
+    contacts::{ContactCard, ContactRequestOutcome, KeyMigration},
     crypto::{
         create_encrypted_channel, generate_auth_data, generate_session_hash, key_exchange,
-        verify_auth_message, AuthMessage, DecryptingReader, EncryptingWriter,
+        session_verification_code, verify_auth_message, AuthMessage, DecryptingReader,
+        EncryptingWriter,
     },
     engine::{ConnectionDirection, ConnectionEvent, ConnectionInfo, Engine, EngineEvent},
+    feed::FeedMessage,
+    group::GroupMessage,
+    intro::IdentityCard,
     logger::Logger,
+    outbox::DeliveryStatus,
+    pow::Challenge,
+    profile::ProfileMessage,
+    rate_limit::HandshakePermit,
+    room::RoomMessage,
+    util::{address_service_id, parse_service_id},
 };
 use anyhow::{anyhow, Result};
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
-use std::str::FromStr;
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tokio::time::timeout;
+use tokio::time::{interval, timeout, Instant, MissedTickBehavior};
 use tokio_socks::tcp::Socks5Stream;
 use tor_client_lib::{
     control_connection::{OnionServiceStream, TorSocketAddr},
     TorServiceId,
 };
 
+/// Sent by the connecting side once the handshake is done, asking to be let in
+#[derive(Debug, Serialize, Deserialize)]
+struct ContactRequestMessage {
+    introduction: String,
+}
+
+/// The answer to a contact request
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
-struct ConnectionAuthorizedMessage;
+enum ContactResponse {
+    Accepted,
+    Rejected,
+}
+
+/// Messages exchanged with the peer once the connection is authorized
+#[derive(Debug, Serialize, Deserialize)]
+enum PeerMessage {
+    Chat(Box<ChatMessage>),
+    Delivered(MessageId),
+    SetTtl(ConversationTtl),
+    /// Our clock at the start of the connection, so the peer can check for clock skew
+    Time(HybridTimestamp),
+    Ping(u64),
+    Pong(u64),
+    Typing(bool),
+    Group(GroupMessage),
+    Room(RoomMessage),
+    Feed(FeedMessage),
+    KeyMigration(Box<KeyMigration>),
+    ContactCard(Box<ContactCard>),
+    Profile(ProfileMessage),
+    /// We're destroying this identity, so won't be reachable at it again
+    Burned,
+    Identity(Box<IdentityCard>),
+}
+
+/// How long we give a peer to solve a proof-of-work puzzle
+const PROOF_OF_WORK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Keepalive settings for a connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Keepalive {
+    /// How often we ping the peer
+    pub ping_interval: Duration,
+    /// How long we wait without hearing anything from the peer before closing the connection
+    pub idle_timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+/// What we tell a peer about ourselves when we connect to them
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Introduction {
+    pub text: String,
+    /// How long we wait for them to accept the connection
+    pub timeout: Duration,
+}
 
 pub struct Connection<T: AsyncRead + AsyncWrite> {
     connection_info: ConnectionInfo,
@@ -30,6 +104,10 @@ pub struct Connection<T: AsyncRead + AsyncWrite> {
     writer: EncryptingWriter<WriteHalf<T>>,
     engine_tx: mpsc::UnboundedSender<EngineEvent>,
     rx: mpsc::UnboundedReceiver<ConnectionEvent>,
+    keepalive: Keepalive,
+    /// Whether the peer has been let in. Incoming connections aren't until the user accepts
+    /// their contact request.
+    authorized: bool,
 }
 
 impl<T: AsyncRead + AsyncWrite> Connection<T> {
@@ -39,6 +117,8 @@ impl<T: AsyncRead + AsyncWrite> Connection<T> {
         writer: EncryptingWriter<WriteHalf<T>>,
         engine_tx: mpsc::UnboundedSender<EngineEvent>,
         rx: mpsc::UnboundedReceiver<ConnectionEvent>,
+        keepalive: Keepalive,
+        authorized: bool,
     ) -> Self {
         Self {
             connection_info,
@@ -46,16 +126,103 @@ impl<T: AsyncRead + AsyncWrite> Connection<T> {
             writer,
             engine_tx,
             rx,
+            keepalive,
+            authorized,
         }
     }
 
     pub async fn handle_connection(&mut self, logger: &mut dyn Logger) {
+        let queued = if self.authorized {
+            Vec::new()
+        } else {
+            match self.wait_for_authorization(logger).await {
+                Some(queued) => queued,
+                None => return,
+            }
+        };
+        if let Err(error) = self.writer.send(&PeerMessage::Time(clock::now())).await {
+            logger.log_error(&format!("Error sending clock time: {}", error));
+        }
+        let mut ping_interval = interval(self.keepalive.ping_interval);
+        ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_received = Instant::now();
+        let mut ping_nonce = 0u64;
+        let mut ping_sent: Option<(u64, Instant)> = None;
+        for event in queued {
+            if !self.handle_connection_event(event, logger).await {
+                return;
+            }
+        }
         loop {
             tokio::select! {
-                result = self.reader.read() => {
+                result = self.reader.read::<PeerMessage>() => {
+                    if let Ok(Some(_)) = result {
+                        last_received = Instant::now();
+                    }
                     match result {
-                        Ok(Some(chat_message)) => {
-                            let _ = self.engine_tx.send(EngineEvent::Message(Box::new(chat_message)));
+                        Ok(Some(PeerMessage::Ping(nonce))) => {
+                            if let Err(error) = self.writer.send(&PeerMessage::Pong(nonce)).await {
+                                logger.log_error(&format!("Error sending pong: {}", error));
+                            }
+                        },
+                        Ok(Some(PeerMessage::Pong(nonce))) => {
+                            if let Some((sent_nonce, sent_at)) = ping_sent {
+                                if nonce == sent_nonce {
+                                    ping_sent = None;
+                                    let _ = self.engine_tx.send(EngineEvent::Latency(self.connection_info.id(), sent_at.elapsed()));
+                                }
+                            }
+                        },
+                        Ok(Some(PeerMessage::Typing(typing))) => {
+                            let _ = self.engine_tx.send(EngineEvent::Typing(self.connection_info.id(), typing));
+                        },
+                        Ok(Some(PeerMessage::Group(group_message))) => {
+                            let _ = self.engine_tx.send(EngineEvent::Group(self.connection_info.id(), group_message));
+                        },
+                        Ok(Some(PeerMessage::Room(room_message))) => {
+                            let _ = self.engine_tx.send(EngineEvent::Room(self.connection_info.id(), room_message));
+                        },
+                        Ok(Some(PeerMessage::Feed(feed_message))) => {
+                            let _ = self.engine_tx.send(EngineEvent::Feed(self.connection_info.id(), feed_message));
+                        },
+                        Ok(Some(PeerMessage::KeyMigration(migration))) => {
+                            let _ = self.engine_tx.send(EngineEvent::KeyMigration(self.connection_info.id(), migration));
+                        },
+                        Ok(Some(PeerMessage::Profile(profile_message))) => {
+                            let _ = self.engine_tx.send(EngineEvent::Profile(self.connection_info.id(), profile_message));
+                        },
+                        Ok(Some(PeerMessage::Identity(card))) => {
+                            let _ = self.engine_tx.send(EngineEvent::Identity(self.connection_info.id(), card));
+                        },
+                        Ok(Some(PeerMessage::Burned)) => {
+                            let _ = self.engine_tx.send(EngineEvent::Burned(self.connection_info.id()));
+                        },
+                        Ok(Some(PeerMessage::ContactCard(card))) => {
+                            let _ = self.engine_tx.send(EngineEvent::ContactCard(self.connection_info.id(), card));
+                        },
+                        Ok(Some(PeerMessage::Chat(chat_message))) if chat_message.sender != self.connection_info.id() => {
+                            logger.log_warning(&format!("Dropping message from {} claiming to be from {}", self.connection_info.id(), chat_message.sender));
+                        },
+                        Ok(Some(PeerMessage::Chat(chat_message))) => {
+                            if let Err(error) = self.writer.send(&PeerMessage::Delivered(chat_message.id)).await {
+                                logger.log_error(&format!("Error sending delivery receipt: {}", error));
+                            }
+                            let _ = self.engine_tx.send(EngineEvent::Message(self.connection_info.id(), chat_message));
+                        },
+                        Ok(Some(PeerMessage::Time(timestamp))) => {
+                            let offset = chrono::Duration::milliseconds(timestamp.wall - Utc::now().timestamp_millis());
+                            clock::observe(&timestamp);
+                            let _ = self.engine_tx.send(EngineEvent::ClockOffset(self.connection_info.id(), offset));
+                        },
+                        Ok(Some(PeerMessage::SetTtl(ttl))) => {
+                            let _ = self.engine_tx.send(EngineEvent::TtlChanged(self.connection_info.id(), ttl));
+                        },
+                        Ok(Some(PeerMessage::Delivered(id))) => {
+                            let _ = self.engine_tx.send(EngineEvent::DeliveryStatus {
+                                recipient: self.connection_info.id(),
+                                id,
+                                status: DeliveryStatus::Delivered,
+                            });
                         },
                         Ok(None) => {
                             let _ = self.engine_tx.send(EngineEvent::ConnectionClosed(Box::new(self.connection_info.clone())));
@@ -74,30 +241,167 @@ impl<T: AsyncRead + AsyncWrite> Connection<T> {
                 },
                 event = self.rx.recv() => {
                     if let Some(event) = event {
-                        match event {
-                            ConnectionEvent::Message(chat_message) => {
-                                if let Err(error) = self.writer.send(&chat_message).await {
-                                    logger.log_error(&format!("Error sending message: {}", error));
-                                }
-                            },
-                            ConnectionEvent::ConnectionAuthorized => {
-                                if let Err(error) = self.writer.send(&ConnectionAuthorizedMessage).await {
-                                    logger.log_error(&format!("Error sending message: {}", error));
-                                }
-                            },
-                            ConnectionEvent::CloseConnection => {
-                                logger.log_info(&format!("Disconnecting from {}", self.connection_info.id()));
-                                break;
+                        if !self.handle_connection_event(event, logger).await {
+                            break;
+                        }
+                    }
+                },
+                _ = ping_interval.tick() => {
+                    if last_received.elapsed() > self.keepalive.idle_timeout {
+                        logger.log_info(&format!("Connection to {} timed out", self.connection_info.id()));
+                        let _ = self.engine_tx.send(EngineEvent::ConnectionClosed(Box::new(self.connection_info.clone())));
+                        break;
+                    }
+                    ping_nonce += 1;
+                    ping_sent = Some((ping_nonce, Instant::now()));
+                    if let Err(error) = self.writer.send(&PeerMessage::Ping(ping_nonce)).await {
+                        logger.log_error(&format!("Error sending ping: {}", error));
+                    }
+                }
+            }
+        }
+    }
+
+    // Wait for the user to answer the peer's contact request. Anything the engine sends in
+    // the meantime is held back, since the peer won't read it until they've been answered.
+    // Returns the held back events if the peer was accepted.
+    async fn wait_for_authorization(
+        &mut self,
+        logger: &mut dyn Logger,
+    ) -> Option<Vec<ConnectionEvent>> {
+        let mut queued = Vec::new();
+        loop {
+            tokio::select! {
+                result = self.reader.read::<PeerMessage>() => match result {
+                    Ok(Some(message)) => {
+                        logger.log_debug(&format!("Ignoring {:?} from unaccepted peer", message));
+                    }
+                    // They've given up waiting
+                    Ok(None) | Err(_) => break,
+                },
+                event = self.rx.recv() => match event {
+                    Some(ConnectionEvent::ConnectionAuthorized) => {
+                        match self.writer.send(&ContactResponse::Accepted).await {
+                            Ok(()) => {
+                                self.authorized = true;
+                                return Some(queued);
                             }
-                            _ => {
-                                logger.log_error(&format!("Unexpected event received: {:?}", event));
+                            Err(error) => {
+                                logger.log_error(&format!("Error accepting contact request: {}", error));
                                 break;
                             }
                         }
                     }
+                    Some(ConnectionEvent::ContactRejected) => {
+                        if let Err(error) = self.writer.send(&ContactResponse::Rejected).await {
+                            logger.log_error(&format!("Error rejecting contact request: {}", error));
+                        }
+                        break;
+                    }
+                    Some(ConnectionEvent::CloseConnection) | None => break,
+                    Some(event) => queued.push(event),
+                },
+            }
+        }
+        let _ = self.engine_tx.send(EngineEvent::ConnectionClosed(Box::new(
+            self.connection_info.clone(),
+        )));
+        None
+    }
+
+    // Send an event from the engine on to the peer. Returns false if the connection should
+    // be closed.
+    async fn handle_connection_event(
+        &mut self,
+        event: ConnectionEvent,
+        logger: &mut dyn Logger,
+    ) -> bool {
+        match event {
+            ConnectionEvent::Message(chat_message) => {
+                let id = chat_message.id;
+                match self.writer.send(&PeerMessage::Chat(chat_message)).await {
+                    Ok(()) => {
+                        let _ = self.engine_tx.send(EngineEvent::DeliveryStatus {
+                            recipient: self.connection_info.id(),
+                            id,
+                            status: DeliveryStatus::Sent,
+                        });
+                    }
+                    Err(error) => {
+                        logger.log_error(&format!("Error sending message: {}", error));
+                    }
+                }
+            }
+            ConnectionEvent::Typing(typing) => {
+                if let Err(error) = self.writer.send(&PeerMessage::Typing(typing)).await {
+                    logger.log_error(&format!("Error sending typing notification: {}", error));
+                }
+            }
+            ConnectionEvent::Group(group_message) => {
+                if let Err(error) = self.writer.send(&PeerMessage::Group(group_message)).await {
+                    logger.log_error(&format!("Error sending group message: {}", error));
                 }
             }
+            ConnectionEvent::Room(room_message) => {
+                if let Err(error) = self.writer.send(&PeerMessage::Room(room_message)).await {
+                    logger.log_error(&format!("Error sending room message: {}", error));
+                }
+            }
+            ConnectionEvent::Feed(feed_message) => {
+                if let Err(error) = self.writer.send(&PeerMessage::Feed(feed_message)).await {
+                    logger.log_error(&format!("Error sending feed message: {}", error));
+                }
+            }
+            ConnectionEvent::KeyMigration(migration) => {
+                if let Err(error) = self
+                    .writer
+                    .send(&PeerMessage::KeyMigration(migration))
+                    .await
+                {
+                    logger.log_error(&format!("Error sending key migration: {}", error));
+                }
+            }
+            ConnectionEvent::ContactCard(card) => {
+                if let Err(error) = self.writer.send(&PeerMessage::ContactCard(card)).await {
+                    logger.log_error(&format!("Error sending contact card: {}", error));
+                }
+            }
+            ConnectionEvent::Identity(card) => {
+                if let Err(error) = self.writer.send(&PeerMessage::Identity(card)).await {
+                    logger.log_error(&format!("Error sending identity card: {}", error));
+                }
+            }
+            ConnectionEvent::Burned => {
+                if let Err(error) = self.writer.send(&PeerMessage::Burned).await {
+                    logger.log_error(&format!("Error sending burn notice: {}", error));
+                }
+            }
+            ConnectionEvent::Profile(profile_message) => {
+                if let Err(error) = self
+                    .writer
+                    .send(&PeerMessage::Profile(profile_message))
+                    .await
+                {
+                    logger.log_error(&format!("Error sending profile: {}", error));
+                }
+            }
+            ConnectionEvent::SetTtl(ttl) => {
+                if let Err(error) = self.writer.send(&PeerMessage::SetTtl(ttl)).await {
+                    logger.log_error(&format!("Error sending message timer: {}", error));
+                }
+            }
+            // Already let in
+            ConnectionEvent::ConnectionAuthorized => {}
+            ConnectionEvent::ContactRejected | ConnectionEvent::CloseConnection => {
+                logger.log_info(&format!("Disconnecting from {}", self.connection_info.id()));
+                return false;
+            }
+            _ => {
+                logger.log_error(&format!("Unexpected event received: {:?}", event));
+                return false;
+            }
         }
+        true
     }
 }
 
@@ -106,6 +410,8 @@ pub async fn connect(
     proxy_address: &SocketAddr,
     id: &TorServiceId,
     engine_tx: mpsc::UnboundedSender<EngineEvent>,
+    keepalive: Keepalive,
+    introduction: Introduction,
     logger: &mut dyn Logger,
 ) -> Result<Connection<TcpStream>> {
     logger.log_debug(&format!("Connecting as client to {}", address));
@@ -115,8 +421,7 @@ pub async fn connect(
     iter.next()
         .and_then(|port_str| port_str.parse::<u16>().ok())
         .ok_or(anyhow::anyhow!("Invalid port value"))?;
-    let domain = iter.next().ok_or(anyhow::anyhow!("Invalid domain"))?;
-    let peer_id = TorServiceId::from_str(domain.split('.').collect::<Vec<&str>>()[0])?;
+    let peer_id = address_service_id(address).ok_or(anyhow::anyhow!("Invalid domain"))?;
 
     // Connect through the Tor SOCKS proxy
     logger.log_info(&format!("Connecting to {}...", address));
@@ -147,9 +452,24 @@ pub async fn connect(
     let (main_thread_tx, rx) = mpsc::unbounded_channel();
 
     let auth_data = generate_auth_data(id, &session_hash);
-    let signature = Engine::sign_data(&auth_data, &engine_tx).await?;
+    let signature = Engine::sign_data(id, &auth_data, &engine_tx).await?;
     let auth_message = AuthMessage::new(id, &signature);
     writer.send(&auth_message).await?;
+    match timeout(Duration::from_secs(10), reader.read::<Option<Challenge>>()).await? {
+        Ok(Some(Some(challenge))) => {
+            logger.log_info(&format!(
+                "Solving proof-of-work puzzle of difficulty {} for {}",
+                challenge.difficulty, address
+            ));
+            let solution = tokio::task::spawn_blocking(move || challenge.solve()).await??;
+            writer.send(&solution).await?;
+        }
+        Ok(Some(None)) => {}
+        Ok(None) => {
+            return Err(anyhow!("Peer disconnected during handshake"));
+        }
+        Err(error) => return Err(error),
+    }
     let peer_auth_message =
         match timeout(Duration::from_secs(10), reader.read::<AuthMessage>()).await? {
             Ok(Some(auth_message)) => auth_message,
@@ -160,14 +480,40 @@ pub async fn connect(
         };
     verify_auth_message(&peer_auth_message, &peer_id, &session_hash)?;
 
-    logger.log_debug("Waiting for connection authorized message");
-    reader.read::<ConnectionAuthorizedMessage>().await?;
-    logger.log_debug("Got connection authorized message");
+    writer
+        .send(&ContactRequestMessage {
+            introduction: introduction.text,
+        })
+        .await?;
+    logger.log_debug("Waiting for contact request to be answered");
+    let outcome = match timeout(introduction.timeout, reader.read::<ContactResponse>()).await {
+        Ok(Ok(Some(ContactResponse::Accepted))) => ContactRequestOutcome::Accepted,
+        Ok(Ok(Some(ContactResponse::Rejected))) => ContactRequestOutcome::Rejected,
+        Ok(Ok(None)) => {
+            return Err(anyhow!(
+                "Peer disconnected before answering our contact request"
+            ));
+        }
+        Ok(Err(error)) => return Err(error),
+        Err(_) => ContactRequestOutcome::TimedOut,
+    };
+    let _ = engine_tx.send(EngineEvent::ContactRequestOutcome(peer_id.clone(), outcome));
+    match outcome {
+        ContactRequestOutcome::Accepted => logger.log_debug("Contact request accepted"),
+        ContactRequestOutcome::Rejected => {
+            return Err(anyhow!("{} rejected our contact request", peer_id));
+        }
+        ContactRequestOutcome::TimedOut => {
+            return Err(anyhow!("{} didn't answer our contact request", peer_id));
+        }
+    }
 
     let connection_info = ConnectionInfo::new(
         (*proxy_address).into(),
         &peer_id,
+        id,
         ConnectionDirection::Outgoing,
+        &session_verification_code(&session_hash),
     );
 
     // Let the main thread know we're connected
@@ -184,6 +530,8 @@ pub async fn connect(
         writer,
         engine_tx,
         rx,
+        keepalive,
+        true,
     ))
 }
 
@@ -192,6 +540,8 @@ pub async fn handle_incoming_connection(
     stream: OnionServiceStream,
     socket_addr: TorSocketAddr,
     engine_tx: mpsc::UnboundedSender<EngineEvent>,
+    keepalive: Keepalive,
+    mut permit: HandshakePermit,
     logger: &mut dyn Logger,
 ) -> Result<Connection<OnionServiceStream>> {
     let (mut reader, mut writer) = tokio::io::split(stream);
@@ -210,15 +560,37 @@ pub async fn handle_incoming_connection(
             }
             Err(_) => Err(anyhow!("Read timeout"))?,
         };
-    let peer_id = match TorServiceId::from_str(&peer_auth_message.service_id()) {
-        Ok(service_id) => service_id,
-        Err(error) => {
-            return Err(anyhow!(
-                "Error parsing service ID from auth message: {}",
-                error
-            ));
-        }
+    let peer_id = match parse_service_id(&peer_auth_message.service_id()) {
+        Some(service_id) => service_id,
+        None => return Err(anyhow!("Bad service ID in auth message")),
     };
+    // Check they're allowed to connect before doing any more work for them
+    if let Err(error) = permit.identify(&peer_id) {
+        let _ = engine_tx.send(EngineEvent::Audit(format!(
+            "Rejected handshake from {}: {}",
+            peer_id, error
+        )));
+        return Err(error);
+    }
+    // Make peers we don't know do some work before we do any for them
+    let challenge = Engine::proof_of_work_challenge(&peer_id, &engine_tx).await?;
+    writer.send(&challenge).await?;
+    if let Some(challenge) = challenge {
+        let solution = match timeout(PROOF_OF_WORK_TIMEOUT, reader.read::<u64>()).await? {
+            Ok(Some(solution)) => solution,
+            Ok(None) => {
+                return Err(anyhow!("Peer disconnected during handshake"));
+            }
+            Err(error) => return Err(error),
+        };
+        if !challenge.verify(solution) {
+            let _ = engine_tx.send(EngineEvent::Audit(format!(
+                "Rejected handshake from {}: bad proof-of-work",
+                peer_id
+            )));
+            return Err(anyhow!("Bad proof-of-work from {}", peer_id));
+        }
+    }
     let session_hash = match generate_session_hash(&peer_id, id, &shared_secret) {
         Ok(hash) => hash,
         Err(error) => {
@@ -226,13 +598,37 @@ pub async fn handle_incoming_connection(
         }
     };
     verify_auth_message(&peer_auth_message, &peer_id, &session_hash)?;
+    if let Err(error) = permit.verified(&peer_id) {
+        let _ = engine_tx.send(EngineEvent::Audit(format!(
+            "Rejected handshake from {}: {}",
+            peer_id, error
+        )));
+        return Err(error);
+    }
     let auth_data = generate_auth_data(id, &session_hash);
-    let signature = Engine::sign_data(&auth_data, &engine_tx).await?;
+    let signature = Engine::sign_data(id, &auth_data, &engine_tx).await?;
     let auth_message = AuthMessage::new(id, &signature);
     writer.send(&auth_message).await?;
+    let request = match timeout(
+        Duration::from_secs(10),
+        reader.read::<ContactRequestMessage>(),
+    )
+    .await?
+    {
+        Ok(Some(request)) => request,
+        Ok(None) => {
+            return Err(anyhow!("Peer disconnected during handshake"));
+        }
+        Err(_) => Err(anyhow!("Read timeout"))?,
+    };
 
-    let connection_info =
-        ConnectionInfo::new(socket_addr.clone(), &peer_id, ConnectionDirection::Incoming);
+    let connection_info = ConnectionInfo::new(
+        socket_addr.clone(),
+        &peer_id,
+        id,
+        ConnectionDirection::Incoming,
+        &session_verification_code(&session_hash),
+    );
 
     // Let the main thread know we're connected
     engine_tx
@@ -241,6 +637,10 @@ pub async fn handle_incoming_connection(
             main_thread_tx,
         ))
         .unwrap();
+    let _ = engine_tx.send(EngineEvent::ContactRequest(
+        peer_id.clone(),
+        request.introduction,
+    ));
 
     Ok(Connection::new(
         connection_info,
@@ -248,5 +648,135 @@ pub async fn handle_incoming_connection(
         writer,
         engine_tx,
         rx,
+        keepalive,
+        false,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::create_encrypted_channel;
+    use crate::logger::StandardLogger;
+    use chacha20poly1305::{aead::OsRng, ChaCha20Poly1305, Key as SymmetricKey, KeyInit};
+    use std::str::FromStr;
+    use tokio::io::DuplexStream;
+
+    // An authorized connection to `peer`, along with the peer's end of the stream
+    fn test_connection(
+        key: &SymmetricKey,
+        peer: &TorServiceId,
+        keepalive: Keepalive,
+    ) -> Result<(
+        Connection<DuplexStream>,
+        mpsc::UnboundedReceiver<EngineEvent>,
+        DuplexStream,
+    )> {
+        let (ours, theirs) = tokio::io::duplex(4096);
+        let (reader, writer) = tokio::io::split(ours);
+        let (reader, writer) = create_encrypted_channel(key, reader, writer);
+        let (engine_tx, engine_rx) = mpsc::unbounded_channel();
+        let (_, rx) = mpsc::unbounded_channel();
+        let connection_info = ConnectionInfo::new(
+            TorSocketAddr::from_str("127.0.0.1:3000")?,
+            peer,
+            &TorServiceId::generate(),
+            ConnectionDirection::Outgoing,
+            "",
+        );
+        let connection = Connection::new(
+            connection_info,
+            reader,
+            writer,
+            engine_tx,
+            rx,
+            keepalive,
+            true,
+        );
+        Ok((connection, engine_rx, theirs))
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() -> Result<()> {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let keepalive = Keepalive {
+            ping_interval: Duration::from_millis(20),
+            idle_timeout: Duration::from_millis(100),
+        };
+        let (mut connection, mut engine_rx, theirs) =
+            test_connection(&key, &TorServiceId::generate(), keepalive)?;
+
+        // The peer reads everything we send, but never answers
+        let (their_reader, their_writer) = tokio::io::split(theirs);
+        let (mut their_reader, _) = create_encrypted_channel(&key, their_reader, tokio::io::sink());
+        let pings = tokio::spawn(async move {
+            let mut pings = 0;
+            while let Ok(Some(message)) = their_reader.read::<PeerMessage>().await {
+                if let PeerMessage::Ping(_) = message {
+                    pings += 1;
+                }
+            }
+            pings
+        });
+
+        let mut logger = StandardLogger::new(100);
+        timeout(
+            Duration::from_secs(5),
+            connection.handle_connection(&mut logger),
+        )
+        .await?;
+        assert!(matches!(
+            engine_rx.try_recv(),
+            Ok(EngineEvent::ConnectionClosed(_))
+        ));
+
+        drop(connection);
+        drop(their_writer);
+        assert!(pings.await? >= 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_sender() -> Result<()> {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let peer = TorServiceId::generate();
+        let (mut connection, mut engine_rx, theirs) =
+            test_connection(&key, &peer, Keepalive::default())?;
+
+        // The peer sends a message claiming to be from someone else, then a real one, and
+        // hangs up
+        let (their_reader, their_writer) = tokio::io::split(theirs);
+        let (_, mut their_writer) = create_encrypted_channel(&key, their_reader, their_writer);
+        let me = TorServiceId::generate();
+        let spoofed = ChatMessage::new(&TorServiceId::generate(), &me, "Hi".to_string());
+        let genuine = ChatMessage::new(&peer, &me, "Hi".to_string());
+        their_writer
+            .send(&PeerMessage::Chat(Box::new(spoofed)))
+            .await?;
+        their_writer
+            .send(&PeerMessage::Chat(Box::new(genuine.clone())))
+            .await?;
+        drop(their_writer);
+
+        let mut logger = StandardLogger::new(100);
+        timeout(
+            Duration::from_secs(5),
+            connection.handle_connection(&mut logger),
+        )
+        .await?;
+        match engine_rx.try_recv() {
+            Ok(EngineEvent::Message(from, message)) => {
+                assert_eq!(peer, from);
+                assert_eq!(genuine.id, message.id);
+            }
+            _ => panic!("Expected the genuine message"),
+        }
+        assert!(matches!(
+            engine_rx.try_recv(),
+            Ok(EngineEvent::ConnectionClosed(_))
+        ));
+
+        Ok(())
+    }
+}
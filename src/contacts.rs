@@ -0,0 +1,804 @@
+use crate::{
+    client_auth::{ClientAuthKey, ClientAuthPublicKey},
+    onion_service::OnionService,
+    profile::Profile,
+    storage::{read_encrypted, write_encrypted, StorageKey},
+    util::create_onion_service_dir,
+};
+use anyhow::{anyhow, Result};
+use chrono::{serde::ts_seconds, DateTime, SubsecRound, Utc};
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tor_client_lib::{TorEd25519SigningKey, TorServiceId};
+
+/// How much we trust that a contact's ID belongs to who we think it does
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub enum VerificationStatus {
+    /// We've never connected to them
+    #[default]
+    Unverified,
+    /// Trust on first use - we've connected to them, but haven't checked who they are
+    Tofu,
+    /// We've compared the session verification code (SAS) with them out-of-band
+    Verified,
+}
+
+/// Announcement that a contact has moved to a new onion service ID, signed with both the
+/// old and new keys so that nobody but the owner of the old ID can redirect it
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct KeyMigration {
+    pub old_id: TorServiceId,
+    pub new_id: TorServiceId,
+    #[serde(with = "ts_seconds")]
+    pub date: DateTime<Utc>,
+    old_signature: Vec<u8>,
+    new_signature: Vec<u8>,
+}
+
+impl KeyMigration {
+    pub fn new(old_key: &TorEd25519SigningKey, new_key: &TorEd25519SigningKey) -> Self {
+        let mut migration = Self {
+            old_id: old_key.verifying_key().into(),
+            new_id: new_key.verifying_key().into(),
+            date: Utc::now().round_subsecs(0),
+            old_signature: Vec::new(),
+            new_signature: Vec::new(),
+        };
+        let data = migration.signed_data();
+        migration.old_signature = old_key.sign(&data).to_bytes().to_vec();
+        migration.new_signature = new_key.sign(&data).to_bytes().to_vec();
+        migration
+    }
+
+    /// Check both signatures
+    pub fn verify(&self) -> Result<()> {
+        let data = self.signed_data();
+        for (id, signature) in [
+            (&self.old_id, &self.old_signature),
+            (&self.new_id, &self.new_signature),
+        ] {
+            let signature_bytes: [u8; 64] = match signature.as_slice().try_into() {
+                Ok(bytes) => bytes,
+                Err(_) => return Err(anyhow!("Bad signature length")),
+            };
+            id.verifying_key()?
+                .verify(&data, &Signature::from_bytes(&signature_bytes))?;
+        }
+        Ok(())
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = b"voynich-key-migration".to_vec();
+        data.extend_from_slice(self.old_id.as_str().as_bytes());
+        data.extend_from_slice(self.new_id.as_str().as_bytes());
+        data.extend_from_slice(&self.date.timestamp().to_be_bytes());
+        data
+    }
+}
+
+/// A contact shared with another contact, signed by whoever introduced them
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ContactCard {
+    pub id: TorServiceId,
+    pub nickname: String,
+    pub port: u16,
+    pub introducer: TorServiceId,
+    /// Who the card was made for, so it can't be passed on as if we'd introduced someone else
+    pub recipient: TorServiceId,
+    #[serde(with = "ts_seconds")]
+    pub date: DateTime<Utc>,
+    signature: Vec<u8>,
+}
+
+impl ContactCard {
+    pub fn new(
+        introducer_key: &TorEd25519SigningKey,
+        contact: &Contact,
+        recipient: &TorServiceId,
+    ) -> Self {
+        let mut card = Self {
+            id: contact.id.clone(),
+            nickname: contact.nickname.clone(),
+            port: contact.port,
+            introducer: introducer_key.verifying_key().into(),
+            recipient: recipient.clone(),
+            date: Utc::now().round_subsecs(0),
+            signature: Vec::new(),
+        };
+        card.signature = introducer_key.sign(&card.signed_data()).to_bytes().to_vec();
+        card
+    }
+
+    /// Check that the card was signed by the introducer
+    pub fn verify(&self) -> Result<()> {
+        let signature_bytes: [u8; 64] = match self.signature.as_slice().try_into() {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(anyhow!("Bad signature length")),
+        };
+        self.introducer.verifying_key()?.verify(
+            &self.signed_data(),
+            &Signature::from_bytes(&signature_bytes),
+        )?;
+        Ok(())
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = b"voynich-contact-card".to_vec();
+        data.extend_from_slice(self.introducer.as_str().as_bytes());
+        data.extend_from_slice(self.recipient.as_str().as_bytes());
+        data.extend_from_slice(self.id.as_str().as_bytes());
+        data.extend_from_slice(&self.port.to_be_bytes());
+        data.extend_from_slice(&self.date.timestamp().to_be_bytes());
+        data.extend_from_slice(self.nickname.as_bytes());
+        data
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Contact {
+    pub id: TorServiceId,
+    pub nickname: String,
+    pub notes: String,
+    /// Port their onion service listens on
+    pub port: u16,
+    pub verification: VerificationStatus,
+    #[serde(with = "ts_seconds")]
+    pub first_seen: DateTime<Utc>,
+    /// Key they gave us to connect to their private onion service
+    #[serde(default)]
+    pub client_auth_key: Option<ClientAuthKey>,
+    /// Key we've authorized them to connect to our private onion service with
+    #[serde(default)]
+    pub authorized_client_key: Option<ClientAuthPublicKey>,
+    /// The contact who sent us their contact card, if that's how we got them
+    #[serde(default)]
+    pub introduced_by: Option<TorServiceId>,
+    /// Whether we send them our profile. Off by default, since it may say more about us
+    /// than our onion service ID does.
+    #[serde(default)]
+    pub share_profile: bool,
+    /// The latest profile they've sent us
+    #[serde(default)]
+    pub profile: Option<Profile>,
+    /// Name of the onion service we use only with them, if we have one
+    #[serde(default)]
+    pub identity: Option<String>,
+}
+
+impl Contact {
+    pub fn new(id: &TorServiceId, nickname: &str, port: u16) -> Self {
+        Self {
+            id: id.clone(),
+            nickname: nickname.to_string(),
+            notes: String::new(),
+            port,
+            verification: VerificationStatus::default(),
+            first_seen: Utc::now().round_subsecs(0),
+            client_auth_key: None,
+            authorized_client_key: None,
+            introduced_by: None,
+            share_profile: false,
+            profile: None,
+            identity: None,
+        }
+    }
+
+    /// Address to connect to, as `<onion hostname>:<port>`
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.id.onion_hostname(), self.port)
+    }
+}
+
+/// Address book mapping onion service IDs to nicknames, stored encrypted on disk
+pub struct Contacts {
+    path: PathBuf,
+    key: StorageKey,
+    contacts: BTreeMap<TorServiceId, Contact>,
+}
+
+impl Contacts {
+    /// Open (or create) an address book stored in `path`, encrypted with `key`
+    pub fn open(path: &Path, key: StorageKey) -> Result<Self> {
+        let contacts = read_encrypted::<Vec<Contact>>(path, &key)?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|contact| (contact.id.clone(), contact))
+            .collect();
+        Ok(Self {
+            path: path.to_path_buf(),
+            key,
+            contacts,
+        })
+    }
+
+    /// Open the address book stored under the data directory for this onion service
+    pub fn for_onion_service(onion_service: &OnionService) -> Result<Self> {
+        let dir = create_onion_service_dir(onion_service.name())?;
+        Self::open(
+            &Path::new(&dir).join("contacts"),
+            StorageKey::for_dir(Path::new(&dir), onion_service.signing_key(), "contacts")?,
+        )
+    }
+
+    /// Add a contact, or replace the one with the same ID. Nicknames must be unique, and
+    /// can't contain ':' since they'd be taken for addresses by `resolve`.
+    pub fn add(&mut self, contact: Contact) -> Result<()> {
+        if contact.nickname.contains(':') {
+            return Err(anyhow!("Nickname {} can't contain ':'", contact.nickname));
+        }
+        if self
+            .by_nickname(&contact.nickname)
+            .is_some_and(|existing| existing.id != contact.id)
+        {
+            return Err(anyhow!("Nickname {} is already in use", contact.nickname));
+        }
+        self.contacts.insert(contact.id.clone(), contact);
+        self.save()
+    }
+
+    /// Add the contact from a card someone sent us, under `nickname` or the nickname on the
+    /// card. Contacts we already have are left alone.
+    pub fn import_card(&mut self, card: &ContactCard, nickname: Option<&str>) -> Result<()> {
+        card.verify()?;
+        if self.contacts.contains_key(&card.id) {
+            return Err(anyhow!("{} is already a contact", card.id));
+        }
+        let mut contact = Contact::new(&card.id, nickname.unwrap_or(&card.nickname), card.port);
+        contact.introduced_by = Some(card.introducer.clone());
+        self.add(contact)
+    }
+
+    pub fn remove(&mut self, id: &TorServiceId) -> Result<Option<Contact>> {
+        let removed = self.contacts.remove(id);
+        if removed.is_some() {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn get(&self, id: &TorServiceId) -> Option<&Contact> {
+        self.contacts.get(id)
+    }
+
+    pub fn by_nickname(&self, nickname: &str) -> Option<&Contact> {
+        self.contacts
+            .values()
+            .find(|contact| contact.nickname == nickname)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Contact> {
+        self.contacts.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.contacts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+
+    pub fn set_notes(&mut self, id: &TorServiceId, notes: &str) -> Result<()> {
+        self.update(id, |contact| contact.notes = notes.to_string())
+    }
+
+    pub fn set_verification(
+        &mut self,
+        id: &TorServiceId,
+        verification: VerificationStatus,
+    ) -> Result<()> {
+        self.update(id, |contact| contact.verification = verification)
+    }
+
+    pub fn set_client_auth_key(
+        &mut self,
+        id: &TorServiceId,
+        key: Option<ClientAuthKey>,
+    ) -> Result<()> {
+        self.update(id, |contact| contact.client_auth_key = key)
+    }
+
+    pub fn set_authorized_client_key(
+        &mut self,
+        id: &TorServiceId,
+        key: Option<ClientAuthPublicKey>,
+    ) -> Result<()> {
+        self.update(id, |contact| contact.authorized_client_key = key)
+    }
+
+    /// Keys of all the contacts authorized to connect to our private onion service
+    pub fn authorized_client_keys(&self) -> Vec<ClientAuthPublicKey> {
+        self.contacts
+            .values()
+            .filter_map(|contact| contact.authorized_client_key)
+            .collect()
+    }
+
+    /// Record the pairwise identity we use with a contact
+    pub fn set_identity(&mut self, id: &TorServiceId, identity: Option<&str>) -> Result<()> {
+        self.update(id, |contact| {
+            contact.identity = identity.map(|identity| identity.to_string())
+        })
+    }
+
+    pub fn set_share_profile(&mut self, id: &TorServiceId, share: bool) -> Result<()> {
+        self.update(id, |contact| contact.share_profile = share)
+    }
+
+    /// Cache a profile a contact has sent us, if it's newer than the one we have. Returns
+    /// whether it was.
+    pub fn update_profile(&mut self, profile: &Profile) -> Result<bool> {
+        profile.verify()?;
+        let newer = match self.contacts.get(&profile.owner) {
+            Some(contact) => contact
+                .profile
+                .as_ref()
+                .is_none_or(|cached| profile.version > cached.version),
+            None => return Err(anyhow!("Unknown contact {}", profile.owner)),
+        };
+        if newer {
+            self.update(&profile.owner, |contact| {
+                contact.profile = Some(profile.clone())
+            })?;
+        }
+        Ok(newer)
+    }
+
+    /// Pin a contact's ID the first time we connect to them. Does nothing if they're already
+    /// pinned or verified. Returns whether the contact was updated.
+    pub fn trust_on_first_use(&mut self, id: &TorServiceId) -> Result<bool> {
+        match self.contacts.get(id) {
+            Some(contact) if contact.verification == VerificationStatus::Unverified => {
+                self.set_verification(id, VerificationStatus::Tofu)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Move a contact to their new ID. Since the new ID hasn't been checked the way the old
+    /// one may have been, the contact's trust drops to TOFU. Fails if we already have a
+    /// contact with the new ID.
+    pub fn migrate(&mut self, migration: &KeyMigration) -> Result<()> {
+        migration.verify()?;
+        if self.contacts.contains_key(&migration.new_id) {
+            return Err(anyhow!("{} is already a contact", migration.new_id));
+        }
+        let mut contact = match self.contacts.remove(&migration.old_id) {
+            Some(contact) => contact,
+            None => return Err(anyhow!("Unknown contact {}", migration.old_id)),
+        };
+        contact.id = migration.new_id.clone();
+        contact.verification = contact.verification.min(VerificationStatus::Tofu);
+        self.contacts.insert(contact.id.clone(), contact);
+        self.save()
+    }
+
+    /// Turn a nickname into an address. Anything that already looks like `host:port` is
+    /// returned as is.
+    pub fn resolve(&self, nickname_or_address: &str) -> Result<String> {
+        if nickname_or_address.contains(':') {
+            return Ok(nickname_or_address.to_string());
+        }
+        match self.by_nickname(nickname_or_address) {
+            Some(contact) => Ok(contact.address()),
+            None => Err(anyhow!("Unknown contact {}", nickname_or_address)),
+        }
+    }
+
+    fn update<F: FnOnce(&mut Contact)>(&mut self, id: &TorServiceId, f: F) -> Result<()> {
+        match self.contacts.get_mut(id) {
+            Some(contact) => {
+                f(contact);
+                self.save()
+            }
+            None => Err(anyhow!("Unknown contact {}", id)),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        write_encrypted(
+            &self.path,
+            &self.key,
+            &self.contacts.values().collect::<Vec<&Contact>>(),
+        )
+    }
+}
+
+/// Request from someone who isn't a contact yet to be let in
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ContactRequest {
+    pub id: TorServiceId,
+    /// Who they say they are, and why they want to talk to us
+    pub introduction: String,
+    #[serde(with = "ts_seconds")]
+    pub received: DateTime<Utc>,
+}
+
+impl ContactRequest {
+    pub fn new(id: &TorServiceId, introduction: &str) -> Self {
+        Self {
+            id: id.clone(),
+            introduction: introduction.to_string(),
+            received: Utc::now().round_subsecs(0),
+        }
+    }
+}
+
+/// How a contact request we sent was answered
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ContactRequestOutcome {
+    Accepted,
+    Rejected,
+    /// They didn't answer in time. The request stays with them, and if they accept it
+    /// we'll be let straight in next time we connect.
+    TimedOut,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ContactRequestState {
+    pending: BTreeMap<TorServiceId, ContactRequest>,
+    accepted: BTreeSet<TorServiceId>,
+}
+
+/// Contact requests waiting for an answer, and the peers whose requests we've accepted.
+/// Requests are kept until they're answered, even if the requester goes away.
+pub struct ContactRequests {
+    storage: Option<(PathBuf, StorageKey)>,
+    state: ContactRequestState,
+}
+
+impl ContactRequests {
+    /// Requests which only last as long as the process
+    pub fn new() -> Self {
+        Self {
+            storage: None,
+            state: ContactRequestState::default(),
+        }
+    }
+
+    /// Open (or create) the requests stored in `path`, encrypted with `key`
+    pub fn open(path: &Path, key: StorageKey) -> Result<Self> {
+        let state = read_encrypted(path, &key)?.unwrap_or_default();
+        Ok(Self {
+            storage: Some((path.to_path_buf(), key)),
+            state,
+        })
+    }
+
+    /// Open the requests stored under the data directory for this onion service
+    pub fn for_onion_service(onion_service: &OnionService) -> Result<Self> {
+        let dir = create_onion_service_dir(onion_service.name())?;
+        Self::open(
+            &Path::new(&dir).join("contact_requests"),
+            StorageKey::for_dir(
+                Path::new(&dir),
+                onion_service.signing_key(),
+                "contact_requests",
+            )?,
+        )
+    }
+
+    /// Add a request, replacing any earlier one from the same peer
+    pub fn add(&mut self, request: ContactRequest) -> Result<()> {
+        self.state.pending.insert(request.id.clone(), request);
+        self.save()
+    }
+
+    pub fn get(&self, id: &TorServiceId) -> Option<&ContactRequest> {
+        self.state.pending.get(id)
+    }
+
+    /// Requests waiting for an answer, oldest first
+    pub fn pending(&self) -> Vec<&ContactRequest> {
+        let mut pending = self.state.pending.values().collect::<Vec<_>>();
+        pending.sort_by_key(|request| request.received);
+        pending
+    }
+
+    pub fn accept(&mut self, id: &TorServiceId) -> Result<Option<ContactRequest>> {
+        let request = self.state.pending.remove(id);
+        self.state.accepted.insert(id.clone());
+        self.save()?;
+        Ok(request)
+    }
+
+    /// Drop a request. They can ask again.
+    pub fn reject(&mut self, id: &TorServiceId) -> Result<Option<ContactRequest>> {
+        let request = self.state.pending.remove(id);
+        self.state.accepted.remove(id);
+        self.save()?;
+        Ok(request)
+    }
+
+    pub fn is_accepted(&self, id: &TorServiceId) -> bool {
+        self.state.accepted.contains(id)
+    }
+
+    fn save(&self) -> Result<()> {
+        match &self.storage {
+            Some((path, key)) => write_encrypted(path, key, &self.state),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for ContactRequests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Our own key migration, while the old identity is still redirecting to the new one
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct RetiringIdentity {
+    pub migration: KeyMigration,
+    /// When the old identity stops redirecting
+    pub until: DateTime<Utc>,
+    /// The old identity's persistent onion service, wiped once it's retired
+    pub name: String,
+}
+
+/// Keeps our own key migration across restarts, so the old identity goes on redirecting
+/// for the rest of its grace period
+pub struct MigrationState {
+    storage: Option<(PathBuf, StorageKey)>,
+    retiring: Option<RetiringIdentity>,
+}
+
+impl MigrationState {
+    /// State which only lasts as long as the process
+    pub fn new() -> Self {
+        Self {
+            storage: None,
+            retiring: None,
+        }
+    }
+
+    /// Open (or create) the state stored in `path`, encrypted with `key`
+    pub fn open(path: &Path, key: StorageKey) -> Result<Self> {
+        let retiring = read_encrypted(path, &key)?.unwrap_or_default();
+        Ok(Self {
+            storage: Some((path.to_path_buf(), key)),
+            retiring,
+        })
+    }
+
+    /// Open the state stored under the data directory for this onion service
+    pub fn for_onion_service(onion_service: &OnionService) -> Result<Self> {
+        let dir = create_onion_service_dir(onion_service.name())?;
+        Self::open(
+            &Path::new(&dir).join("migration"),
+            StorageKey::for_dir(Path::new(&dir), onion_service.signing_key(), "migration")?,
+        )
+    }
+
+    pub fn get(&self) -> Option<&RetiringIdentity> {
+        self.retiring.as_ref()
+    }
+
+    pub fn set(&mut self, retiring: RetiringIdentity) -> Result<()> {
+        self.retiring = Some(retiring);
+        self.save()
+    }
+
+    /// Forget the migration. It's gone from memory even if it can't be saved.
+    pub fn clear(&mut self) -> Result<()> {
+        self.retiring = None;
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        match &self.storage {
+            Some((path, key)) => write_encrypted(path, key, &self.retiring),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for MigrationState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::read_or_create_storage_secret;
+    use anyhow::Result;
+    use tor_client_lib::TorEd25519SigningKey;
+
+    #[test]
+    fn test_contacts() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("contacts");
+        let key =
+            StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([5u8; 64]), "contacts")?;
+        let alice = TorServiceId::generate();
+        let bob = TorServiceId::generate();
+
+        let mut contacts = Contacts::open(&path, key.clone())?;
+        contacts.add(Contact::new(&alice, "alice", 3000))?;
+        assert!(contacts.add(Contact::new(&bob, "alice", 3000)).is_err());
+        assert!(contacts.add(Contact::new(&bob, "bob:4000", 4000)).is_err());
+        contacts.add(Contact::new(&bob, "bob", 4000))?;
+        contacts.set_verification(&bob, VerificationStatus::Verified)?;
+        contacts.set_identity(&bob, Some("pairwise-bob"))?;
+
+        let contacts = Contacts::open(&path, key)?;
+        assert_eq!(2, contacts.len());
+        assert_eq!(
+            VerificationStatus::Verified,
+            contacts.get(&bob).unwrap().verification
+        );
+        assert_eq!(
+            Some("pairwise-bob"),
+            contacts.get(&bob).unwrap().identity.as_deref()
+        );
+        assert!(contacts.get(&alice).unwrap().identity.is_none());
+        assert_eq!(
+            format!("{}:4000", bob.onion_hostname()),
+            contacts.resolve("bob")?
+        );
+        assert_eq!("example.onion:80", contacts.resolve("example.onion:80")?);
+        assert!(contacts.resolve("carol").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migration() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key =
+            StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([5u8; 64]), "contacts")?;
+        let old_key = TorEd25519SigningKey::from_bytes([1u8; 64]);
+        let new_key = TorEd25519SigningKey::from_bytes([2u8; 64]);
+        let old_id: TorServiceId = old_key.verifying_key().into();
+
+        let mut contacts = Contacts::open(&dir.path().join("contacts"), key)?;
+        contacts.add(Contact::new(&old_id, "alice", 3000))?;
+        assert!(contacts.trust_on_first_use(&old_id)?);
+        contacts.set_verification(&old_id, VerificationStatus::Verified)?;
+        assert!(!contacts.trust_on_first_use(&old_id)?);
+
+        // Signed by someone other than the new ID
+        let mut forged = KeyMigration::new(&old_key, &new_key);
+        forged.new_id = TorServiceId::generate();
+        assert!(contacts.migrate(&forged).is_err());
+
+        // A contact we already have at the new ID isn't overwritten
+        let migration = KeyMigration::new(&old_key, &new_key);
+        contacts.add(Contact::new(&migration.new_id, "bob", 3000))?;
+        assert!(contacts.migrate(&migration).is_err());
+        assert!(contacts.get(&old_id).is_some());
+        assert_eq!("bob", contacts.get(&migration.new_id).unwrap().nickname);
+        contacts.remove(&migration.new_id)?;
+
+        contacts.migrate(&migration)?;
+        assert!(contacts.get(&old_id).is_none());
+        let contact = contacts.by_nickname("alice").unwrap();
+        assert_eq!(migration.new_id, contact.id);
+        assert_eq!(VerificationStatus::Tofu, contact.verification);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_contact_cards() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key =
+            StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([5u8; 64]), "contacts")?;
+        let introducer_key = TorEd25519SigningKey::from_bytes([1u8; 64]);
+        let introducer: TorServiceId = introducer_key.verifying_key().into();
+        let me = TorServiceId::generate();
+        let bob = Contact::new(&TorServiceId::generate(), "bob", 3000);
+
+        let card = ContactCard::new(&introducer_key, &bob, &me);
+        let mut tampered = card.clone();
+        tampered.port = 4000;
+        assert!(tampered.verify().is_err());
+
+        let mut contacts = Contacts::open(&dir.path().join("contacts"), key)?;
+        contacts.import_card(&card, Some("Bob"))?;
+        assert!(contacts.import_card(&card, None).is_err());
+        let imported = contacts.by_nickname("Bob").unwrap();
+        assert_eq!(bob.address(), imported.address());
+        assert_eq!(Some(&introducer), imported.introduced_by.as_ref());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_contact_requests() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("contact_requests");
+        let key = StorageKey::from_signing_key(
+            &TorEd25519SigningKey::from_bytes([5u8; 64]),
+            "contact_requests",
+        )?;
+        let alice = TorServiceId::generate();
+        let bob = TorServiceId::generate();
+
+        let mut requests = ContactRequests::open(&path, key.clone())?;
+        requests.add(ContactRequest::new(&alice, "Hi, it's Alice"))?;
+        requests.add(ContactRequest::new(&bob, "Bob from work"))?;
+
+        // Still there after a restart
+        let mut requests = ContactRequests::open(&path, key.clone())?;
+        assert_eq!(2, requests.pending().len());
+        assert_eq!(
+            "Hi, it's Alice",
+            requests.accept(&alice)?.unwrap().introduction
+        );
+        requests.reject(&bob)?;
+
+        let requests = ContactRequests::open(&path, key)?;
+        assert!(requests.pending().is_empty());
+        assert!(requests.is_accepted(&alice));
+        assert!(!requests.is_accepted(&bob));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migration_state() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("migration");
+        let key = StorageKey::from_signing_key(
+            &TorEd25519SigningKey::from_bytes([6u8; 64]),
+            "migration",
+        )?;
+        let old_key = TorEd25519SigningKey::from_bytes([7u8; 64]);
+        let new_key = TorEd25519SigningKey::from_bytes([8u8; 64]);
+        let retiring = RetiringIdentity {
+            migration: KeyMigration::new(&old_key, &new_key),
+            until: Utc::now().round_subsecs(0),
+            name: "test-retired".to_string(),
+        };
+
+        let mut state = MigrationState::open(&path, key.clone())?;
+        assert!(state.get().is_none());
+        state.set(retiring.clone())?;
+        let mut state = MigrationState::open(&path, key.clone())?;
+        assert_eq!(Some(&retiring), state.get());
+        state.clear()?;
+        assert!(MigrationState::open(&path, key)?.get().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotated_storage_key() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("contacts");
+        let old_key = TorEd25519SigningKey::from_bytes([9u8; 64]);
+        let new_key = TorEd25519SigningKey::from_bytes([10u8; 64]);
+        let alice = TorServiceId::generate();
+
+        // Written before there was a storage secret
+        let mut contacts =
+            Contacts::open(&path, StorageKey::from_signing_key(&old_key, "contacts")?)?;
+        contacts.add(Contact::new(&alice, "alice", 3000))?;
+
+        // Rotating the key makes the secret from the old key first, so every store can be
+        // reopened under the new one
+        read_or_create_storage_secret(dir.path(), &old_key)?;
+        let mut contacts = Contacts::open(
+            &path,
+            StorageKey::for_dir(dir.path(), &new_key, "contacts")?,
+        )?;
+        assert!(contacts.get(&alice).is_some());
+        let bob = TorServiceId::generate();
+        contacts.add(Contact::new(&bob, "bob", 4000))?;
+        let contacts = Contacts::open(
+            &path,
+            StorageKey::for_dir(dir.path(), &new_key, "contacts")?,
+        )?;
+        assert_eq!(2, contacts.len());
+
+        Ok(())
+    }
+}
@@ -1,9 +1,17 @@
+use crate::burner::{self, Burner};
+use crate::client_auth::{ClientAuthConnection, ClientAuthPublicKey};
 use crate::config::TorAuthConfig;
+use crate::contacts::KeyMigration;
 use crate::onion_service::{OnionService, OnionType};
-use crate::util::{get_onion_address, get_onion_service, save_onion_service};
+use crate::storage::read_or_create_storage_secret;
+use crate::util::{
+    create_onion_service_dir, get_onion_address, get_onion_service, save_onion_service,
+};
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use rpassword::read_password;
 use std::io::Write;
+use std::path::Path;
 use std::str::FromStr;
 use tokio::net::ToSocketAddrs;
 use tor_client_lib::{
@@ -33,12 +41,7 @@ pub async fn connect_to_tor<A: ToSocketAddrs>(
     let tor_authentication = match authentication {
         Some(TorAuthConfig::HashedPassword) => match hashed_password {
             Some(password) => TorAuthentication::HashedPassword(password),
-            None => {
-                print!("Type a password: ");
-                std::io::stdout().flush().unwrap();
-                let password = read_password().unwrap();
-                TorAuthentication::HashedPassword(password)
-            }
+            None => TorAuthentication::HashedPassword(prompt_for_password()?),
         },
         Some(TorAuthConfig::SafeCookie) => match cookie {
             Some(cookie) => TorAuthentication::SafeCookie(Some(cookie)),
@@ -57,6 +60,12 @@ pub async fn connect_to_tor<A: ToSocketAddrs>(
     Ok(control_connection)
 }
 
+pub(crate) fn prompt_for_password() -> Result<String> {
+    print!("Type a password: ");
+    std::io::stdout().flush()?;
+    Ok(read_password()?)
+}
+
 pub async fn create_transient_onion_service(
     control_connection: &mut TorControlConnection,
     service_port: u16,
@@ -138,63 +147,230 @@ pub async fn create_onion_service(
     service_port: Option<u16>,
     listen_address: Option<TorSocketAddr>,
 ) -> Result<(OnionService, OnionAddress, OnionServiceListener)> {
-    match onion_type {
+    let (onion_service, onion_address, listen_address) = match onion_type {
         OnionType::Transient => {
-            let service_port = match service_port {
-                Some(port) => port,
-                None => {
-                    return Err(anyhow!(
-                        "Error: No service port specified for transient onion service"
-                    ));
-                }
-            };
-            let listen_address = match listen_address.clone() {
-                Some(listen_address) => listen_address,
-                None => TorSocketAddr::from_str(&format!("127.0.0.1:{}", service_port)).unwrap(),
-            };
-            let service =
+            let service_port = required_service_port(service_port)?;
+            let listen_address = listen_address_or_default(listen_address, service_port)?;
+            let onion_service =
                 create_transient_onion_service(control_connection, service_port, &listen_address)
                     .await?;
-            let listener = OnionServiceListener::bind(listen_address.clone()).await?;
-            let onion_service_address =
-                OnionAddress::new(service.service_id().clone(), service_port);
-            Ok((service, onion_service_address, listener))
+            let onion_address = OnionAddress::new(onion_service.service_id().clone(), service_port);
+            (onion_service, onion_address, listen_address)
         }
-        OnionType::Persistent { name, create } => {
-            if create {
-                let listen_address = match listen_address.clone() {
-                    Some(listen_address) => listen_address,
-                    None => {
-                        TorSocketAddr::from_str(&format!("127.0.0.1:{}", service_port.unwrap()))
-                            .unwrap()
-                    }
-                };
-                let onion_service = create_persistent_onion_service(
-                    control_connection,
-                    &name,
-                    service_port.unwrap(),
-                    &listen_address,
-                )
-                .await?;
-                let listener = OnionServiceListener::bind(listen_address.clone()).await?;
-                let onion_service_address =
-                    OnionAddress::new(onion_service.service_id().clone(), service_port.unwrap());
-                Ok((onion_service, onion_service_address, listener))
-            } else {
-                let onion_address = get_onion_address(&name)?;
-                let listen_address = match listen_address.clone() {
-                    Some(listen_address) => listen_address,
-                    None => TorSocketAddr::from_str(&format!(
-                        "127.0.0.1:{}",
-                        onion_address.service_port()
-                    ))
-                    .unwrap(),
-                };
-                let onion_service = get_onion_service(&name, &onion_address, &listen_address)?;
-                use_persistent_onion_service(control_connection, &onion_service).await?;
-                let listener = OnionServiceListener::bind(listen_address.clone()).await?;
-                Ok((onion_service, onion_address, listener))
+        OnionType::Persistent { name, create: true } => {
+            let service_port = required_service_port(service_port)?;
+            let listen_address = listen_address_or_default(listen_address, service_port)?;
+            let onion_service = create_persistent_onion_service(
+                control_connection,
+                &name,
+                service_port,
+                &listen_address,
+            )
+            .await?;
+            let onion_address = OnionAddress::new(onion_service.service_id().clone(), service_port);
+            (onion_service, onion_address, listen_address)
+        }
+        OnionType::Persistent {
+            name,
+            create: false,
+        } => {
+            let (onion_service, onion_address, listen_address) =
+                load_persistent_onion_service(&name, listen_address)?;
+            if !start_burner_session(&name)? {
+                burner::burn(control_connection, &onion_service).await?;
+                return Err(burner_spent(&name));
             }
+            use_persistent_onion_service(control_connection, &onion_service).await?;
+            (onion_service, onion_address, listen_address)
         }
+    };
+    let listener = OnionServiceListener::bind(listen_address).await?;
+    Ok((onion_service, onion_address, listener))
+}
+
+fn required_service_port(service_port: Option<u16>) -> Result<u16> {
+    service_port.ok_or(anyhow!(
+        "Error: No service port specified for new onion service"
+    ))
+}
+
+fn listen_address_or_default(
+    listen_address: Option<TorSocketAddr>,
+    service_port: u16,
+) -> Result<TorSocketAddr> {
+    match listen_address {
+        Some(listen_address) => Ok(listen_address),
+        None => Ok(TorSocketAddr::from_str(&format!(
+            "127.0.0.1:{}",
+            service_port
+        ))?),
     }
 }
+
+// Load a saved persistent onion service, with its address and the address to listen on
+fn load_persistent_onion_service(
+    name: &str,
+    listen_address: Option<TorSocketAddr>,
+) -> Result<(OnionService, OnionAddress, TorSocketAddr)> {
+    let onion_address = get_onion_address(name)?;
+    let listen_address = listen_address_or_default(listen_address, onion_address.service_port())?;
+    let onion_service = get_onion_service(name, &onion_address, &listen_address)?;
+    Ok((onion_service, onion_address, listen_address))
+}
+
+// Count another session of the service if it's a burner. Returns false if the burner is
+// spent, in which case the caller has to destroy it.
+fn start_burner_session(name: &str) -> Result<bool> {
+    match Burner::load(name)? {
+        Some(burner) if burner.is_spent() => Ok(false),
+        Some(mut burner) => {
+            burner.start_session(name)?;
+            Ok(true)
+        }
+        None => Ok(true),
+    }
+}
+
+fn burner_spent(name: &str) -> anyhow::Error {
+    anyhow!(
+        "Burner identity {} has expired, and has been destroyed",
+        name
+    )
+}
+
+/// Create a new persistent onion service which is destroyed once `expires` has passed, or
+/// it's been started `max_sessions` times, counting this one. Later sessions start it with
+/// [`create_onion_service`] and `OnionType::existing_persistent(name)`, which does the
+/// destroying. While it's running, `Engine::use_burner` watches for it expiring.
+pub async fn create_burner_onion_service(
+    control_connection: &mut TorControlConnection,
+    name: &str,
+    expires: Option<DateTime<Utc>>,
+    max_sessions: Option<u32>,
+    service_port: u16,
+    listen_address: Option<TorSocketAddr>,
+) -> Result<(OnionService, OnionAddress, OnionServiceListener, Burner)> {
+    if expires.is_none() && max_sessions.is_none() {
+        return Err(anyhow!(
+            "A burner identity needs an expiry date or session limit"
+        ));
+    }
+    let mut burner = Burner::new(expires, max_sessions);
+    let (onion_service, onion_address, listener) = create_onion_service(
+        control_connection,
+        OnionType::new_persistent(name),
+        Some(service_port),
+        listen_address,
+    )
+    .await?;
+    if let Err(error) = burner.start_session(name) {
+        // Don't leave an identity behind with no limits
+        let _ = burner::burn(control_connection, &onion_service).await;
+        return Err(error);
+    }
+    Ok((onion_service, onion_address, listener, burner))
+}
+
+/// Retire the key of a running persistent onion service, replacing it with a new one under
+/// the same name. The old service is saved as `<name>-retired` and left running, so contacts
+/// can be told where we've gone; `Engine::migrate_identity` does that, and wipes the old key
+/// once it's no longer needed. Data stored for the service stays readable under the new key.
+/// Returns the new service, its address and listener, the retired service and the migration
+/// signed by both keys.
+///
+/// The new service needs a `listen_address` of its own while the old one is still running.
+pub async fn rotate_persistent_onion_service(
+    control_connection: &mut TorControlConnection,
+    onion_service: &OnionService,
+    listen_address: &TorSocketAddr,
+) -> Result<(
+    OnionService,
+    OnionAddress,
+    OnionServiceListener,
+    OnionService,
+    KeyMigration,
+)> {
+    let service_port = match onion_service.ports().first() {
+        Some(mapping) => mapping.virt_port(),
+        None => return Err(anyhow!("Onion service has no ports")),
+    };
+    // Storage keys for the service's data come from a secret made from the old key, which
+    // has to exist before the key changes
+    let dir = create_onion_service_dir(onion_service.name())?;
+    read_or_create_storage_secret(Path::new(&dir), onion_service.signing_key())?;
+    // Save the old key before the new one overwrites it
+    let retired = onion_service.with_name(&format!("{}-retired", onion_service.name()));
+    save_onion_service(&retired, service_port)?;
+    let (new_service, onion_address, listener) = create_onion_service(
+        control_connection,
+        OnionType::new_persistent(onion_service.name()),
+        Some(service_port),
+        Some(listen_address.clone()),
+    )
+    .await?;
+    let migration = KeyMigration::new(onion_service.signing_key(), new_service.signing_key());
+    Ok((new_service, onion_address, listener, retired, migration))
+}
+
+/// Like [`create_onion_service`], but only clients holding one of the keys in `clients` can
+/// connect to the service, or even tell whether it's online. Existing persistent services
+/// are re-added with the new list of clients.
+pub async fn create_private_onion_service(
+    connection: &mut ClientAuthConnection,
+    onion_type: OnionType,
+    service_port: Option<u16>,
+    listen_address: Option<TorSocketAddr>,
+    clients: &[ClientAuthPublicKey],
+) -> Result<(OnionService, OnionAddress, OnionServiceListener)> {
+    let (onion_service, onion_address, listen_address) = match onion_type {
+        OnionType::Transient | OnionType::Persistent { create: true, .. } => {
+            let service_port = required_service_port(service_port)?;
+            let listen_address = listen_address_or_default(listen_address, service_port)?;
+            let ports = [OnionServiceMapping::new(
+                service_port,
+                Some(listen_address.clone()),
+            )];
+            let onion_service = match onion_type {
+                OnionType::Persistent { name, .. } => {
+                    let service = connection
+                        .add_onion_service(None, &ports, true, clients)
+                        .await?;
+                    let onion_service = OnionService::new(&name, service);
+                    save_onion_service(&onion_service, service_port)?;
+                    onion_service
+                }
+                OnionType::Transient => connection
+                    .add_onion_service(None, &ports, false, clients)
+                    .await?
+                    .into(),
+            };
+            let onion_address = OnionAddress::new(onion_service.service_id().clone(), service_port);
+            (onion_service, onion_address, listen_address)
+        }
+        OnionType::Persistent {
+            name,
+            create: false,
+        } => {
+            let (onion_service, onion_address, listen_address) =
+                load_persistent_onion_service(&name, listen_address)?;
+            connection
+                .remove_onion_service(onion_service.service_id())
+                .await;
+            if !start_burner_session(&name)? {
+                burner::wipe(&name)?;
+                return Err(burner_spent(&name));
+            }
+            connection
+                .add_onion_service(
+                    Some(onion_service.signing_key()),
+                    onion_service.ports(),
+                    true,
+                    clients,
+                )
+                .await?;
+            (onion_service, onion_address, listen_address)
+        }
+    };
+    let listener = OnionServiceListener::bind(listen_address).await?;
+    Ok((onion_service, onion_address, listener))
+}
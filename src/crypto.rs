@@ -32,7 +32,7 @@ pub struct Cryptor {
     cipher: ChaCha20Poly1305,
 }
 
-const NONCE_SIZE: usize = 12;
+pub(crate) const NONCE_SIZE: usize = 12;
 
 impl Cryptor {
     pub fn new(key: &SymmetricKey) -> Self {
@@ -291,6 +291,18 @@ pub fn generate_session_hash(
     Ok(hasher.finalize().to_vec())
 }
 
+/// Generate a short code from the session hash which both peers can compare out-of-band to
+/// verify that nobody is intercepting the session
+pub fn session_verification_code(session_hash: &SessionHash) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update("verification".as_bytes());
+    hasher.update(session_hash);
+    let digest = hasher.finalize();
+    let value = u64::from_be_bytes(digest[..8].try_into().unwrap()) % 1_000_000_000_000_000;
+    let digits = format!("{:015}", value);
+    format!("{} {} {}", &digits[..5], &digits[5..10], &digits[10..])
+}
+
 pub async fn key_exchange<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
     mut reader: &mut R,
     mut writer: &mut W,
@@ -1,16 +1,42 @@
 use crate::{
-    chat::ChatMessage,
-    connection::{connect, handle_incoming_connection},
+    audit::AuditLog,
+    authorized_keys::AuthorizedKeys,
+    burner::{self, Burner},
+    chat::{ChatMessage, ConversationTtl, MessageId},
+    client_auth::{ClientAuthConnection, ClientAuthKey},
+    clock,
+    connection::{connect, handle_incoming_connection, Introduction, Keepalive},
+    contacts::{
+        Contact, ContactCard, ContactRequest, ContactRequestOutcome, ContactRequests, Contacts,
+        KeyMigration, MigrationState, RetiringIdentity,
+    },
+    feed::{Feed, FeedMessage, FeedPost},
+    group::{Group, GroupId, GroupMessage, Groups, MembershipAction, MembershipChange},
+    history::HistoryStore,
+    intro::{IdentityCard, IntroService},
+    invite::InviteUri,
     logger::{Level, LogMessage, Logger},
     onion_service::OnionService,
+    outbox::{DeliveryStatus, Outbox},
+    pow::Challenge,
+    profile::{Profile, ProfileMessage},
+    rate_limit::{Blocklist, HandshakeLimiter, HandshakeLimits},
+    room::{ModerationAction, Room, RoomId, RoomMessage, RoomRequest, RoomUpdate},
+    util::address_service_id,
 };
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, SubsecRound, Utc};
+use circular_queue::CircularQueue;
 use ed25519_dalek::{Signature, Signer};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Interval, MissedTickBehavior};
 use tor_client_lib::{
-    control_connection::{OnionAddress, OnionServiceStream, TorSocketAddr},
+    control_connection::{OnionAddress, OnionServiceStream, TorControlConnection, TorSocketAddr},
     TorServiceId,
 };
 
@@ -18,25 +44,187 @@ pub enum EngineEvent {
     NewConnection(Box<ConnectionInfo>, mpsc::UnboundedSender<ConnectionEvent>),
     SignatureRequest {
         tx: mpsc::UnboundedSender<ConnectionEvent>,
+        /// Which of our identities should sign
+        signer: TorServiceId,
         data_to_be_signed: Vec<u8>,
     },
-    Message(Box<ChatMessage>),
+    /// Should this peer solve a proof-of-work puzzle before we go on with the handshake?
+    ChallengeRequest {
+        tx: mpsc::UnboundedSender<ConnectionEvent>,
+        peer: TorServiceId,
+    },
+    Message(TorServiceId, Box<ChatMessage>),
+    DeliveryStatus {
+        recipient: TorServiceId,
+        id: MessageId,
+        status: DeliveryStatus,
+    },
+    TtlChanged(TorServiceId, ConversationTtl),
+    ClockOffset(TorServiceId, chrono::Duration),
+    Latency(TorServiceId, Duration),
+    Typing(TorServiceId, bool),
+    Group(TorServiceId, GroupMessage),
+    Room(TorServiceId, RoomMessage),
+    Feed(TorServiceId, FeedMessage),
+    KeyMigration(TorServiceId, Box<KeyMigration>),
+    ContactCard(TorServiceId, Box<ContactCard>),
+    Profile(TorServiceId, ProfileMessage),
+    Burned(TorServiceId),
+    Identity(TorServiceId, Box<IdentityCard>),
+    /// Introduction sent by a peer connecting to us
+    ContactRequest(TorServiceId, String),
+    ContactRequestOutcome(TorServiceId, ContactRequestOutcome),
+    MessagesExpired {
+        id: TorServiceId,
+        cutoff: DateTime<Utc>,
+        message_ids: Vec<MessageId>,
+    },
     Error(anyhow::Error),
+    /// Entry for the audit log
+    Audit(String),
     ConnectionClosed(Box<ConnectionInfo>),
     LogMessage(LogMessage),
 }
 
+impl EngineEvent {
+    /// The peer who sent whatever this event carries, for events which come from something
+    /// the peer sent us rather than from the connection itself
+    fn sent_by(&self) -> Option<&TorServiceId> {
+        match self {
+            Self::Message(id, _)
+            | Self::TtlChanged(id, _)
+            | Self::Typing(id, _)
+            | Self::Group(id, _)
+            | Self::Room(id, _)
+            | Self::Feed(id, _)
+            | Self::KeyMigration(id, _)
+            | Self::ContactCard(id, _)
+            | Self::Profile(id, _)
+            | Self::Burned(id)
+            | Self::Identity(id, _) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// The peer for events which would identify us to them by our main identity
+    fn needs_main_identity(&self) -> Option<&TorServiceId> {
+        match self {
+            Self::Group(id, _) | Self::Room(id, _) | Self::Feed(id, _) | Self::Profile(id, _) => {
+                Some(id)
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ConnectionEvent {
     Message(Box<ChatMessage>),
+    SetTtl(ConversationTtl),
+    Typing(bool),
+    Group(GroupMessage),
+    Room(RoomMessage),
+    Feed(FeedMessage),
+    KeyMigration(Box<KeyMigration>),
+    ContactCard(Box<ContactCard>),
+    Profile(ProfileMessage),
+    Burned,
+    Identity(Box<IdentityCard>),
     SignatureResponse(Signature),
+    ChallengeResponse(Option<Challenge>),
     ConnectionAuthorized,
+    ContactRejected,
     CloseConnection,
 }
 
 pub enum NetworkEvent {
     NewConnection(Box<ConnectionInfo>),
     Message(Box<ChatMessage>),
+    DeliveryStatus {
+        recipient: TorServiceId,
+        id: MessageId,
+        status: DeliveryStatus,
+    },
+    /// The disappearing-message timer for a conversation was changed by the peer. UIs should
+    /// call `ChatList::ttl_changed` so the chat's settings match.
+    TtlChanged {
+        id: TorServiceId,
+        ttl: Option<Duration>,
+    },
+    /// Messages in the conversation with `id` sent before `cutoff` have expired, and have been
+    /// removed from the history and outbox. UIs should call `Chat::expire` with the cutoff.
+    MessagesExpired {
+        id: TorServiceId,
+        cutoff: DateTime<Utc>,
+        message_ids: Vec<MessageId>,
+    },
+    /// The peer's clock differs from ours by more than the maximum allowed clock skew.
+    /// A positive skew means their clock is ahead of ours.
+    ClockSkew {
+        id: TorServiceId,
+        skew: chrono::Duration,
+    },
+    TypingStarted(TorServiceId),
+    TypingStopped(TorServiceId),
+    /// Message sent to a group chat. The message's recipient is always us.
+    GroupMessage {
+        group_id: GroupId,
+        message: Box<ChatMessage>,
+    },
+    /// We've been added to a group, or its membership has changed
+    GroupUpdated(GroupId),
+    /// We've been removed from a group
+    RemovedFromGroup(GroupId),
+    /// Something happened in a hosted room. `host` is us for rooms we're hosting.
+    RoomUpdate {
+        host: TorServiceId,
+        update: RoomUpdate,
+    },
+    /// A peer says they've moved to a new onion service ID. Nothing changes until the user
+    /// calls `Engine::accept_key_migration`.
+    KeyMigration {
+        old_id: TorServiceId,
+        new_id: TorServiceId,
+    },
+    /// Someone we haven't accepted yet has connected to us. The request is kept until
+    /// `Engine::accept_contact_request` or `Engine::reject_contact_request` is called.
+    ContactRequest(ContactRequest),
+    /// A peer we connected to has answered our contact request, or we gave up waiting
+    ContactRequestOutcome {
+        id: TorServiceId,
+        outcome: ContactRequestOutcome,
+    },
+    /// A contact has introduced us to someone. The card can be imported with
+    /// `Contacts::import_card`.
+    ContactCard {
+        from: TorServiceId,
+        card: Box<ContactCard>,
+    },
+    /// A peer has sent us a new version of their profile. It's been cached in our contacts
+    /// if they're in them.
+    ProfileUpdated(Box<Profile>),
+    /// A peer is destroying their identity, and won't be reachable at it again
+    ContactBurned(TorServiceId),
+    /// We've swapped permanent identities with a peer over a one-time intro service. If the
+    /// intro service was ours, it's been taken down.
+    IntroductionComplete {
+        intro: TorServiceId,
+        identity: Box<IdentityCard>,
+    },
+    /// The grace period for our old identity after `Engine::migrate_identity` is up. Its key
+    /// has been wiped, and its onion service taken down if the engine has a control
+    /// connection.
+    KeyRetired {
+        old_id: TorServiceId,
+    },
+    /// Our burner identity has expired. It should be destroyed with `Engine::burn`, or
+    /// `Engine::shutdown` will do it on the way out.
+    BurnerExpired,
+    /// New posts from a feed we're subscribed to, oldest first
+    FeedPosts {
+        publisher: TorServiceId,
+        posts: Vec<FeedPost>,
+    },
     ConnectionClosed(Box<ConnectionInfo>),
 }
 
@@ -50,15 +238,25 @@ pub enum ConnectionDirection {
 pub struct ConnectionInfo {
     address: TorSocketAddr,
     id: TorServiceId,
+    local_id: TorServiceId,
     direction: ConnectionDirection,
+    verification_code: String,
 }
 
 impl ConnectionInfo {
-    pub fn new(address: TorSocketAddr, id: &TorServiceId, direction: ConnectionDirection) -> Self {
+    pub fn new(
+        address: TorSocketAddr,
+        id: &TorServiceId,
+        local_id: &TorServiceId,
+        direction: ConnectionDirection,
+        verification_code: &str,
+    ) -> Self {
         Self {
             address,
             id: id.clone(),
+            local_id: local_id.clone(),
             direction,
+            verification_code: verification_code.to_string(),
         }
     }
 
@@ -66,9 +264,19 @@ impl ConnectionInfo {
         self.id.clone()
     }
 
+    /// Which of our identities the peer is connected to
+    pub fn local_id(&self) -> &TorServiceId {
+        &self.local_id
+    }
+
     pub fn direction(&self) -> &ConnectionDirection {
         &self.direction
     }
+
+    /// Code derived from the session keys, which both sides can compare to verify the session
+    pub fn verification_code(&self) -> &str {
+        &self.verification_code
+    }
 }
 
 pub struct TxLogger {
@@ -97,8 +305,69 @@ impl TxLogger {
     }
 }
 
+/// Number of received message IDs remembered for deduplication
+const RECEIVED_ID_CAPACITY: usize = 1000;
+
+/// Default difference between our clock and a peer's clock before we warn about it
+const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(120);
+
+/// Default time we wait for a peer to answer our contact request
+const DEFAULT_CONTACT_REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often conversations with a message timer are checked for expired messages
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// One of our intro services. Connections are accepted in the background until the
+// introduction is done.
+struct Intro {
+    used_by: Option<TorServiceId>,
+    accept_task: JoinHandle<()>,
+}
+
 pub struct Engine {
     channels: HashMap<TorServiceId, mpsc::UnboundedSender<ConnectionEvent>>,
+    outbox: Option<Outbox>,
+    history: Option<HistoryStore>,
+    received_ids: CircularQueue<MessageId>,
+    ttls: HashMap<TorServiceId, ConversationTtl>,
+    /// Messages sent or received this session in conversations with a message timer, which
+    /// the UI may still be showing
+    expiring: HashMap<TorServiceId, Vec<(DateTime<Utc>, MessageId)>>,
+    expiry_interval: Interval,
+    clock_offsets: HashMap<TorServiceId, chrono::Duration>,
+    max_clock_skew: Duration,
+    keepalive: Keepalive,
+    latencies: HashMap<TorServiceId, Duration>,
+    groups: Groups,
+    hosted_rooms: HashMap<RoomId, Room>,
+    joined_rooms: HashMap<RoomId, TorServiceId>,
+    feed: Option<Feed>,
+    contacts: Option<Contacts>,
+    client_auth: Option<ClientAuthConnection>,
+    contact_requests: ContactRequests,
+    contact_request_timeout: Duration,
+    proof_of_work: Option<u8>,
+    profile: Option<Profile>,
+    identities: HashMap<TorServiceId, OnionService>,
+    burner: Option<Burner>,
+    /// Whether `NetworkEvent::BurnerExpired` has been sent
+    burner_expired: bool,
+    /// Our one-time intro services
+    intros: HashMap<TorServiceId, Intro>,
+    /// Our own control connection, for creating and taking down intro services
+    control_connection: Option<TorControlConnection>,
+    /// Intro services we've connected to, and are expecting an identity card from
+    intro_requests: HashSet<TorServiceId>,
+    authorized_keys: Option<AuthorizedKeys>,
+    audit_log: Option<AuditLog>,
+    authorized: HashSet<TorServiceId>,
+    read_only: HashSet<TorServiceId>,
+    pending_migrations: HashMap<TorServiceId, KeyMigration>,
+    /// Our own key migration, and when the old identity stops redirecting to the new one
+    migration: MigrationState,
+    handshakes: Arc<Mutex<HandshakeLimiter>>,
+    subscribers: HashSet<TorServiceId>,
+    subscriptions: HashMap<TorServiceId, u64>,
     onion_service: OnionService,
     onion_service_address: OnionAddress,
     tor_proxy_address: SocketAddr,
@@ -118,9 +387,46 @@ impl Engine {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
 
         let id = onion_service.service_id().clone();
+        let mut expiry_interval = interval(EXPIRY_CHECK_INTERVAL);
+        expiry_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         Ok(Engine {
             channels: HashMap::new(),
+            outbox: None,
+            history: None,
+            received_ids: CircularQueue::with_capacity(RECEIVED_ID_CAPACITY),
+            ttls: HashMap::new(),
+            expiring: HashMap::new(),
+            expiry_interval,
+            clock_offsets: HashMap::new(),
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+            keepalive: Keepalive::default(),
+            latencies: HashMap::new(),
+            groups: Groups::new(),
+            hosted_rooms: HashMap::new(),
+            joined_rooms: HashMap::new(),
+            feed: None,
+            contacts: None,
+            client_auth: None,
+            control_connection: None,
+            contact_requests: ContactRequests::new(),
+            contact_request_timeout: DEFAULT_CONTACT_REQUEST_TIMEOUT,
+            proof_of_work: None,
+            profile: None,
+            identities: HashMap::new(),
+            burner: None,
+            burner_expired: false,
+            intros: HashMap::new(),
+            intro_requests: HashSet::new(),
+            authorized_keys: None,
+            audit_log: None,
+            authorized: HashSet::new(),
+            read_only: HashSet::new(),
+            pending_migrations: HashMap::new(),
+            migration: MigrationState::new(),
+            handshakes: Arc::new(Mutex::new(HandshakeLimiter::default())),
+            subscribers: HashSet::new(),
+            subscriptions: HashMap::new(),
             onion_service: onion_service.clone(),
             onion_service_address,
             tor_proxy_address,
@@ -139,110 +445,124 @@ impl Engine {
         self.onion_service_address.to_string()
     }
 
-    pub async fn get_event(&mut self, logger: &mut dyn Logger) -> Result<Option<NetworkEvent>> {
-        if let Some(engine_event) = self.rx.recv().await {
-            self.handle_engine_event(engine_event, logger).await
-        } else {
-            Ok(None)
+    /// Invite URI for our onion service, to share with people we want to connect to us
+    pub fn invite_uri(&self) -> InviteUri {
+        InviteUri::from(&self.onion_service_address)
+    }
+
+    /// Queue messages for contacts who aren't connected, and deliver them once they connect
+    pub fn use_outbox(&mut self, outbox: Outbox) {
+        self.outbox = Some(outbox);
+    }
+
+    pub fn outbox(&self) -> Option<&Outbox> {
+        self.outbox.as_ref()
+    }
+
+    /// Record all sent and received messages in a persistent history store. Message timers
+    /// are kept in the store too, so messages go on expiring after a restart.
+    pub fn use_history(&mut self, history: HistoryStore) {
+        for (id, ttl) in history.conversation_ttls() {
+            if self
+                .ttls
+                .get(id)
+                .is_none_or(|current| ttl.supersedes(current))
+            {
+                self.ttls.insert(id.clone(), ttl.clone());
+            }
         }
+        self.history = Some(history);
     }
 
-    pub async fn handle_incoming_connection(
-        &self,
-        stream: OnionServiceStream,
-        socket_addr: TorSocketAddr,
-    ) {
-        let tx = self.tx.clone();
-        let debug = self.debug;
-        let id = self.id.clone();
-        tokio::spawn(async move {
-            let mut logger = TxLogger::new(&tx, debug);
-            let mut connection =
-                match handle_incoming_connection(&id, stream, socket_addr, tx, &mut logger).await {
-                    Ok(connection) => connection,
-                    Err(error) => {
-                        logger.log_error(&format!("Error handling incoming connection: {}", error));
-                        return;
-                    }
-                };
+    pub fn history(&self) -> Option<&HistoryStore> {
+        self.history.as_ref()
+    }
 
-            connection.handle_connection(&mut logger).await;
-        });
+    pub fn history_mut(&mut self) -> Option<&mut HistoryStore> {
+        self.history.as_mut()
     }
 
-    pub async fn send_message(
+    /// Set the disappearing-message timer for the conversation with `id`. The timer is sent
+    /// to the peer now if they're connected, otherwise the next time they connect.
+    pub fn set_conversation_ttl(
         &mut self,
-        message: ChatMessage,
+        id: &TorServiceId,
+        ttl: Option<Duration>,
         logger: &mut dyn Logger,
     ) -> Result<()> {
-        match self.channels.get_mut(&message.recipient.clone()) {
-            Some(tx) => {
-                let _ = tx.send(ConnectionEvent::Message(Box::new(message)));
-            }
-            None => {
-                logger.log_error(&format!(
-                    "No mpsc::Sender found for id {}",
-                    message.recipient
-                ));
-            }
+        let ttl = ConversationTtl::new(ttl);
+        if let Some(tx) = self.channels.get(id) {
+            let _ = tx.send(ConnectionEvent::SetTtl(ttl.clone()));
+        } else {
+            logger.log_debug(&format!(
+                "{} isn't connected, will send message timer when they connect",
+                id
+            ));
+        }
+        self.store_ttl(id, ttl)
+    }
+
+    fn store_ttl(&mut self, id: &TorServiceId, ttl: ConversationTtl) -> Result<()> {
+        if let Some(history) = self.history.as_mut() {
+            history.set_conversation_ttl(id, &ttl)?;
         }
+        if ttl.ttl.is_none() {
+            self.expiring.remove(id);
+        }
+        self.ttls.insert(id.clone(), ttl);
         Ok(())
     }
 
-    pub async fn sign_data(
-        data_to_be_signed: &[u8],
-        engine_tx: &mpsc::UnboundedSender<EngineEvent>,
-    ) -> Result<Signature> {
-        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-        let event = EngineEvent::SignatureRequest {
-            tx,
-            data_to_be_signed: data_to_be_signed.to_vec(),
-        };
-        engine_tx.send(event).unwrap();
-        match rx.recv().await {
-            Some(ConnectionEvent::SignatureResponse(signature)) => Ok(signature),
-            Some(event) => Err(anyhow!(
-                "Got unexpected event {:?} in response to signature request",
-                event
-            )),
-            None => Err(anyhow!(
-                "Engine closed connection before servicing signature request"
-            )),
+    // Keep track of a message in a conversation with a message timer, so the UI is told
+    // when it expires
+    fn track_expiry(&mut self, id: &TorServiceId, message: &ChatMessage) {
+        if self.conversation_ttl(id).is_some() {
+            self.expiring
+                .entry(id.clone())
+                .or_default()
+                .push((message.date, message.id));
         }
     }
 
-    pub async fn connect(&mut self, address: &str) -> Result<()> {
-        let tx = self.tx.clone();
-        let address = address.to_string();
-        let debug = self.debug;
-        let proxy_address = self.tor_proxy_address;
-        let id = self.id.clone();
-        tokio::spawn(async move {
-            let mut logger = TxLogger::new(&tx, debug);
+    pub fn conversation_ttl(&self, id: &TorServiceId) -> Option<Duration> {
+        self.ttls.get(id).and_then(|ttl| ttl.ttl)
+    }
 
-            let mut connection = match connect(&address, &proxy_address, &id, tx, &mut logger).await
-            {
-                Ok(connection) => connection,
-                Err(error) => {
-                    logger.log_error(&format!("Error connecting to {}: {}", address, error));
-                    return;
-                }
-            };
+    /// Set how far a peer's clock can be from ours before we emit `NetworkEvent::ClockSkew`
+    pub fn set_max_clock_skew(&mut self, max_clock_skew: Duration) {
+        self.max_clock_skew = max_clock_skew;
+    }
 
-            connection.handle_connection(&mut logger).await;
-        });
+    /// Difference between the peer's clock and ours, measured when they connected, or when
+    /// they last sent us a message timestamped ahead of our clock
+    pub fn peer_clock_offset(&self, id: &TorServiceId) -> Option<chrono::Duration> {
+        self.clock_offsets.get(id).copied()
+    }
 
+    /// Set the ping interval and idle timeout used for new connections
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) -> Result<()> {
+        if keepalive.ping_interval.is_zero() {
+            return Err(anyhow!("Ping interval must be greater than zero"));
+        }
+        self.keepalive = keepalive;
         Ok(())
     }
 
-    pub async fn send_connection_authorized_message(
+    /// Most recently measured round-trip time to the peer
+    pub fn latency(&self, id: &TorServiceId) -> Option<Duration> {
+        self.latencies.get(id).copied()
+    }
+
+    /// Let the peer know we've started or stopped typing
+    pub fn send_typing_notification(
         &mut self,
         id: &TorServiceId,
+        typing: bool,
         logger: &mut dyn Logger,
     ) -> Result<()> {
-        match self.channels.get_mut(id) {
+        match self.channels.get(id) {
             Some(tx) => {
-                tx.send(ConnectionEvent::ConnectionAuthorized).unwrap();
+                let _ = tx.send(ConnectionEvent::Typing(typing));
                 Ok(())
             }
             None => {
@@ -252,65 +572,2772 @@ impl Engine {
         }
     }
 
-    pub async fn disconnect(&mut self, id: &TorServiceId, logger: &mut dyn Logger) -> Result<()> {
-        match self.channels.get_mut(id) {
-            Some(tx) => {
-                tx.send(ConnectionEvent::CloseConnection).unwrap();
-                Ok(())
-            }
+    /// Keep the groups we're in, so they survive restarts
+    pub fn use_groups(&mut self, groups: Groups) {
+        self.groups = groups;
+    }
+
+    pub fn group(&self, group_id: &GroupId) -> Option<&Group> {
+        self.groups.get(group_id)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &Group> {
+        self.groups.iter()
+    }
+
+    /// Create a group chat with `members`. Members who aren't connected will be told about
+    /// the group when they next connect. Contacts who know us by a pairwise identity can't
+    /// be added, since the group would tell them our main one.
+    pub fn create_group(
+        &mut self,
+        name: &str,
+        members: &[TorServiceId],
+        logger: &mut dyn Logger,
+    ) -> Result<GroupId> {
+        for member in members {
+            self.check_main_identity(member)?;
+        }
+        let group = Group::create(self.onion_service.signing_key(), name, members)?;
+        let group_id = *group.id();
+        let changes = group.changes().to_vec();
+        self.groups.insert(group)?;
+        logger.log_info(&format!("Created group {} ({})", name, group_id));
+        self.group_membership_changed(&group_id, &changes)?;
+        Ok(group_id)
+    }
+
+    pub fn add_group_member(
+        &mut self,
+        group_id: &GroupId,
+        member: &TorServiceId,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        self.check_main_identity(member)?;
+        self.change_group_membership(group_id, MembershipAction::Add(member.clone()), logger)
+    }
+
+    /// Remove someone from a group. Only the creator of the group can do this.
+    pub fn remove_group_member(
+        &mut self,
+        group_id: &GroupId,
+        member: &TorServiceId,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        self.change_group_membership(group_id, MembershipAction::Remove(member.clone()), logger)
+    }
+
+    pub fn leave_group(&mut self, group_id: &GroupId, logger: &mut dyn Logger) -> Result<()> {
+        self.change_group_membership(group_id, MembershipAction::Remove(self.id.clone()), logger)
+    }
+
+    fn change_group_membership(
+        &mut self,
+        group_id: &GroupId,
+        action: MembershipAction,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        let group = match self.groups.get_mut(group_id) {
+            Some(group) => group,
             None => {
-                logger.log_error(&format!("Unknown connection id '{}'", id));
-                Err(anyhow::anyhow!("Unknown connection id '{}'", id))
+                logger.log_error(&format!("Unknown group '{}'", group_id));
+                return Err(anyhow!("Unknown group '{}'", group_id));
             }
+        };
+        let change = MembershipChange::new(self.onion_service.signing_key(), group_id, action);
+        if group.apply_change(&change)? {
+            self.group_membership_changed(group_id, &[change])?;
         }
+        Ok(())
     }
 
-    async fn handle_engine_event(
+    // Pass membership changes on to the other members, and hand out a new sender key if
+    // anyone was removed, so they can't read anything sent from now on
+    fn group_membership_changed(
         &mut self,
-        engine_event: EngineEvent,
+        group_id: &GroupId,
+        applied: &[MembershipChange],
+    ) -> Result<()> {
+        let group = match self.groups.get_mut(group_id) {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+        let removed = applied
+            .iter()
+            .filter_map(|change| match &change.action {
+                MembershipAction::Remove(member) => Some(member.clone()),
+                _ => None,
+            })
+            .collect::<Vec<TorServiceId>>();
+        if !removed.is_empty() {
+            group.rotate_sender_key();
+        }
+
+        let group = match self.groups.get(group_id) {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+        let changes = GroupMessage::Changes(group.changes().to_vec());
+        for member in removed.iter().chain(group.members()) {
+            if *member == self.id || self.uses_pairwise_identity(member) {
+                continue;
+            }
+            if let Some(tx) = self.channels.get(member) {
+                let _ = tx.send(ConnectionEvent::Group(changes.clone()));
+                if group.is_member(member) {
+                    let _ = tx.send(ConnectionEvent::Group(GroupMessage::SenderKey(
+                        *group_id,
+                        group.sender_key().clone(),
+                    )));
+                }
+            }
+        }
+
+        if group.is_member(&self.id) {
+            self.groups.save()
+        } else {
+            self.groups.remove(group_id)
+        }
+    }
+
+    /// Send a message to everyone in a group, returning the message so it can be added to
+    /// the group's `Chat`. Members who aren't connected won't get it.
+    pub fn send_group_message(
+        &mut self,
+        group_id: &GroupId,
+        text: &str,
         logger: &mut dyn Logger,
-    ) -> Result<Option<NetworkEvent>> {
-        match engine_event {
-            EngineEvent::NewConnection(connection, thread_tx) => {
-                logger.log_debug(&format!("Got new connection from {}", connection.id()));
-                self.channels
-                    .insert(connection.id.clone(), thread_tx.clone());
-                Ok(Some(NetworkEvent::NewConnection(connection)))
+    ) -> Result<ChatMessage> {
+        let group = match self.groups.get(group_id) {
+            Some(group) => group,
+            None => {
+                logger.log_error(&format!("Unknown group '{}'", group_id));
+                return Err(anyhow!("Unknown group '{}'", group_id));
             }
-            EngineEvent::SignatureRequest {
-                tx,
-                data_to_be_signed,
-            } => {
-                let signature = self.onion_service.signing_key().sign(&data_to_be_signed);
-                tx.send(ConnectionEvent::SignatureResponse(signature))
-                    .unwrap();
-                Ok(None)
+        };
+        let message = ChatMessage::new(&self.id, &self.id, text.to_string());
+        let envelope = Box::new(group.encrypt(&message)?);
+        for member in group.members() {
+            if *member == self.id || self.uses_pairwise_identity(member) {
+                continue;
             }
-            EngineEvent::Message(chat_message) => Ok(Some(NetworkEvent::Message(chat_message))),
-            EngineEvent::Error(error) => {
-                logger.log_error(&format!("Got network error: {}", error));
-                Ok(None)
+            match self.channels.get(member) {
+                Some(tx) => {
+                    let _ = tx.send(ConnectionEvent::Group(GroupMessage::Message(
+                        envelope.clone(),
+                    )));
+                }
+                None => {
+                    logger.log_debug(&format!(
+                        "{} isn't connected, not sending group message {}",
+                        member, message.id
+                    ));
+                }
             }
-            EngineEvent::ConnectionClosed(connection) => {
-                match self.channels.get(&connection.id) {
-                    Some(_tx) => {
-                        self.channels.remove(&connection.id);
-                    }
-                    None => {
-                        logger.log_error(&format!(
-                            "Dropped unknown connection {}",
-                            connection.address
-                        ));
-                        self.channels.remove(&connection.id);
-                    }
+        }
+        Ok(message)
+    }
+
+    /// Start hosting a room on our onion service
+    pub fn host_room(&mut self, name: &str, invite_only: bool, logger: &mut dyn Logger) -> RoomId {
+        let room = Room::new(&self.id, name, invite_only);
+        let room_id = *room.id();
+        logger.log_info(&format!("Hosting room {} ({})", name, room_id));
+        self.hosted_rooms.insert(room_id, room);
+        room_id
+    }
+
+    pub fn hosted_room(&self, room_id: &RoomId) -> Option<&Room> {
+        self.hosted_rooms.get(room_id)
+    }
+
+    pub fn close_room(&mut self, room_id: &RoomId, logger: &mut dyn Logger) -> Result<()> {
+        match self.hosted_rooms.remove(room_id) {
+            Some(room) => {
+                for member in room.members() {
+                    self.send_room_update(member, RoomUpdate::Closed(*room_id));
                 }
-                logger.log_info(&format!("Lost connection to {}", connection.address));
-                Ok(Some(NetworkEvent::ConnectionClosed(connection)))
+                Ok(())
             }
-            EngineEvent::LogMessage(log_message) => {
-                logger.log(log_message);
-                Ok(None)
+            None => {
+                logger.log_error(&format!("Unknown room '{}'", room_id));
+                Err(anyhow!("Unknown room '{}'", room_id))
+            }
+        }
+    }
+
+    /// Host of a room we've joined (or asked to join)
+    pub fn room_host(&self, room_id: &RoomId) -> Option<&TorServiceId> {
+        self.joined_rooms.get(room_id)
+    }
+
+    /// Ask to join a room hosted by `host`, who we must already be connected to. We'll get a
+    /// `RoomUpdate::Joined` or `RoomUpdate::Denied` back.
+    pub fn join_room(
+        &mut self,
+        host: &TorServiceId,
+        room_id: &RoomId,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        self.check_main_identity(host)?;
+        self.send_room_request(host, RoomRequest::Join(*room_id), logger)?;
+        self.joined_rooms.insert(*room_id, host.clone());
+        Ok(())
+    }
+
+    pub fn leave_room(&mut self, room_id: &RoomId, logger: &mut dyn Logger) -> Result<()> {
+        let host = self.joined_room_host(room_id, logger)?;
+        self.joined_rooms.remove(room_id);
+        self.send_room_request(&host, RoomRequest::Leave(*room_id), logger)
+    }
+
+    /// Post a message to a room, returning the message so it can be added to the room's `Chat`
+    pub fn send_room_message(
+        &mut self,
+        room_id: &RoomId,
+        text: &str,
+        logger: &mut dyn Logger,
+    ) -> Result<ChatMessage> {
+        let message = ChatMessage::new(&self.id, &self.id, text.to_string());
+        let request = RoomRequest::Post {
+            room_id: *room_id,
+            message: Box::new(message.clone()),
+        };
+        if self.hosted_rooms.contains_key(room_id) {
+            self.apply_room_request(&self.id.clone(), request)?;
+        } else {
+            let host = self.joined_room_host(room_id, logger)?;
+            self.send_room_request(&host, request, logger)?;
+        }
+        Ok(message)
+    }
+
+    /// Moderate a room, either one we're hosting or one we're a moderator in
+    pub fn moderate_room(
+        &mut self,
+        room_id: &RoomId,
+        action: ModerationAction,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        let request = RoomRequest::Moderate {
+            room_id: *room_id,
+            action,
+        };
+        if self.hosted_rooms.contains_key(room_id) {
+            self.apply_room_request(&self.id.clone(), request)?;
+            Ok(())
+        } else {
+            let host = self.joined_room_host(room_id, logger)?;
+            self.send_room_request(&host, request, logger)
+        }
+    }
+
+    fn joined_room_host(&self, room_id: &RoomId, logger: &mut dyn Logger) -> Result<TorServiceId> {
+        match self.joined_rooms.get(room_id) {
+            Some(host) => Ok(host.clone()),
+            None => {
+                logger.log_error(&format!("Unknown room '{}'", room_id));
+                Err(anyhow!("Unknown room '{}'", room_id))
             }
         }
     }
+
+    fn send_room_request(
+        &self,
+        host: &TorServiceId,
+        request: RoomRequest,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        match self.channels.get(host) {
+            Some(tx) => {
+                let _ = tx.send(ConnectionEvent::Room(RoomMessage::Request(request)));
+                Ok(())
+            }
+            None => {
+                logger.log_error(&format!("Not connected to room host {}", host));
+                Err(anyhow!("Not connected to room host {}", host))
+            }
+        }
+    }
+
+    fn send_room_update(&self, member: &TorServiceId, update: RoomUpdate) {
+        if let Some(tx) = self.channels.get(member) {
+            let _ = tx.send(ConnectionEvent::Room(RoomMessage::Update(update)));
+        }
+    }
+
+    // Send an update to everyone in a room we're hosting, except `except`
+    fn broadcast_room_update(&self, room_id: &RoomId, update: &RoomUpdate, except: &TorServiceId) {
+        if let Some(room) = self.hosted_rooms.get(room_id) {
+            for member in room.members() {
+                if member != except {
+                    self.send_room_update(member, update.clone());
+                }
+            }
+        }
+    }
+
+    // Apply a request from a member of a room we're hosting, and tell the other members
+    // about it. Returns the update for our own UI.
+    fn apply_room_request(
+        &mut self,
+        from: &TorServiceId,
+        request: RoomRequest,
+    ) -> Result<Option<RoomUpdate>> {
+        let room_id = *request.room_id();
+        let room = match self.hosted_rooms.get_mut(&room_id) {
+            Some(room) => room,
+            None => return Err(anyhow!("No such room")),
+        };
+        let update = match request {
+            RoomRequest::Join(_) => {
+                if room.is_member(from) {
+                    return Ok(None);
+                }
+                room.join(from)?;
+                let joined = RoomUpdate::Joined {
+                    room_id,
+                    name: room.name().to_string(),
+                    members: room.members().cloned().collect(),
+                    history: room.history().cloned().collect(),
+                };
+                self.send_room_update(from, joined);
+                RoomUpdate::MemberJoined {
+                    room_id,
+                    member: from.clone(),
+                }
+            }
+            RoomRequest::Leave(_) => {
+                if !room.leave(from) {
+                    return Ok(None);
+                }
+                RoomUpdate::MemberLeft {
+                    room_id,
+                    member: from.clone(),
+                }
+            }
+            RoomRequest::Post { message, .. } => {
+                if message.sender != *from {
+                    return Err(anyhow!("Message sender doesn't match connection"));
+                }
+                room.post(*message.clone())?;
+                RoomUpdate::Message { room_id, message }
+            }
+            RoomRequest::Moderate { action, .. } => {
+                room.moderate(from, &action)?;
+                let name = room.name().to_string();
+                let update = RoomUpdate::Moderated {
+                    room_id,
+                    by: from.clone(),
+                    action: action.clone(),
+                };
+                match &action {
+                    // The target isn't a member yet (or any more), so tell them separately
+                    ModerationAction::Invite(target) => {
+                        self.send_room_update(target, RoomUpdate::Invited { room_id, name });
+                    }
+                    ModerationAction::Kick(target) | ModerationAction::Ban(target) => {
+                        self.send_room_update(target, update.clone());
+                    }
+                    _ => {}
+                }
+                update
+            }
+        };
+        self.broadcast_room_update(&room_id, &update, from);
+        Ok(Some(update))
+    }
+
+    /// Use an address book, so we can connect to contacts by nickname
+    pub fn use_contacts(&mut self, contacts: Contacts) {
+        self.contacts = Some(contacts);
+    }
+
+    pub fn contacts(&self) -> Option<&Contacts> {
+        self.contacts.as_ref()
+    }
+
+    pub fn contacts_mut(&mut self) -> Option<&mut Contacts> {
+        self.contacts.as_mut()
+    }
+
+    /// Use Tor client authorization. Our onion service should have been created with
+    /// [`create_private_onion_service`](crate::create_private_onion_service) on the same
+    /// connection. Client keys for our contacts' private services are installed as we
+    /// connect to them.
+    pub fn use_client_auth(&mut self, connection: ClientAuthConnection) {
+        self.client_auth = Some(connection);
+    }
+
+    /// Authorize a contact to connect to our private onion service. Returns the private key
+    /// to give them, out-of-band.
+    pub async fn authorize_contact(&mut self, id: &TorServiceId) -> Result<ClientAuthKey> {
+        let key = ClientAuthKey::generate();
+        self.set_authorized_client_key(id, Some(&key)).await?;
+        Ok(key)
+    }
+
+    /// Stop a contact from connecting to our private onion service. At least one contact has
+    /// to stay authorized.
+    pub async fn revoke_contact_authorization(
+        &mut self,
+        id: &TorServiceId,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        self.set_authorized_client_key(id, None).await?;
+        if let Some(tx) = self.channels.get(id) {
+            let _ = tx.send(ConnectionEvent::CloseConnection);
+        }
+        self.audit(&format!("Revoked client authorization for {}", id), logger);
+        Ok(())
+    }
+
+    async fn set_authorized_client_key(
+        &mut self,
+        id: &TorServiceId,
+        key: Option<&ClientAuthKey>,
+    ) -> Result<()> {
+        let (connection, contacts) = match (self.client_auth.as_mut(), self.contacts.as_mut()) {
+            (Some(connection), Some(contacts)) => (connection, contacts),
+            _ => {
+                return Err(anyhow!(
+                    "Client authorization needs contacts and a Tor connection"
+                ))
+            }
+        };
+        let previous = contacts
+            .get(id)
+            .and_then(|contact| contact.authorized_client_key);
+        contacts.set_authorized_client_key(id, key.map(ClientAuthKey::public_key))?;
+        if let Err(error) = connection
+            .set_authorized_clients(&self.onion_service, &contacts.authorized_client_keys())
+            .await
+        {
+            contacts.set_authorized_client_key(id, previous)?;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    // If we're connecting to a contact with a private onion service, give Tor our key for it
+    async fn install_client_auth_key(&mut self, address: &str) -> Result<()> {
+        let (connection, contacts) = match (self.client_auth.as_mut(), self.contacts.as_ref()) {
+            (Some(connection), Some(contacts)) => (connection, contacts),
+            _ => return Ok(()),
+        };
+        let contact = address_service_id(address).and_then(|id| contacts.get(&id));
+        if let Some(Contact {
+            id,
+            client_auth_key: Some(key),
+            ..
+        }) = contact
+        {
+            connection.add_client_auth(id, key).await?;
+        }
+        Ok(())
+    }
+
+    /// Use a pairwise identity, created by `identity::create_pairwise_identity`, for the
+    /// contact it was made for
+    pub fn add_identity(&mut self, onion_service: &OnionService) {
+        self.identities
+            .insert(onion_service.service_id().clone(), onion_service.clone());
+    }
+
+    /// Our identity as seen by `peer`: the pairwise identity recorded for them in our
+    /// contacts, or our main one
+    pub fn identity_for(&self, peer: &TorServiceId) -> TorServiceId {
+        self.identity_service(peer).service_id().clone()
+    }
+
+    /// Give the engine a Tor control connection of its own, used to create intro services
+    /// and take them down once they're done with
+    pub fn use_control_connection(&mut self, control_connection: TorControlConnection) {
+        self.control_connection = Some(control_connection);
+    }
+
+    /// Start a one-time intro service, returning the invite URI to post. It accepts one
+    /// connection, gives whoever makes it our permanent identity, and is taken down once
+    /// they've given us theirs (`NetworkEvent::IntroductionComplete`).
+    pub async fn create_intro_service(&mut self, service_port: u16) -> Result<InviteUri> {
+        let control_connection = match self.control_connection.as_mut() {
+            Some(control_connection) => control_connection,
+            None => return Err(anyhow!("Intro services need a Tor control connection")),
+        };
+        let intro = IntroService::create(control_connection, service_port).await?;
+        let intro_id = intro.id().clone();
+        let invite_uri = intro.invite_uri();
+        self.add_identity(intro.onion_service());
+
+        let handshakes = self.handshakes.clone();
+        let tx = self.tx.clone();
+        let debug = self.debug;
+        let keepalive = self.keepalive;
+        let id = intro_id.clone();
+        let accept_task = tokio::spawn(async move {
+            loop {
+                match intro.accept().await {
+                    Ok((stream, socket_addr)) => spawn_incoming_connection(
+                        &handshakes,
+                        &tx,
+                        debug,
+                        keepalive,
+                        &id,
+                        stream,
+                        socket_addr,
+                    ),
+                    Err(error) => {
+                        let _ = tx.send(EngineEvent::Error(anyhow!(
+                            "Error accepting connection on intro service {}: {}",
+                            id,
+                            error
+                        )));
+                        break;
+                    }
+                }
+            }
+        });
+        self.intros.insert(
+            intro_id,
+            Intro {
+                used_by: None,
+                accept_task,
+            },
+        );
+        Ok(invite_uri)
+    }
+
+    // Stop accepting connections on one of our intro services, and take it down
+    async fn close_intro_service(&mut self, intro: &TorServiceId, logger: &mut dyn Logger) {
+        if let Some(entry) = self.intros.remove(intro) {
+            entry.accept_task.abort();
+        }
+        self.identities.remove(intro);
+        if let Some(control_connection) = self.control_connection.as_mut() {
+            if let Err(error) = control_connection
+                .delete_onion_service(intro.as_str())
+                .await
+            {
+                logger.log_error(&format!(
+                    "Error removing intro service {}: {}",
+                    intro, error
+                ));
+            }
+        }
+    }
+
+    /// Connect to someone's one-time intro service, given its address or invite URI, to
+    /// swap permanent identities with them. The result comes back as
+    /// `NetworkEvent::IntroductionComplete`.
+    pub async fn connect_to_intro(&mut self, address: &str) -> Result<()> {
+        let address = if address.trim_start().starts_with("voynich:") {
+            address.parse::<InviteUri>()?.address()
+        } else {
+            address.to_string()
+        };
+        let intro = address_service_id(&address)
+            .ok_or_else(|| anyhow!("{} isn't an onion service address", address))?;
+        self.intro_requests.insert(intro);
+        self.send_contact_request(&address, "").await
+    }
+
+    fn identity_service(&self, peer: &TorServiceId) -> &OnionService {
+        self.contacts
+            .as_ref()
+            .and_then(|contacts| contacts.get(peer))
+            .and_then(|contact| contact.identity.as_ref())
+            .and_then(|name| {
+                self.identities
+                    .values()
+                    .find(|onion_service| onion_service.name() == name)
+            })
+            .unwrap_or(&self.onion_service)
+    }
+
+    // Groups, rooms, feeds and profiles all identify us by our main identity, so they're not
+    // used with contacts who know us by a pairwise one
+    fn uses_pairwise_identity(&self, peer: &TorServiceId) -> bool {
+        *self.identity_service(peer).service_id() != self.id
+    }
+
+    fn check_main_identity(&self, peer: &TorServiceId) -> Result<()> {
+        if self.uses_pairwise_identity(peer) {
+            return Err(anyhow!(
+                "{} knows us by a pairwise identity, which can't be used for groups, rooms, \
+                 feeds or profiles",
+                peer
+            ));
+        }
+        Ok(())
+    }
+
+    /// Only allow incoming connections from peers listed in `authorized_keys`. Anyone else is
+    /// disconnected before the application hears about them.
+    pub fn use_authorized_keys(&mut self, authorized_keys: AuthorizedKeys) {
+        self.authorized_keys = Some(authorized_keys);
+    }
+
+    /// Record rejected connections and other security-relevant events in `audit_log`
+    pub fn use_audit_log(&mut self, audit_log: AuditLog) {
+        self.audit_log = Some(audit_log);
+    }
+
+    fn audit(&self, entry: &str, logger: &mut dyn Logger) {
+        if let Some(audit_log) = self.audit_log.as_ref() {
+            if let Err(error) = audit_log.record(entry) {
+                logger.log_error(&format!("Error writing to audit log: {}", error));
+            }
+        }
+    }
+
+    /// Set the limits on incoming handshakes
+    pub fn set_handshake_limits(&mut self, limits: HandshakeLimits) {
+        self.handshakes.lock().unwrap().set_limits(limits);
+    }
+
+    /// Keep the blocklist in `blocklist`, so it survives restarts
+    pub fn use_blocklist(&mut self, blocklist: Blocklist) {
+        self.handshakes.lock().unwrap().set_blocklist(blocklist);
+    }
+
+    /// Refuse incoming connections from `id`, and disconnect them if they're connected
+    pub fn block(&mut self, id: &TorServiceId, logger: &mut dyn Logger) -> Result<()> {
+        self.handshakes.lock().unwrap().block(id)?;
+        if let Some(tx) = self.channels.get(id) {
+            let _ = tx.send(ConnectionEvent::CloseConnection);
+        }
+        logger.log_info(&format!("Blocked {}", id));
+        self.audit(&format!("Blocked {}", id), logger);
+        Ok(())
+    }
+
+    pub fn unblock(&mut self, id: &TorServiceId, logger: &mut dyn Logger) -> Result<()> {
+        self.handshakes.lock().unwrap().unblock(id)?;
+        self.audit(&format!("Unblocked {}", id), logger);
+        Ok(())
+    }
+
+    pub fn is_blocked(&self, id: &TorServiceId) -> bool {
+        self.handshakes.lock().unwrap().is_blocked(id)
+    }
+
+    /// Whether we've accepted the connection from `id`, either automatically or by
+    /// `send_connection_authorized_message`
+    pub fn is_connection_authorized(&self, id: &TorServiceId) -> bool {
+        self.authorized.contains(id)
+    }
+
+    /// Our onion service is a burner identity, created by `create_burner_onion_service`.
+    /// `NetworkEvent::BurnerExpired` is sent when it expires.
+    pub fn use_burner(&mut self, burner: Burner) {
+        self.burner = Some(burner);
+        self.burner_expired = false;
+    }
+
+    /// Destroy our burner identity, wiping its key, after disconnecting everyone. If `notify`
+    /// is set, connected peers are told first, so they know not to expect us back.
+    pub async fn burn(
+        &mut self,
+        control_connection: &mut TorControlConnection,
+        notify: bool,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        if self.burner.is_none() {
+            return Err(anyhow!("Our onion service isn't a burner identity"));
+        }
+        for (id, tx) in self.channels.drain() {
+            if notify {
+                logger.log_debug(&format!("Sending burn notice to {}", id));
+                let _ = tx.send(ConnectionEvent::Burned);
+            }
+            let _ = tx.send(ConnectionEvent::CloseConnection);
+        }
+        // Stay a burner if this fails, so that `shutdown` tries again
+        burner::burn(control_connection, &self.onion_service).await?;
+        self.burner = None;
+        Ok(())
+    }
+
+    /// Disconnect everyone, before exiting. A burner identity which has expired or used up
+    /// its sessions is destroyed, since it can't be started again.
+    pub async fn shutdown(
+        &mut self,
+        control_connection: &mut TorControlConnection,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        if self.burner_spent() {
+            logger.log_info("Burner identity is used up, destroying it");
+            return self.burn(control_connection, false, logger).await;
+        }
+        for (_, tx) in self.channels.drain() {
+            let _ = tx.send(ConnectionEvent::CloseConnection);
+        }
+        Ok(())
+    }
+
+    fn burner_spent(&self) -> bool {
+        self.burner.as_ref().is_some_and(Burner::is_spent)
+    }
+
+    // Whether a contact knows us by a pairwise identity. They're never told about our main
+    // one, even when it moves.
+    fn has_pairwise_identity(&self, peer: &TorServiceId) -> bool {
+        self.contacts
+            .as_ref()
+            .and_then(|contacts| contacts.get(peer))
+            .is_some_and(|contact| contact.identity.is_some())
+    }
+
+    /// Tell everyone we're connected to that we've moved to a new onion service ID, except
+    /// contacts who know us by a pairwise identity
+    pub fn announce_key_migration(&mut self, migration: &KeyMigration, logger: &mut dyn Logger) {
+        for (id, tx) in self.channels.iter() {
+            if self.has_pairwise_identity(id) {
+                continue;
+            }
+            logger.log_debug(&format!("Sending key migration to {}", id));
+            let _ = tx.send(ConnectionEvent::KeyMigration(Box::new(migration.clone())));
+        }
+    }
+
+    /// Switch to the new onion service made by `rotate_persistent_onion_service`, which also
+    /// returns the `retired` one. Everyone connected is sent the migration, and we connect to
+    /// the rest of our contacts from the old identity to send it to them; contacts who know us
+    /// by a pairwise identity aren't told. Until `grace_period` is up, anyone connecting to
+    /// the old identity is sent the migration and disconnected. After that they're refused,
+    /// the old key is wiped, and `NetworkEvent::KeyRetired` is sent.
+    ///
+    /// The migration is saved in the `MigrationState` given to `use_migration_state`.
+    /// Connections to the old service should still go to `handle_incoming_connection_for`,
+    /// with the old ID.
+    pub async fn migrate_identity(
+        &mut self,
+        onion_service: &OnionService,
+        onion_service_address: OnionAddress,
+        retired: &OnionService,
+        migration: KeyMigration,
+        grace_period: Duration,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        if migration.old_id != self.id
+            || migration.old_id != *retired.service_id()
+            || migration.new_id != *onion_service.service_id()
+        {
+            return Err(anyhow!(
+                "Key migration isn't from our identity to the new one"
+            ));
+        }
+        migration.verify()?;
+        let grace_period = chrono::Duration::from_std(grace_period)?;
+        self.migration.set(RetiringIdentity {
+            migration: migration.clone(),
+            until: Utc::now() + grace_period,
+            name: retired.name().to_string(),
+        })?;
+        self.identities.insert(self.id.clone(), retired.clone());
+        self.onion_service = onion_service.clone();
+        self.id = onion_service.service_id().clone();
+        self.onion_service_address = onion_service_address;
+
+        self.announce_key_migration(&migration, logger);
+        let offline = self
+            .contacts
+            .as_ref()
+            .map(|contacts| {
+                contacts
+                    .iter()
+                    .filter(|contact| {
+                        contact.identity.is_none() && !self.channels.contains_key(&contact.id)
+                    })
+                    .map(|contact| contact.address())
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+        for address in offline {
+            if let Err(error) = self.install_client_auth_key(&address).await {
+                logger.log_error(&format!("Error connecting to {}: {}", address, error));
+                continue;
+            }
+            self.spawn_connection(address, migration.old_id.clone(), "");
+        }
+
+        self.audit(
+            &format!(
+                "Migrated our identity from {} to {}",
+                migration.old_id, migration.new_id
+            ),
+            logger,
+        );
+        Ok(())
+    }
+
+    /// Pick up our own key migration where `migrate_identity` left it, after a restart. The
+    /// retired service should be started again and given to `add_identity`, so that it goes
+    /// on redirecting until its grace period is up.
+    pub fn use_migration_state(&mut self, migration: MigrationState) {
+        self.migration = migration;
+    }
+
+    /// Set our profile, and send it to connected contacts we share it with. Others get it
+    /// next time they connect.
+    pub fn set_profile(&mut self, profile: Profile, logger: &mut dyn Logger) -> Result<()> {
+        if profile.owner != self.id {
+            return Err(anyhow!("Profile belongs to {}", profile.owner));
+        }
+        profile.verify()?;
+        for (id, tx) in self.channels.iter() {
+            if self.authorized.contains(id)
+                && self.shares_profile_with(id)
+                && !self.uses_pairwise_identity(id)
+            {
+                logger.log_debug(&format!("Sending profile to {}", id));
+                let _ = tx.send(ConnectionEvent::Profile(ProfileMessage::Profile(Box::new(
+                    profile.clone(),
+                ))));
+            }
+        }
+        self.profile = Some(profile);
+        Ok(())
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    fn shares_profile_with(&self, id: &TorServiceId) -> bool {
+        self.contacts
+            .as_ref()
+            .and_then(|contacts| contacts.get(id))
+            .is_some_and(|contact| contact.share_profile)
+    }
+
+    /// Introduce our contact `subject` to our contact `recipient`, by sending them
+    /// `subject`'s contact card. If `mutual` is set, `subject` is sent `recipient`'s card
+    /// too, so they both know about the introduction. Both have to be connected.
+    pub fn introduce(
+        &self,
+        subject: &TorServiceId,
+        recipient: &TorServiceId,
+        mutual: bool,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        let contacts = match self.contacts.as_ref() {
+            Some(contacts) => contacts,
+            None => return Err(anyhow!("Introductions need an address book")),
+        };
+        let mut cards = vec![(subject, recipient)];
+        if mutual {
+            cards.push((recipient, subject));
+        }
+        let mut sends = Vec::new();
+        for (subject, recipient) in cards {
+            let contact = contacts
+                .get(subject)
+                .ok_or_else(|| anyhow!("Unknown contact {}", subject))?;
+            let tx = self
+                .channels
+                .get(recipient)
+                .ok_or_else(|| anyhow!("Not connected to {}", recipient))?;
+            let signing_key = self.identity_service(recipient).signing_key();
+            let card = ContactCard::new(signing_key, contact, recipient);
+            sends.push((tx, card));
+        }
+        for (tx, card) in sends {
+            logger.log_debug(&format!(
+                "Sending contact card for {} to {}",
+                card.id, card.recipient
+            ));
+            let _ = tx.send(ConnectionEvent::ContactCard(Box::new(card)));
+        }
+        Ok(())
+    }
+
+    /// Migration announced by `old_id`, waiting for the user to accept or reject it
+    pub fn pending_key_migration(&self, old_id: &TorServiceId) -> Option<&KeyMigration> {
+        self.pending_migrations.get(old_id)
+    }
+
+    /// Accept a contact's move to a new ID, updating their contact entry
+    pub fn accept_key_migration(
+        &mut self,
+        old_id: &TorServiceId,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        let migration = match self.pending_migrations.remove(old_id) {
+            Some(migration) => migration,
+            None => {
+                logger.log_error(&format!("No key migration pending for {}", old_id));
+                return Err(anyhow!("No key migration pending for {}", old_id));
+            }
+        };
+        if let Some(contacts) = self.contacts.as_mut() {
+            contacts.migrate(&migration)?;
+        }
+        if let Some(ttl) = self.ttls.remove(old_id) {
+            if let Some(history) = self.history.as_mut() {
+                history.remove_conversation_ttl(old_id)?;
+            }
+            self.store_ttl(&migration.new_id, ttl)?;
+        }
+        self.audit(
+            &format!(
+                "Accepted key migration from {} to {}",
+                migration.old_id, migration.new_id
+            ),
+            logger,
+        );
+        Ok(())
+    }
+
+    pub fn reject_key_migration(&mut self, old_id: &TorServiceId, logger: &mut dyn Logger) {
+        if let Some(migration) = self.pending_migrations.remove(old_id) {
+            self.audit(
+                &format!(
+                    "Rejected key migration from {} to {}",
+                    migration.old_id, migration.new_id
+                ),
+                logger,
+            );
+        }
+    }
+
+    /// Publish a feed from our onion service, which others can subscribe to
+    pub fn use_feed(&mut self, feed: Feed) {
+        self.feed = Some(feed);
+    }
+
+    pub fn feed(&self) -> Option<&Feed> {
+        self.feed.as_ref()
+    }
+
+    /// Post to our feed, sending the post to any subscribers who are connected. The others
+    /// will get it when they next connect.
+    pub fn publish(&mut self, content: &str, logger: &mut dyn Logger) -> Result<FeedPost> {
+        let feed = match self.feed.as_mut() {
+            Some(feed) => feed,
+            None => {
+                logger.log_error("Can't publish, no feed configured");
+                return Err(anyhow!("Can't publish, no feed configured"));
+            }
+        };
+        let post = feed.publish(self.onion_service.signing_key(), content)?;
+        for subscriber in self.subscribers.iter() {
+            if let Some(tx) = self.channels.get(subscriber) {
+                let _ = tx.send(ConnectionEvent::Feed(FeedMessage::Posts(
+                    vec![post.clone()],
+                )));
+            }
+        }
+        Ok(post)
+    }
+
+    /// Subscribe to the feed published by `publisher`, starting after the post with sequence
+    /// number `since` (0 for all posts). We catch up on the feed every time we connect to them.
+    pub fn subscribe(&mut self, publisher: &TorServiceId, since: u64) -> Result<()> {
+        self.check_main_identity(publisher)?;
+        if let Some(tx) = self.channels.get(publisher) {
+            let _ = tx.send(ConnectionEvent::Feed(FeedMessage::Subscribe { since }));
+        }
+        self.subscriptions.insert(publisher.clone(), since);
+        Ok(())
+    }
+
+    pub fn unsubscribe(&mut self, publisher: &TorServiceId) {
+        if self.subscriptions.remove(publisher).is_some() {
+            if let Some(tx) = self.channels.get(publisher) {
+                let _ = tx.send(ConnectionEvent::Feed(FeedMessage::Unsubscribe));
+            }
+        }
+    }
+
+    /// Feeds we're subscribed to, with the sequence number of the last post we've seen
+    pub fn subscriptions(&self) -> impl Iterator<Item = (&TorServiceId, &u64)> {
+        self.subscriptions.iter()
+    }
+
+    pub async fn get_event(&mut self, logger: &mut dyn Logger) -> Result<Option<NetworkEvent>> {
+        let check_expiry = self.ttls.values().any(|ttl| ttl.ttl.is_some())
+            || self
+                .burner
+                .as_ref()
+                .is_some_and(|burner| burner.expires.is_some() && !self.burner_expired)
+            || self.migration.get().is_some();
+        tokio::select! {
+            engine_event = self.rx.recv() => match engine_event {
+                Some(engine_event) => self.handle_engine_event(engine_event, logger).await,
+                None => Ok(None),
+            },
+            _ = self.expiry_interval.tick(), if check_expiry => {
+                self.expire_messages(logger);
+                if !self.burner_expired
+                    && self.burner.as_ref().is_some_and(|burner| burner.is_expired())
+                {
+                    // Only tell the UI once
+                    self.burner_expired = true;
+                    return Ok(Some(NetworkEvent::BurnerExpired));
+                }
+                if let Some(old_id) = self.end_grace_period(logger).await {
+                    return Ok(Some(NetworkEvent::KeyRetired { old_id }));
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    // Stop answering for our old identity once its grace period is up. Its key is wiped, so
+    // handshakes with it fail and it can't be brought back.
+    async fn end_grace_period(&mut self, logger: &mut dyn Logger) -> Option<TorServiceId> {
+        let retiring = match self.migration.get() {
+            Some(retiring) if retiring.until <= Utc::now() => retiring.clone(),
+            _ => return None,
+        };
+        let old_id = retiring.migration.old_id;
+        if let Err(error) = self.migration.clear() {
+            logger.log_error(&format!("Error saving key migration state: {}", error));
+        }
+        self.identities.remove(&old_id);
+        if let Err(error) = burner::wipe(&retiring.name) {
+            logger.log_error(&format!("Error wiping retired key {}: {}", old_id, error));
+        }
+        if let Some(control_connection) = self.control_connection.as_mut() {
+            if let Err(error) = control_connection
+                .delete_onion_service(old_id.as_str())
+                .await
+            {
+                logger.log_error(&format!(
+                    "Error removing retired onion service {}: {}",
+                    old_id, error
+                ));
+            }
+        }
+        self.audit(&format!("Retired our old identity {}", old_id), logger);
+        Some(old_id)
+    }
+
+    // Remove expired messages from the history and outbox, and let the UI know
+    fn expire_messages(&mut self, logger: &mut dyn Logger) {
+        let now = Utc::now();
+        for (id, ttl) in self.ttls.iter() {
+            let cutoff = match ttl.ttl.map(chrono::Duration::from_std) {
+                Some(Ok(ttl)) => now - ttl,
+                Some(Err(_)) | None => continue,
+            };
+            let mut message_ids = HashSet::new();
+            if let Some(history) = self.history.as_mut() {
+                match history.expire(id, cutoff) {
+                    Ok(expired) => message_ids.extend(expired),
+                    Err(error) => logger.log_error(&format!("Error expiring messages: {}", error)),
+                }
+            }
+            if let Some(outbox) = self.outbox.as_mut() {
+                match outbox.expire(id, cutoff) {
+                    Ok(expired) => message_ids.extend(expired),
+                    Err(error) => logger.log_error(&format!("Error expiring messages: {}", error)),
+                }
+            }
+            if let Some(expiring) = self.expiring.get_mut(id) {
+                expiring.retain(|(date, message_id)| {
+                    if *date < cutoff {
+                        message_ids.insert(*message_id);
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+            if message_ids.is_empty() {
+                continue;
+            }
+            let _ = self.tx.send(EngineEvent::MessagesExpired {
+                id: id.clone(),
+                cutoff,
+                message_ids: message_ids.into_iter().collect(),
+            });
+        }
+    }
+
+    // Update our clock with the timestamp of a message from `sender`, replacing it with the
+    // time we received it so a peer can't backdate messages to sort them before others. A
+    // message can arrive late but never early, so a timestamp ahead of our clock says the
+    // sender's clock is ahead of ours.
+    fn observe_timestamp(&self, sender: &TorServiceId, message: &mut ChatMessage) {
+        let offset = message.timestamp.wall - Utc::now().timestamp_millis();
+        if offset > 0 {
+            let _ = self.tx.send(EngineEvent::ClockOffset(
+                sender.clone(),
+                chrono::Duration::milliseconds(offset),
+            ));
+        }
+        message.timestamp = clock::observe(&message.timestamp);
+    }
+
+    // Record that we've received the message with `id`, returning false if we already had.
+    // With an outbox the IDs are kept across restarts, so redelivered messages are dropped.
+    fn record_received(&mut self, id: &MessageId, logger: &mut dyn Logger) -> bool {
+        if let Some(outbox) = self.outbox.as_mut() {
+            match outbox.record_received(id) {
+                Ok(new) => return new,
+                Err(error) => {
+                    logger.log_error(&format!("Error saving received message ID: {}", error));
+                }
+            }
+        }
+        if self.received_ids.iter().any(|received| received == id) {
+            return false;
+        }
+        self.received_ids.push(*id);
+        true
+    }
+
+    pub async fn handle_incoming_connection(
+        &self,
+        stream: OnionServiceStream,
+        socket_addr: TorSocketAddr,
+    ) {
+        self.handle_incoming_connection_for(&self.id, stream, socket_addr)
+            .await
+    }
+
+    /// Handle a connection to one of our pairwise identities
+    pub async fn handle_incoming_connection_for(
+        &self,
+        identity: &TorServiceId,
+        stream: OnionServiceStream,
+        socket_addr: TorSocketAddr,
+    ) {
+        spawn_incoming_connection(
+            &self.handshakes,
+            &self.tx,
+            self.debug,
+            self.keepalive,
+            identity,
+            stream,
+            socket_addr,
+        );
+    }
+
+    pub async fn send_message(
+        &mut self,
+        mut message: ChatMessage,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        if message.sender == self.id {
+            message.sender = self.identity_for(&message.recipient);
+        }
+        if let Some(history) = self.history.as_mut() {
+            if let Err(error) = history.append(&message.recipient, &message) {
+                logger.log_error(&format!("Error saving message to history: {}", error));
+            }
+        }
+        self.track_expiry(&message.recipient, &message);
+        if let Some(outbox) = self.outbox.as_mut() {
+            outbox.push(message.clone())?;
+        }
+        match self.channels.get_mut(&message.recipient.clone()) {
+            Some(tx) => {
+                let _ = tx.send(ConnectionEvent::Message(Box::new(message)));
+            }
+            None if self.outbox.is_some() => {
+                logger.log_debug(&format!(
+                    "Queued message {} for {}",
+                    message.id, message.recipient
+                ));
+                let _ = self.tx.send(EngineEvent::DeliveryStatus {
+                    recipient: message.recipient,
+                    id: message.id,
+                    status: DeliveryStatus::Queued,
+                });
+            }
+            None => {
+                logger.log_error(&format!(
+                    "No mpsc::Sender found for id {}",
+                    message.recipient
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the engine whether an incoming peer has to solve a proof-of-work puzzle
+    pub async fn proof_of_work_challenge(
+        peer: &TorServiceId,
+        engine_tx: &mpsc::UnboundedSender<EngineEvent>,
+    ) -> Result<Option<Challenge>> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        engine_tx
+            .send(EngineEvent::ChallengeRequest {
+                tx,
+                peer: peer.clone(),
+            })
+            .unwrap();
+        match rx.recv().await {
+            Some(ConnectionEvent::ChallengeResponse(challenge)) => Ok(challenge),
+            Some(event) => Err(anyhow!(
+                "Got unexpected event {:?} in response to challenge request",
+                event
+            )),
+            None => Err(anyhow!(
+                "Engine closed connection before servicing challenge request"
+            )),
+        }
+    }
+
+    pub async fn sign_data(
+        signer: &TorServiceId,
+        data_to_be_signed: &[u8],
+        engine_tx: &mpsc::UnboundedSender<EngineEvent>,
+    ) -> Result<Signature> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let event = EngineEvent::SignatureRequest {
+            tx,
+            signer: signer.clone(),
+            data_to_be_signed: data_to_be_signed.to_vec(),
+        };
+        engine_tx.send(event).unwrap();
+        match rx.recv().await {
+            Some(ConnectionEvent::SignatureResponse(signature)) => Ok(signature),
+            Some(event) => Err(anyhow!(
+                "Got unexpected event {:?} in response to signature request",
+                event
+            )),
+            None => Err(anyhow!(
+                "Engine closed connection before servicing signature request"
+            )),
+        }
+    }
+
+    /// Connect to a peer, given their `host:port` address, a `voynich:` invite URI, or their
+    /// nickname in our contacts
+    pub async fn connect(&mut self, address: &str) -> Result<()> {
+        self.send_contact_request(address, "").await
+    }
+
+    /// Connect to a peer, introducing ourselves with `introduction` in case they haven't
+    /// accepted us before. The outcome comes back as `NetworkEvent::ContactRequestOutcome`.
+    pub async fn send_contact_request(&mut self, address: &str, introduction: &str) -> Result<()> {
+        let address = if address.trim_start().starts_with("voynich:") {
+            address.parse::<InviteUri>()?.address()
+        } else {
+            match self.contacts.as_ref() {
+                Some(contacts) => contacts.resolve(address)?,
+                None => address.to_string(),
+            }
+        };
+        self.install_client_auth_key(&address).await?;
+        let id = match address_service_id(&address) {
+            Some(peer) => self.identity_for(&peer),
+            None => self.id.clone(),
+        };
+        self.spawn_connection(address, id, introduction);
+        Ok(())
+    }
+
+    // Connect to `address` as our identity `id`
+    fn spawn_connection(&self, address: String, id: TorServiceId, introduction: &str) {
+        let tx = self.tx.clone();
+        let debug = self.debug;
+        let proxy_address = self.tor_proxy_address;
+        let keepalive = self.keepalive;
+        let introduction = Introduction {
+            text: introduction.to_string(),
+            timeout: self.contact_request_timeout,
+        };
+        tokio::spawn(async move {
+            let mut logger = TxLogger::new(&tx, debug);
+
+            let mut connection = match connect(
+                &address,
+                &proxy_address,
+                &id,
+                tx,
+                keepalive,
+                introduction,
+                &mut logger,
+            )
+            .await
+            {
+                Ok(connection) => connection,
+                Err(error) => {
+                    logger.log_error(&format!("Error connecting to {}: {}", address, error));
+                    return;
+                }
+            };
+
+            connection.handle_connection(&mut logger).await;
+        });
+    }
+
+    pub async fn send_connection_authorized_message(
+        &mut self,
+        id: &TorServiceId,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        if self.authorized.contains(id) {
+            return Ok(());
+        }
+        match self.channels.get_mut(id) {
+            Some(tx) => {
+                tx.send(ConnectionEvent::ConnectionAuthorized).unwrap();
+                self.authorized.insert(id.clone());
+                if let Err(error) = self.contact_requests.accept(id) {
+                    logger.log_error(&format!("Error saving contact request: {}", error));
+                }
+                Ok(())
+            }
+            None => {
+                logger.log_error(&format!("Unknown connection id '{}'", id));
+                Err(anyhow::anyhow!("Unknown connection id '{}'", id))
+            }
+        }
+    }
+
+    /// Keep contact requests in a persistent store, rather than only for this session
+    pub fn use_contact_requests(&mut self, contact_requests: ContactRequests) {
+        self.contact_requests = contact_requests;
+    }
+
+    pub fn contact_requests(&self) -> &ContactRequests {
+        &self.contact_requests
+    }
+
+    /// Make peers who aren't in our contacts or authorized keys, and whose contact requests
+    /// we haven't accepted, solve a proof-of-work puzzle of `difficulty` bits before we sign
+    /// anything for them. `None` turns this off. The difficulty is capped at
+    /// [`MAX_DIFFICULTY`](crate::pow::MAX_DIFFICULTY), since peers won't solve anything
+    /// harder.
+    pub fn require_proof_of_work(&mut self, difficulty: Option<u8>) {
+        self.proof_of_work = difficulty;
+    }
+
+    fn is_known_peer(&self, id: &TorServiceId) -> bool {
+        self.contacts
+            .as_ref()
+            .is_some_and(|contacts| contacts.get(id).is_some())
+            || self
+                .authorized_keys
+                .as_ref()
+                .is_some_and(|authorized_keys| authorized_keys.get(id).is_some())
+            || self.contact_requests.is_accepted(id)
+    }
+
+    /// How long we wait for peers to answer our contact requests
+    pub fn set_contact_request_timeout(&mut self, timeout: Duration) {
+        self.contact_request_timeout = timeout;
+    }
+
+    /// Accept a contact request. If they're still connected they're let in, otherwise they
+    /// will be next time they connect.
+    pub async fn accept_contact_request(
+        &mut self,
+        id: &TorServiceId,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        self.contact_requests.accept(id)?;
+        self.audit(&format!("Accepted contact request from {}", id), logger);
+        if self.channels.contains_key(id) {
+            self.send_connection_authorized_message(id, logger).await?;
+        }
+        Ok(())
+    }
+
+    /// Reject a contact request, and disconnect them if they're still waiting
+    pub fn reject_contact_request(
+        &mut self,
+        id: &TorServiceId,
+        logger: &mut dyn Logger,
+    ) -> Result<()> {
+        self.contact_requests.reject(id)?;
+        self.audit(&format!("Rejected contact request from {}", id), logger);
+        if !self.authorized.contains(id) {
+            if let Some(tx) = self.channels.get(id) {
+                let _ = tx.send(ConnectionEvent::ContactRejected);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn disconnect(&mut self, id: &TorServiceId, logger: &mut dyn Logger) -> Result<()> {
+        match self.channels.get_mut(id) {
+            Some(tx) => {
+                tx.send(ConnectionEvent::CloseConnection).unwrap();
+                Ok(())
+            }
+            None => {
+                logger.log_error(&format!("Unknown connection id '{}'", id));
+                Err(anyhow::anyhow!("Unknown connection id '{}'", id))
+            }
+        }
+    }
+
+    async fn handle_engine_event(
+        &mut self,
+        engine_event: EngineEvent,
+        logger: &mut dyn Logger,
+    ) -> Result<Option<NetworkEvent>> {
+        if let Some(peer) = engine_event
+            .sent_by()
+            .filter(|peer| self.read_only.contains(*peer))
+        {
+            logger.log_debug(&format!("Dropping event from read-only peer {}", peer));
+            return Ok(None);
+        }
+        if let Some(peer) = engine_event
+            .needs_main_identity()
+            .filter(|peer| self.uses_pairwise_identity(peer))
+        {
+            logger.log_debug(&format!(
+                "Dropping event from {}, who knows us by a pairwise identity",
+                peer
+            ));
+            return Ok(None);
+        }
+        match engine_event {
+            EngineEvent::NewConnection(connection, thread_tx) => {
+                logger.log_debug(&format!("Got new connection from {}", connection.id()));
+                if let Some(RetiringIdentity { migration, .. }) = self
+                    .migration
+                    .get()
+                    .filter(|retiring| retiring.migration.old_id == *connection.local_id())
+                {
+                    // Point them at our new identity, and don't let them stay
+                    logger.log_debug(&format!("Sending key migration to {}", connection.id));
+                    if *connection.direction() == ConnectionDirection::Incoming {
+                        let _ = thread_tx.send(ConnectionEvent::ConnectionAuthorized);
+                    }
+                    let _ =
+                        thread_tx.send(ConnectionEvent::KeyMigration(Box::new(migration.clone())));
+                    let _ = thread_tx.send(ConnectionEvent::CloseConnection);
+                    return Ok(None);
+                }
+                if let Some(intro) = self.intros.get_mut(connection.local_id()) {
+                    if intro.used_by.is_some() {
+                        let _ = thread_tx.send(ConnectionEvent::CloseConnection);
+                        logger.log_warning(&format!(
+                            "Rejected connection from {} to used intro service {}",
+                            connection.id,
+                            connection.local_id()
+                        ));
+                        return Ok(None);
+                    }
+                    intro.used_by = Some(connection.id.clone());
+                    // Nothing else happens on an intro connection, so it doesn't go to the UI
+                    let card = IdentityCard::new(
+                        self.onion_service.signing_key(),
+                        &self.onion_service_address,
+                        connection.local_id(),
+                    );
+                    let _ = thread_tx.send(ConnectionEvent::ConnectionAuthorized);
+                    let _ = thread_tx.send(ConnectionEvent::Identity(Box::new(card)));
+                    self.authorized.insert(connection.id.clone());
+                    self.channels.insert(connection.id.clone(), thread_tx);
+                    return Ok(None);
+                }
+                if *connection.direction() == ConnectionDirection::Incoming {
+                    if let Some(authorized_keys) = self.authorized_keys.as_ref() {
+                        match authorized_keys.get(&connection.id).copied() {
+                            Some(options) => {
+                                if options.read_only {
+                                    self.read_only.insert(connection.id.clone());
+                                }
+                                if options.auto_accept
+                                    || self.contact_requests.is_accepted(&connection.id)
+                                {
+                                    let _ = thread_tx.send(ConnectionEvent::ConnectionAuthorized);
+                                    self.authorized.insert(connection.id.clone());
+                                }
+                            }
+                            None => {
+                                let _ = thread_tx.send(ConnectionEvent::CloseConnection);
+                                logger.log_warning(&format!(
+                                    "Rejected connection from unauthorized peer {}",
+                                    connection.id
+                                ));
+                                self.audit(
+                                    &format!(
+                                        "Rejected connection from {}: not in authorized keys",
+                                        connection.id
+                                    ),
+                                    logger,
+                                );
+                                return Ok(None);
+                            }
+                        }
+                    } else if self.contact_requests.is_accepted(&connection.id) {
+                        let _ = thread_tx.send(ConnectionEvent::ConnectionAuthorized);
+                        self.authorized.insert(connection.id.clone());
+                    }
+                } else {
+                    self.authorized.insert(connection.id.clone());
+                }
+                if let Some(contacts) = self.contacts.as_mut() {
+                    match contacts.trust_on_first_use(&connection.id) {
+                        Ok(true) => {
+                            logger.log_info(&format!("Pinned contact {}", connection.id));
+                        }
+                        Ok(false) => {}
+                        Err(error) => {
+                            logger.log_error(&format!("Error updating contact: {}", error));
+                        }
+                    }
+                }
+                self.channels
+                    .insert(connection.id.clone(), thread_tx.clone());
+                if let Some(ttl) = self.ttls.get(&connection.id) {
+                    // Make sure we agree on the message timer
+                    let _ = thread_tx.send(ConnectionEvent::SetTtl(ttl.clone()));
+                }
+                if !self.uses_pairwise_identity(&connection.id) {
+                    for group in self.groups.iter() {
+                        // Catch them up on any groups we share
+                        if group.is_member(&connection.id) {
+                            let _ = thread_tx.send(ConnectionEvent::Group(GroupMessage::Changes(
+                                group.changes().to_vec(),
+                            )));
+                            let _ = thread_tx.send(ConnectionEvent::Group(
+                                GroupMessage::SenderKey(*group.id(), group.sender_key().clone()),
+                            ));
+                        }
+                    }
+                    // Ask for their profile, if it's changed since we last saw it
+                    let profile_version = self
+                        .contacts
+                        .as_ref()
+                        .and_then(|contacts| contacts.get(&connection.id))
+                        .and_then(|contact| contact.profile.as_ref())
+                        .map_or(0, |profile| profile.version);
+                    let _ = thread_tx.send(ConnectionEvent::Profile(ProfileMessage::Have(
+                        profile_version,
+                    )));
+                    if let Some(since) = self.subscriptions.get(&connection.id) {
+                        let _ = thread_tx.send(ConnectionEvent::Feed(FeedMessage::Subscribe {
+                            since: *since,
+                        }));
+                    }
+                }
+                if let Some(outbox) = self.outbox.as_ref() {
+                    // Deliver anything queued while they were offline, oldest first
+                    for message in outbox.pending(&connection.id) {
+                        let _ = thread_tx.send(ConnectionEvent::Message(Box::new(message.clone())));
+                    }
+                }
+                Ok(Some(NetworkEvent::NewConnection(connection)))
+            }
+            EngineEvent::SignatureRequest {
+                tx,
+                signer,
+                data_to_be_signed,
+            } => {
+                let onion_service = if signer == self.id {
+                    &self.onion_service
+                } else {
+                    match self.identities.get(&signer) {
+                        Some(onion_service) => onion_service,
+                        None => {
+                            // Dropping tx fails the handshake
+                            logger.log_error(&format!("No identity {} to sign with", signer));
+                            return Ok(None);
+                        }
+                    }
+                };
+                let signature = onion_service.signing_key().sign(&data_to_be_signed);
+                tx.send(ConnectionEvent::SignatureResponse(signature))
+                    .unwrap();
+                Ok(None)
+            }
+            EngineEvent::ChallengeRequest { tx, peer } => {
+                let challenge = match self.proof_of_work {
+                    Some(difficulty) if !self.is_known_peer(&peer) => {
+                        Some(Challenge::new(difficulty))
+                    }
+                    _ => None,
+                };
+                let _ = tx.send(ConnectionEvent::ChallengeResponse(challenge));
+                Ok(None)
+            }
+            EngineEvent::Message(from, mut chat_message) => {
+                chat_message.received = Some(Utc::now().round_subsecs(0));
+                self.observe_timestamp(&from, &mut chat_message);
+                if !self.record_received(&chat_message.id, logger) {
+                    logger.log_debug(&format!("Dropping duplicate message {}", chat_message.id));
+                    return Ok(None);
+                }
+                if let Some(history) = self.history.as_mut() {
+                    if let Err(error) = history.append(&from, &chat_message) {
+                        logger.log_error(&format!("Error saving message to history: {}", error));
+                    }
+                }
+                self.track_expiry(&from, &chat_message);
+                Ok(Some(NetworkEvent::Message(chat_message)))
+            }
+            EngineEvent::DeliveryStatus {
+                recipient,
+                id,
+                status,
+            } => {
+                if status == DeliveryStatus::Delivered {
+                    if let Some(outbox) = self.outbox.as_mut() {
+                        outbox.remove(&recipient, &id)?;
+                    }
+                }
+                Ok(Some(NetworkEvent::DeliveryStatus {
+                    recipient,
+                    id,
+                    status,
+                }))
+            }
+            EngineEvent::TtlChanged(id, mut ttl) => {
+                // Don't let a timer set in the future win over every later change
+                ttl.set_at = ttl.set_at.min(Utc::now().trunc_subsecs(0));
+                if self
+                    .ttls
+                    .get(&id)
+                    .is_some_and(|current| !ttl.supersedes(current))
+                {
+                    return Ok(None);
+                }
+                logger.log_info(&format!("Message timer for {} changed", id));
+                let duration = ttl.ttl;
+                if let Err(error) = self.store_ttl(&id, ttl) {
+                    logger.log_error(&format!("Error saving message timer: {}", error));
+                }
+                Ok(Some(NetworkEvent::TtlChanged { id, ttl: duration }))
+            }
+            EngineEvent::Latency(id, latency) => {
+                self.latencies.insert(id, latency);
+                Ok(None)
+            }
+            EngineEvent::Group(from, group_message) => {
+                self.handle_group_message(&from, group_message, logger)
+            }
+            EngineEvent::Room(from, RoomMessage::Request(request)) => {
+                let room_id = *request.room_id();
+                match self.apply_room_request(&from, request) {
+                    Ok(update) => Ok(update.map(|update| NetworkEvent::RoomUpdate {
+                        host: self.id.clone(),
+                        update,
+                    })),
+                    Err(error) => {
+                        self.send_room_update(
+                            &from,
+                            RoomUpdate::Denied {
+                                room_id,
+                                reason: error.to_string(),
+                            },
+                        );
+                        Ok(None)
+                    }
+                }
+            }
+            EngineEvent::Room(from, RoomMessage::Update(update)) => {
+                self.handle_room_update(&from, update, logger)
+            }
+            EngineEvent::Feed(from, feed_message) => {
+                self.handle_feed_message(&from, feed_message, logger)
+            }
+            EngineEvent::KeyMigration(from, migration) => {
+                if from != migration.old_id && from != migration.new_id {
+                    logger.log_warning(&format!(
+                        "{} sent a key migration for {}",
+                        from, migration.old_id
+                    ));
+                    return Ok(None);
+                }
+                if let Err(error) = migration.verify() {
+                    logger.log_warning(&format!("Bad key migration from {}: {}", from, error));
+                    self.audit(
+                        &format!("Rejected badly signed key migration from {}", from),
+                        logger,
+                    );
+                    return Ok(None);
+                }
+                if self.pending_migrations.get(&migration.old_id) == Some(&migration) {
+                    return Ok(None);
+                }
+                let old_id = migration.old_id.clone();
+                let new_id = migration.new_id.clone();
+                self.pending_migrations.insert(old_id.clone(), *migration);
+                Ok(Some(NetworkEvent::KeyMigration { old_id, new_id }))
+            }
+            EngineEvent::Profile(from, profile_message) => {
+                self.handle_profile_message(&from, profile_message, logger)
+            }
+            EngineEvent::Identity(from, card) => {
+                self.handle_identity_card(&from, *card, logger).await
+            }
+            EngineEvent::Burned(from) => Ok(Some(NetworkEvent::ContactBurned(from))),
+            EngineEvent::ContactCard(from, card) => {
+                if card.introducer != from || card.recipient != self.identity_for(&from) {
+                    logger.log_warning(&format!(
+                        "{} sent a contact card from {} for {}",
+                        from, card.introducer, card.recipient
+                    ));
+                    return Ok(None);
+                }
+                if let Err(error) = card.verify() {
+                    logger.log_warning(&format!("Bad contact card from {}: {}", from, error));
+                    return Ok(None);
+                }
+                Ok(Some(NetworkEvent::ContactCard { from, card }))
+            }
+            EngineEvent::Typing(id, true) => Ok(Some(NetworkEvent::TypingStarted(id))),
+            EngineEvent::Typing(id, false) => Ok(Some(NetworkEvent::TypingStopped(id))),
+            EngineEvent::ClockOffset(id, offset) => {
+                self.clock_offsets.insert(id.clone(), offset);
+                let too_far = match chrono::Duration::from_std(self.max_clock_skew) {
+                    Ok(max_clock_skew) => offset.abs() > max_clock_skew,
+                    Err(_) => false,
+                };
+                if too_far {
+                    logger.log_warning(&format!(
+                        "Clock for {} is off from ours by {} seconds",
+                        id,
+                        offset.num_seconds()
+                    ));
+                    Ok(Some(NetworkEvent::ClockSkew { id, skew: offset }))
+                } else {
+                    Ok(None)
+                }
+            }
+            EngineEvent::MessagesExpired {
+                id,
+                cutoff,
+                message_ids,
+            } => Ok(Some(NetworkEvent::MessagesExpired {
+                id,
+                cutoff,
+                message_ids,
+            })),
+            EngineEvent::Error(error) => {
+                logger.log_error(&format!("Got network error: {}", error));
+                Ok(None)
+            }
+            EngineEvent::Audit(entry) => {
+                logger.log_warning(&entry);
+                self.audit(&entry, logger);
+                Ok(None)
+            }
+            EngineEvent::ContactRequest(id, introduction) => {
+                if self.authorized.contains(&id) {
+                    return Ok(None);
+                }
+                let request = ContactRequest::new(&id, &introduction);
+                if let Err(error) = self.contact_requests.add(request.clone()) {
+                    logger.log_error(&format!("Error saving contact request: {}", error));
+                }
+                Ok(Some(NetworkEvent::ContactRequest(request)))
+            }
+            EngineEvent::ContactRequestOutcome(id, outcome) => {
+                Ok(Some(NetworkEvent::ContactRequestOutcome { id, outcome }))
+            }
+            EngineEvent::ConnectionClosed(connection) => {
+                match self.channels.get(&connection.id) {
+                    Some(_tx) => {
+                        self.channels.remove(&connection.id);
+                    }
+                    None => {
+                        logger.log_error(&format!(
+                            "Dropped unknown connection {}",
+                            connection.address
+                        ));
+                        self.channels.remove(&connection.id);
+                    }
+                }
+                if let Some(intro) = self
+                    .intros
+                    .get_mut(connection.local_id())
+                    .filter(|intro| intro.used_by.as_ref() == Some(&connection.id))
+                {
+                    // They left before the introduction was done, so let someone else use it
+                    intro.used_by = None;
+                }
+                self.latencies.remove(&connection.id);
+                self.authorized.remove(&connection.id);
+                self.read_only.remove(&connection.id);
+                self.subscribers.remove(&connection.id);
+                self.joined_rooms.retain(|_, host| *host != connection.id);
+                let left = self
+                    .hosted_rooms
+                    .iter_mut()
+                    .filter_map(|(room_id, room)| room.leave(&connection.id).then_some(*room_id))
+                    .collect::<Vec<RoomId>>();
+                for room_id in left {
+                    let update = RoomUpdate::MemberLeft {
+                        room_id,
+                        member: connection.id.clone(),
+                    };
+                    self.broadcast_room_update(&room_id, &update, &connection.id);
+                }
+                logger.log_info(&format!("Lost connection to {}", connection.address));
+                Ok(Some(NetworkEvent::ConnectionClosed(connection)))
+            }
+            EngineEvent::LogMessage(log_message) => {
+                logger.log(log_message);
+                Ok(None)
+            }
+        }
+    }
+
+    fn handle_group_message(
+        &mut self,
+        from: &TorServiceId,
+        group_message: GroupMessage,
+        logger: &mut dyn Logger,
+    ) -> Result<Option<NetworkEvent>> {
+        match group_message {
+            GroupMessage::Changes(changes) => {
+                let group_id = match changes.first() {
+                    Some(change) => change.group_id,
+                    None => return Ok(None),
+                };
+                let applied = match self.groups.get_mut(&group_id) {
+                    Some(group) => Ok(group.apply(&changes, logger)),
+                    None => match Group::from_changes(&changes, logger) {
+                        Ok(group) if group.is_member(&self.id) => {
+                            let applied = group.changes().to_vec();
+                            self.groups.insert(group)?;
+                            Ok(applied)
+                        }
+                        Ok(_) => Ok(Vec::new()),
+                        Err(error) => Err(error),
+                    },
+                };
+                let applied = match applied {
+                    Ok(applied) => applied,
+                    Err(error) => {
+                        logger.log_warning(&format!(
+                            "Bad membership changes for group {} from {}: {}",
+                            group_id, from, error
+                        ));
+                        return Ok(None);
+                    }
+                };
+                if applied.is_empty() {
+                    return Ok(None);
+                }
+                self.group_membership_changed(&group_id, &applied)?;
+                if self.groups.contains(&group_id) {
+                    Ok(Some(NetworkEvent::GroupUpdated(group_id)))
+                } else {
+                    logger.log_info(&format!("Removed from group {}", group_id));
+                    Ok(Some(NetworkEvent::RemovedFromGroup(group_id)))
+                }
+            }
+            GroupMessage::SenderKey(group_id, key) => {
+                match self.groups.get_mut(&group_id) {
+                    Some(group) => {
+                        if let Err(error) = group.set_member_key(from, key) {
+                            logger.log_warning(&format!("Rejected sender key: {}", error));
+                        } else {
+                            self.groups.save()?;
+                        }
+                    }
+                    None => logger.log_debug(&format!(
+                        "Got sender key for unknown group {} from {}",
+                        group_id, from
+                    )),
+                }
+                Ok(None)
+            }
+            GroupMessage::Message(envelope) => {
+                if envelope.sender != *from {
+                    logger.log_warning(&format!(
+                        "{} sent a group message claiming to be from {}",
+                        from, envelope.sender
+                    ));
+                    return Ok(None);
+                }
+                let group = match self.groups.get(&envelope.group_id) {
+                    Some(group) => group,
+                    None => {
+                        logger.log_debug(&format!(
+                            "Got message for unknown group {} from {}",
+                            envelope.group_id, from
+                        ));
+                        return Ok(None);
+                    }
+                };
+                let mut message = match group.decrypt(&envelope) {
+                    Ok(message) => message,
+                    Err(error) => {
+                        logger.log_error(&format!("Error decrypting group message: {}", error));
+                        return Ok(None);
+                    }
+                };
+                message.recipient = self.id.clone();
+                message.received = Some(Utc::now().round_subsecs(0));
+                self.observe_timestamp(from, &mut message);
+                if !self.record_received(&message.id, logger) {
+                    return Ok(None);
+                }
+                Ok(Some(NetworkEvent::GroupMessage {
+                    group_id: envelope.group_id,
+                    message: Box::new(message),
+                }))
+            }
+        }
+    }
+
+    fn handle_room_update(
+        &mut self,
+        from: &TorServiceId,
+        mut update: RoomUpdate,
+        logger: &mut dyn Logger,
+    ) -> Result<Option<NetworkEvent>> {
+        let room_id = match &update {
+            // Anyone can invite us to their room
+            RoomUpdate::Invited { .. } => {
+                return Ok(Some(NetworkEvent::RoomUpdate {
+                    host: from.clone(),
+                    update,
+                }))
+            }
+            RoomUpdate::Joined { room_id, .. }
+            | RoomUpdate::Message { room_id, .. }
+            | RoomUpdate::MemberJoined { room_id, .. }
+            | RoomUpdate::MemberLeft { room_id, .. }
+            | RoomUpdate::Moderated { room_id, .. }
+            | RoomUpdate::Denied { room_id, .. }
+            | RoomUpdate::Closed(room_id) => *room_id,
+        };
+        if self.joined_rooms.get(&room_id) != Some(from) {
+            logger.log_warning(&format!(
+                "Got update for room {} from {}, who isn't its host",
+                room_id, from
+            ));
+            return Ok(None);
+        }
+        match &mut update {
+            RoomUpdate::Message { message, .. } => {
+                let sender = message.sender.clone();
+                self.observe_timestamp(&sender, message);
+            }
+            RoomUpdate::Moderated {
+                action: ModerationAction::Kick(target) | ModerationAction::Ban(target),
+                ..
+            } if *target == self.id => {
+                self.joined_rooms.remove(&room_id);
+            }
+            RoomUpdate::Denied { .. } | RoomUpdate::Closed(_) => {
+                self.joined_rooms.remove(&room_id);
+            }
+            _ => {}
+        }
+        Ok(Some(NetworkEvent::RoomUpdate {
+            host: from.clone(),
+            update,
+        }))
+    }
+
+    fn handle_feed_message(
+        &mut self,
+        from: &TorServiceId,
+        feed_message: FeedMessage,
+        logger: &mut dyn Logger,
+    ) -> Result<Option<NetworkEvent>> {
+        match feed_message {
+            FeedMessage::Subscribe { since } => {
+                let feed = match self.feed.as_ref() {
+                    Some(feed) => feed,
+                    None => {
+                        logger.log_debug(&format!(
+                            "{} tried to subscribe, but we have no feed",
+                            from
+                        ));
+                        return Ok(None);
+                    }
+                };
+                logger.log_debug(&format!("{} subscribed to our feed", from));
+                let missed = feed.since(since);
+                if let Some(tx) = self.channels.get(from) {
+                    if !missed.is_empty() {
+                        let _ = tx.send(ConnectionEvent::Feed(FeedMessage::Posts(missed.to_vec())));
+                    }
+                }
+                self.subscribers.insert(from.clone());
+                Ok(None)
+            }
+            FeedMessage::Unsubscribe => {
+                self.subscribers.remove(from);
+                Ok(None)
+            }
+            FeedMessage::Posts(posts) => {
+                let cursor = match self.subscriptions.get_mut(from) {
+                    Some(cursor) => cursor,
+                    None => {
+                        logger.log_debug(&format!("Ignoring feed posts from {}", from));
+                        return Ok(None);
+                    }
+                };
+                let mut new_posts = Vec::new();
+                for post in posts {
+                    if post.sequence <= *cursor {
+                        continue;
+                    }
+                    if post.publisher != *from || post.verify().is_err() {
+                        logger.log_warning(&format!("Bad feed post from {}", from));
+                        continue;
+                    }
+                    *cursor = post.sequence;
+                    new_posts.push(post);
+                }
+                if new_posts.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(NetworkEvent::FeedPosts {
+                        publisher: from.clone(),
+                        posts: new_posts,
+                    }))
+                }
+            }
+        }
+    }
+
+    fn handle_profile_message(
+        &mut self,
+        from: &TorServiceId,
+        profile_message: ProfileMessage,
+        logger: &mut dyn Logger,
+    ) -> Result<Option<NetworkEvent>> {
+        match profile_message {
+            ProfileMessage::Have(version) => {
+                if let Some(profile) = self.profile.as_ref() {
+                    if profile.version > version && self.shares_profile_with(from) {
+                        if let Some(tx) = self.channels.get(from) {
+                            let _ = tx.send(ConnectionEvent::Profile(ProfileMessage::Profile(
+                                Box::new(profile.clone()),
+                            )));
+                        }
+                    }
+                }
+                Ok(None)
+            }
+            ProfileMessage::Profile(profile) => {
+                if profile.owner != *from {
+                    logger.log_warning(&format!("{} sent a profile for {}", from, profile.owner));
+                    return Ok(None);
+                }
+                if let Err(error) = profile.verify() {
+                    logger.log_warning(&format!("Bad profile from {}: {}", from, error));
+                    return Ok(None);
+                }
+                if let Some(contacts) = self.contacts.as_mut() {
+                    if contacts.get(from).is_some() {
+                        match contacts.update_profile(&profile) {
+                            Ok(true) => {}
+                            Ok(false) => return Ok(None),
+                            Err(error) => {
+                                logger.log_error(&format!("Error saving profile: {}", error));
+                            }
+                        }
+                    }
+                }
+                Ok(Some(NetworkEvent::ProfileUpdated(profile)))
+            }
+        }
+    }
+
+    // Identity card from a peer we've met through a one-time intro service, either theirs or
+    // ours
+    async fn handle_identity_card(
+        &mut self,
+        from: &TorServiceId,
+        card: IdentityCard,
+        logger: &mut dyn Logger,
+    ) -> Result<Option<NetworkEvent>> {
+        let hosted = self
+            .intros
+            .iter()
+            .find(|(_, intro)| intro.used_by.as_ref() == Some(from))
+            .map(|(intro, _)| intro.clone());
+        let expected_intro = match hosted.clone() {
+            Some(intro) => intro,
+            None if self.intro_requests.remove(from) => from.clone(),
+            None => {
+                logger.log_warning(&format!("Unexpected identity card from {}", from));
+                return Ok(None);
+            }
+        };
+        if card.intro != expected_intro {
+            logger.log_warning(&format!(
+                "{} sent an identity card for intro service {}",
+                from, card.intro
+            ));
+            return Ok(None);
+        }
+        if let Err(error) = card.verify() {
+            logger.log_warning(&format!("Bad identity card from {}: {}", from, error));
+            return Ok(None);
+        }
+        match hosted {
+            Some(intro) => {
+                // That's all the intro service is for
+                if let Some(tx) = self.channels.get(from) {
+                    let _ = tx.send(ConnectionEvent::CloseConnection);
+                }
+                self.close_intro_service(&intro, logger).await;
+            }
+            None => {
+                let our_card = IdentityCard::new(
+                    self.onion_service.signing_key(),
+                    &self.onion_service_address,
+                    from,
+                );
+                if let Some(tx) = self.channels.get(from) {
+                    let _ = tx.send(ConnectionEvent::Identity(Box::new(our_card)));
+                }
+            }
+        }
+        Ok(Some(NetworkEvent::IntroductionComplete {
+            intro: expected_intro,
+            identity: Box::new(card),
+        }))
+    }
+}
+
+// Run the handshake and then the connection for an incoming connection to `identity`
+fn spawn_incoming_connection(
+    handshakes: &Arc<Mutex<HandshakeLimiter>>,
+    tx: &mpsc::UnboundedSender<EngineEvent>,
+    debug: bool,
+    keepalive: Keepalive,
+    identity: &TorServiceId,
+    stream: OnionServiceStream,
+    socket_addr: TorSocketAddr,
+) {
+    // Turn them away before the key exchange if there are too many handshakes going on
+    let permit = match HandshakeLimiter::start(handshakes) {
+        Ok(permit) => permit,
+        Err(error) => {
+            let _ = tx.send(EngineEvent::Audit(format!(
+                "Rejected incoming connection: {}",
+                error
+            )));
+            return;
+        }
+    };
+    let tx = tx.clone();
+    let id = identity.clone();
+    tokio::spawn(async move {
+        let mut logger = TxLogger::new(&tx, debug);
+        let mut connection = match handle_incoming_connection(
+            &id,
+            stream,
+            socket_addr,
+            tx,
+            keepalive,
+            permit,
+            &mut logger,
+        )
+        .await
+        {
+            Ok(connection) => connection,
+            Err(error) => {
+                logger.log_error(&format!("Error handling incoming connection: {}", error));
+                return;
+            }
+        };
+
+        connection.handle_connection(&mut logger).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::HybridTimestamp;
+    use crate::history::RetentionPolicy;
+    use crate::logger::StandardLogger;
+    use crate::storage::StorageKey;
+    use ed25519_dalek::Verifier;
+    use std::str::FromStr;
+    use tor_client_lib::{
+        control_connection::OnionService as TorClientOnionService, TorEd25519SigningKey,
+    };
+
+    fn test_onion_service(name: &str, seed: u8) -> OnionService {
+        let signing_key = TorEd25519SigningKey::from_bytes([seed; 64]);
+        let id: TorServiceId = signing_key.verifying_key().into();
+        OnionService::new(name, TorClientOnionService::new(id, signing_key, &[]))
+    }
+
+    async fn test_engine(seed: u8) -> Engine {
+        let mut onion_service = test_onion_service("test", seed);
+        let address = OnionAddress::new(onion_service.service_id().clone(), 3000);
+        Engine::new(
+            &mut onion_service,
+            address,
+            SocketAddr::from_str("127.0.0.1:9050").unwrap(),
+            false,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn test_history(dir: &std::path::Path) -> Result<HistoryStore> {
+        let key =
+            StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([7u8; 64]), "history")?;
+        HistoryStore::open(dir, key, RetentionPolicy::default())
+    }
+
+    #[tokio::test]
+    async fn test_conversation_ttl() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut logger = StandardLogger::new(100);
+        let mut engine = test_engine(1).await;
+        engine.use_history(test_history(dir.path())?);
+        let peer = TorServiceId::generate();
+
+        // A timer set in the future is treated as set now
+        let mut ttl = ConversationTtl::new(Some(Duration::from_secs(60)));
+        ttl.set_at += chrono::Duration::days(1);
+        engine
+            .handle_engine_event(EngineEvent::TtlChanged(peer.clone(), ttl), &mut logger)
+            .await?;
+        assert!(engine.ttls[&peer].set_at <= Utc::now());
+
+        // Nothing has expired yet
+        engine.expire_messages(&mut logger);
+        assert!(engine.rx.try_recv().is_err());
+
+        let mut message = ChatMessage::new(&peer, &engine.id(), "Hi".to_string());
+        message.date -= chrono::Duration::minutes(5);
+        engine
+            .handle_engine_event(
+                EngineEvent::Message(peer.clone(), Box::new(message.clone())),
+                &mut logger,
+            )
+            .await?;
+        engine.expire_messages(&mut logger);
+        match engine.rx.try_recv() {
+            Ok(EngineEvent::MessagesExpired {
+                id, message_ids, ..
+            }) => {
+                assert_eq!(peer, id);
+                assert_eq!(vec![message.id], message_ids);
+            }
+            _ => panic!("Expected expired messages"),
+        }
+        assert!(engine.history().unwrap().is_empty(&peer));
+        engine.expire_messages(&mut logger);
+        assert!(engine.rx.try_recv().is_err());
+
+        // The timer is kept across restarts
+        let mut engine = test_engine(1).await;
+        engine.use_history(test_history(dir.path())?);
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            engine.conversation_ttl(&peer)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_timestamps() -> Result<()> {
+        let mut logger = StandardLogger::new(100);
+        let mut engine = test_engine(1).await;
+        let peer = TorServiceId::generate();
+        let before = clock::now();
+
+        // Backdated messages are ordered by when we got them
+        let mut message = ChatMessage::new(&peer, &engine.id(), "Hi".to_string());
+        message.timestamp = HybridTimestamp::default();
+        match engine
+            .handle_engine_event(
+                EngineEvent::Message(peer.clone(), Box::new(message)),
+                &mut logger,
+            )
+            .await?
+        {
+            Some(NetworkEvent::Message(message)) => assert!(message.timestamp > before),
+            _ => panic!("Expected message"),
+        }
+        assert!(engine.rx.try_recv().is_err());
+
+        // Messages from the future tell us the peer's clock is ahead
+        let mut message = ChatMessage::new(&peer, &engine.id(), "Hi".to_string());
+        message.timestamp.wall += 10 * 60 * 1000;
+        engine
+            .handle_engine_event(
+                EngineEvent::Message(peer.clone(), Box::new(message)),
+                &mut logger,
+            )
+            .await?;
+        match engine.rx.try_recv() {
+            Ok(event @ EngineEvent::ClockOffset(..)) => {
+                assert!(matches!(
+                    engine.handle_engine_event(event, &mut logger).await?,
+                    Some(NetworkEvent::ClockSkew { .. })
+                ));
+            }
+            _ => panic!("Expected clock offset"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_keepalive() {
+        let mut engine = test_engine(40).await;
+        assert!(engine
+            .set_keepalive(Keepalive {
+                ping_interval: Duration::ZERO,
+                idle_timeout: Duration::from_secs(10),
+            })
+            .is_err());
+        assert!(engine
+            .set_keepalive(Keepalive {
+                ping_interval: Duration::from_secs(5),
+                idle_timeout: Duration::from_secs(10),
+            })
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_peer() -> Result<()> {
+        let mut logger = StandardLogger::new(100);
+        let mut engine = test_engine(42).await;
+        let peer = TorServiceId::generate();
+        engine.read_only.insert(peer.clone());
+
+        let ttl = ConversationTtl::new(Some(Duration::from_secs(60)));
+        let message = ChatMessage::new(&peer, &engine.id(), "Hi".to_string());
+        for event in [
+            EngineEvent::Message(peer.clone(), Box::new(message)),
+            EngineEvent::TtlChanged(peer.clone(), ttl),
+            EngineEvent::Typing(peer.clone(), true),
+            EngineEvent::Burned(peer.clone()),
+        ] {
+            assert!(engine
+                .handle_engine_event(event, &mut logger)
+                .await?
+                .is_none());
+        }
+        assert!(engine.conversation_ttl(&peer).is_none());
+
+        Ok(())
+    }
+
+    // Give `engine` a pairwise identity for `peer`, recorded in its contacts
+    fn use_pairwise_identity(
+        engine: &mut Engine,
+        dir: &std::path::Path,
+        peer: &TorServiceId,
+    ) -> Result<TorServiceId> {
+        let pairwise = test_onion_service("pairwise", 43);
+        let key =
+            StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([7u8; 64]), "contacts")?;
+        let mut contacts = Contacts::open(&dir.join("contacts"), key)?;
+        contacts.add(Contact::new(peer, "alice", 3000))?;
+        contacts.set_identity(peer, Some("pairwise"))?;
+        engine.use_contacts(contacts);
+        engine.add_identity(&pairwise);
+        Ok(pairwise.service_id().clone())
+    }
+
+    #[tokio::test]
+    async fn test_pairwise_identity() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut logger = StandardLogger::new(100);
+        let mut engine = test_engine(44).await;
+        let peer = TorServiceId::generate();
+        let pairwise = use_pairwise_identity(&mut engine, dir.path(), &peer)?;
+        assert_eq!(pairwise, engine.identity_for(&peer));
+        assert_eq!(engine.id(), engine.identity_for(&TorServiceId::generate()));
+
+        // Handshakes are signed by whichever identity the peer connected to
+        let data = b"handshake".to_vec();
+        for signer in [pairwise.clone(), engine.id()] {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            engine
+                .handle_engine_event(
+                    EngineEvent::SignatureRequest {
+                        tx,
+                        signer: signer.clone(),
+                        data_to_be_signed: data.clone(),
+                    },
+                    &mut logger,
+                )
+                .await?;
+            match rx.try_recv() {
+                Ok(ConnectionEvent::SignatureResponse(signature)) => {
+                    signer.verifying_key()?.verify(&data, &signature)?
+                }
+                _ => panic!("Expected a signature from {}", signer),
+            }
+        }
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        engine
+            .handle_engine_event(
+                EngineEvent::SignatureRequest {
+                    tx,
+                    signer: TorServiceId::generate(),
+                    data_to_be_signed: data,
+                },
+                &mut logger,
+            )
+            .await?;
+        assert!(rx.try_recv().is_err());
+
+        // Messages we send go out from the pairwise identity
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        engine.channels.insert(peer.clone(), tx);
+        engine
+            .send_message(
+                ChatMessage::new(&engine.id(), &peer, "Hi".to_string()),
+                &mut logger,
+            )
+            .await?;
+        match rx.try_recv() {
+            Ok(ConnectionEvent::Message(message)) => assert_eq!(pairwise, message.sender),
+            _ => panic!("Expected the message to be sent"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pairwise_identity_features() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut logger = StandardLogger::new(100);
+        let mut engine = test_engine(45).await;
+        let peer = TorServiceId::generate();
+        use_pairwise_identity(&mut engine, dir.path(), &peer)?;
+
+        assert!(engine
+            .create_group("Friends", std::slice::from_ref(&peer), &mut logger)
+            .is_err());
+        assert!(engine.subscribe(&peer, 0).is_err());
+        assert!(engine
+            .join_room(&peer, &RoomId::generate(), &mut logger)
+            .is_err());
+
+        // Groups they add us to are ignored, but not ones from anyone else
+        let group_key = TorEd25519SigningKey::from_bytes([46u8; 64]);
+        let group = Group::create(&group_key, "Friends", &[engine.id()])?;
+        let changes = GroupMessage::Changes(group.changes().to_vec());
+        engine
+            .handle_engine_event(EngineEvent::Group(peer, changes.clone()), &mut logger)
+            .await?;
+        assert!(engine.group(group.id()).is_none());
+        engine
+            .handle_engine_event(
+                EngineEvent::Group(TorServiceId::generate(), changes),
+                &mut logger,
+            )
+            .await?;
+        assert!(engine.group(group.id()).is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_burner_spent() {
+        let mut engine = test_engine(47).await;
+        assert!(!engine.burner_spent());
+
+        // Still has a session left
+        let mut burner = Burner::new(None, Some(2));
+        burner.sessions = 1;
+        engine.use_burner(burner.clone());
+        assert!(!engine.burner_spent());
+
+        // This is the last session, so the identity goes when we shut down
+        burner.sessions = 2;
+        engine.use_burner(burner);
+        assert!(engine.burner_spent());
+
+        // Expired identities go too, even after the UI's been told
+        engine.use_burner(Burner::new(
+            Some(Utc::now() - chrono::Duration::seconds(1)),
+            None,
+        ));
+        engine.burner_expired = true;
+        assert!(engine.burner_spent());
+    }
+
+    fn intro_connection(peer: &TorServiceId, intro: &TorServiceId) -> Box<ConnectionInfo> {
+        Box::new(ConnectionInfo::new(
+            TorSocketAddr::from_str("127.0.0.1:3000").unwrap(),
+            peer,
+            intro,
+            ConnectionDirection::Incoming,
+            "",
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_hosted_intro() -> Result<()> {
+        let mut logger = StandardLogger::new(100);
+        let mut engine = test_engine(48).await;
+        let intro = test_onion_service("intro", 49);
+        let intro_id = intro.service_id().clone();
+        engine.add_identity(&intro);
+        engine.intros.insert(
+            intro_id.clone(),
+            Intro {
+                used_by: None,
+                accept_task: tokio::spawn(async {}),
+            },
+        );
+
+        // Someone connects, but leaves before sending their card, so the intro can be reused
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let quitter = TorServiceId::generate();
+        engine
+            .handle_engine_event(
+                EngineEvent::NewConnection(intro_connection(&quitter, &intro_id), tx),
+                &mut logger,
+            )
+            .await?;
+        assert_eq!(Some(&quitter), engine.intros[&intro_id].used_by.as_ref());
+        engine
+            .handle_engine_event(
+                EngineEvent::ConnectionClosed(intro_connection(&quitter, &intro_id)),
+                &mut logger,
+            )
+            .await?;
+        assert!(engine.intros[&intro_id].used_by.is_none());
+
+        // The next peer gets our identity, and sends theirs
+        let peer_key = TorEd25519SigningKey::from_bytes([50u8; 64]);
+        let peer: TorServiceId = peer_key.verifying_key().into();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        engine
+            .handle_engine_event(
+                EngineEvent::NewConnection(intro_connection(&peer, &intro_id), tx),
+                &mut logger,
+            )
+            .await?;
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ConnectionEvent::ConnectionAuthorized)
+        ));
+        match rx.try_recv() {
+            Ok(ConnectionEvent::Identity(card)) => {
+                assert_eq!(engine.id(), card.owner);
+                assert_eq!(intro_id, card.intro);
+            }
+            _ => panic!("Expected our identity card"),
+        }
+
+        let card = IdentityCard::new(&peer_key, &OnionAddress::new(peer.clone(), 3000), &intro_id);
+        match engine
+            .handle_engine_event(EngineEvent::Identity(peer, Box::new(card)), &mut logger)
+            .await?
+        {
+            Some(NetworkEvent::IntroductionComplete { intro, .. }) => assert_eq!(intro_id, intro),
+            _ => panic!("Expected the introduction to complete"),
+        }
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ConnectionEvent::CloseConnection)
+        ));
+        assert!(engine.intros.is_empty());
+        assert!(!engine.identities.contains_key(&intro_id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_requested_intro() -> Result<()> {
+        let mut logger = StandardLogger::new(100);
+        let mut engine = test_engine(51).await;
+        let host_key = TorEd25519SigningKey::from_bytes([52u8; 64]);
+        let host: TorServiceId = host_key.verifying_key().into();
+        let intro_id = test_onion_service("intro", 53).service_id().clone();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        engine.channels.insert(intro_id.clone(), tx);
+        engine.intro_requests.insert(intro_id.clone());
+
+        // A card naming some other intro service is turned down
+        let other_card = IdentityCard::new(
+            &host_key,
+            &OnionAddress::new(host.clone(), 3000),
+            &TorServiceId::generate(),
+        );
+        assert!(engine
+            .handle_engine_event(
+                EngineEvent::Identity(intro_id.clone(), Box::new(other_card)),
+                &mut logger
+            )
+            .await?
+            .is_none());
+
+        engine.intro_requests.insert(intro_id.clone());
+        let card = IdentityCard::new(&host_key, &OnionAddress::new(host.clone(), 3000), &intro_id);
+        match engine
+            .handle_engine_event(
+                EngineEvent::Identity(intro_id.clone(), Box::new(card)),
+                &mut logger,
+            )
+            .await?
+        {
+            Some(NetworkEvent::IntroductionComplete { intro, identity }) => {
+                assert_eq!(intro_id, intro);
+                assert_eq!(host, identity.owner);
+            }
+            _ => panic!("Expected the introduction to complete"),
+        }
+        match rx.try_recv() {
+            Ok(ConnectionEvent::Identity(card)) => {
+                assert_eq!(engine.id(), card.owner);
+                assert_eq!(intro_id, card.intro);
+            }
+            _ => panic!("Expected our identity card"),
+        }
+        assert!(engine.intro_requests.is_empty());
+
+        Ok(())
+    }
+
+    // Move `engine`, made with `test_engine(54)`, to a new identity
+    async fn migrate_test_engine(
+        engine: &mut Engine,
+        grace_period: Duration,
+        logger: &mut dyn Logger,
+    ) -> Result<KeyMigration> {
+        let retired = test_onion_service("test-retired", 54);
+        let new_service = test_onion_service("test", 55);
+        let migration = KeyMigration::new(retired.signing_key(), new_service.signing_key());
+        let address = OnionAddress::new(new_service.service_id().clone(), 3000);
+        engine
+            .migrate_identity(
+                &new_service,
+                address,
+                &retired,
+                migration.clone(),
+                grace_period,
+                logger,
+            )
+            .await?;
+        Ok(migration)
+    }
+
+    #[tokio::test]
+    async fn test_migration_redirect() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut logger = StandardLogger::new(100);
+        let mut engine = test_engine(54).await;
+        let old_id = engine.id();
+
+        // Contacts who know us by a pairwise identity aren't told
+        let pairwise_peer = TorServiceId::generate();
+        use_pairwise_identity(&mut engine, dir.path(), &pairwise_peer)?;
+        let (tx, mut pairwise_rx) = mpsc::unbounded_channel();
+        engine.channels.insert(pairwise_peer, tx);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        engine.channels.insert(TorServiceId::generate(), tx);
+
+        let migration =
+            migrate_test_engine(&mut engine, Duration::from_secs(3600), &mut logger).await?;
+        assert_eq!(migration.new_id, engine.id());
+        assert!(
+            matches!(rx.try_recv(), Ok(ConnectionEvent::KeyMigration(sent)) if *sent == migration)
+        );
+        assert!(pairwise_rx.try_recv().is_err());
+
+        // Anyone connecting to the old identity is pointed at the new one, and disconnected
+        let peer = TorServiceId::generate();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        assert!(engine
+            .handle_engine_event(
+                EngineEvent::NewConnection(intro_connection(&peer, &old_id), tx),
+                &mut logger,
+            )
+            .await?
+            .is_none());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ConnectionEvent::ConnectionAuthorized)
+        ));
+        assert!(
+            matches!(rx.try_recv(), Ok(ConnectionEvent::KeyMigration(sent)) if *sent == migration)
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ConnectionEvent::CloseConnection)
+        ));
+        assert!(!engine.channels.contains_key(&peer));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_end_grace_period() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut logger = StandardLogger::new(100);
+        let mut engine = test_engine(54).await;
+        let path = dir.path().join("migration");
+        let old_key = TorEd25519SigningKey::from_bytes([54u8; 64]);
+        let key = StorageKey::for_dir(dir.path(), &old_key, "migration")?;
+        engine.use_migration_state(MigrationState::open(&path, key)?);
+        let migration =
+            migrate_test_engine(&mut engine, Duration::from_secs(3600), &mut logger).await?;
+
+        // The migration survives a restart, which opens it with the new key
+        let new_key = TorEd25519SigningKey::from_bytes([55u8; 64]);
+        let key = StorageKey::for_dir(dir.path(), &new_key, "migration")?;
+        let state = MigrationState::open(&path, key.clone())?;
+        assert_eq!(
+            Some(&migration),
+            state.get().map(|retiring| &retiring.migration)
+        );
+
+        // Not over yet
+        assert!(engine.end_grace_period(&mut logger).await.is_none());
+        assert!(engine.identities.contains_key(&migration.old_id));
+
+        // The retired key is wiped when it's over. Its name is joined onto the data
+        // directory, so an absolute path points it at a temporary one.
+        let retired_dir = tempfile::tempdir()?;
+        std::fs::write(retired_dir.path().join("key"), b"secret")?;
+        let mut retiring = engine.migration.get().unwrap().clone();
+        retiring.until = Utc::now() - chrono::Duration::seconds(1);
+        retiring.name = retired_dir.path().to_string_lossy().to_string();
+        engine.migration.set(retiring)?;
+        assert_eq!(
+            Some(migration.old_id.clone()),
+            engine.end_grace_period(&mut logger).await
+        );
+        assert!(!retired_dir.path().join("key").exists());
+        assert!(!engine.identities.contains_key(&migration.old_id));
+        assert!(engine.migration.get().is_none());
+        let state = MigrationState::open(&path, key)?;
+        assert!(state.get().is_none());
+        assert!(engine.end_grace_period(&mut logger).await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_room_denied() -> Result<()> {
+        let mut logger = StandardLogger::new(100);
+        let mut engine = test_engine(56).await;
+        let host = TorServiceId::generate();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        engine.channels.insert(host.clone(), tx);
+        let room_id = RoomId::generate();
+        engine.join_room(&host, &room_id, &mut logger)?;
+        assert!(matches!(rx.try_recv(), Ok(ConnectionEvent::Room(_))));
+
+        // Once the host turns us away, we're not in the room
+        let update = RoomUpdate::Denied {
+            room_id,
+            reason: "Banned".to_string(),
+        };
+        assert!(engine
+            .handle_engine_event(
+                EngineEvent::Room(host, RoomMessage::Update(update)),
+                &mut logger
+            )
+            .await?
+            .is_some());
+        assert!(engine
+            .send_room_message(&room_id, "Hello", &mut logger)
+            .is_err());
+        assert!(engine.leave_room(&room_id, &mut logger).is_err());
+        assert!(rx.try_recv().is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,296 @@
+use crate::{
+    chat::{Chat, ChatMessage},
+    history::HistoryStore,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::Serialize;
+use std::fmt::Write;
+use tor_client_lib::{TorEd25519SigningKey, TorServiceId};
+
+/// Format of an exported conversation
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExportFormat {
+    /// Machine-readable JSON document
+    Json,
+    /// Human-readable Markdown transcript
+    Markdown,
+    /// One email per message, for use with mail tools
+    Mbox,
+}
+
+/// Optional extras included in an export
+#[derive(Clone, Default)]
+pub struct ExportOptions<'a> {
+    /// Session verification code (see `ConnectionInfo::verification_code`)
+    pub verification_code: Option<String>,
+    /// If set, each message is signed with this key, so the recipient of the export can
+    /// check it hasn't been tampered with using `verify_message_signature`
+    pub signing_key: Option<&'a TorEd25519SigningKey>,
+}
+
+#[derive(Serialize)]
+struct Transcript<'a> {
+    contact: &'a TorServiceId,
+    exported_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exported_by: Option<TorServiceId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verification_code: Option<&'a str>,
+    messages: Vec<TranscriptMessage<'a>>,
+}
+
+#[derive(Serialize)]
+struct TranscriptMessage<'a> {
+    id: String,
+    date: String,
+    sender: &'a TorServiceId,
+    recipient: &'a TorServiceId,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+/// Export the messages currently held in a chat with a single contact
+pub fn export_chat(chat: &Chat, format: ExportFormat, options: &ExportOptions) -> Result<String> {
+    let contact = match chat.service_id() {
+        Some(contact) => contact,
+        None => return Err(anyhow!("Can't export group chat {}", chat.id())),
+    };
+    export_messages(
+        contact,
+        &chat.iter().cloned().collect::<Vec<ChatMessage>>(),
+        format,
+        options,
+    )
+}
+
+/// Export the full stored history of the conversation with `contact`
+pub fn export_history(
+    history: &HistoryStore,
+    contact: &TorServiceId,
+    format: ExportFormat,
+    options: &ExportOptions,
+) -> Result<String> {
+    let messages = history.load_page(contact, 0, history.len(contact))?;
+    export_messages(contact, &messages, format, options)
+}
+
+/// Export a list of messages from the conversation with `contact`
+pub fn export_messages(
+    contact: &TorServiceId,
+    messages: &[ChatMessage],
+    format: ExportFormat,
+    options: &ExportOptions,
+) -> Result<String> {
+    let signatures = messages
+        .iter()
+        .map(|message| {
+            options
+                .signing_key
+                .map(|key| hex::encode(key.sign(&signed_data(message)).to_bytes()))
+        })
+        .collect::<Vec<Option<String>>>();
+
+    match format {
+        ExportFormat::Json => export_json(contact, messages, &signatures, options),
+        ExportFormat::Markdown => Ok(export_markdown(contact, messages, &signatures, options)),
+        ExportFormat::Mbox => Ok(export_mbox(contact, messages, &signatures, options)),
+    }
+}
+
+/// Verify the signature on an exported message, given the ID of whoever exported it
+pub fn verify_message_signature(
+    exported_by: &TorServiceId,
+    message: &ChatMessage,
+    signature: &str,
+) -> Result<()> {
+    let signature_bytes: [u8; 64] = match hex::decode(signature)?.try_into() {
+        Ok(bytes) => bytes,
+        Err(_) => return Err(anyhow!("Bad signature length")),
+    };
+    exported_by.verifying_key()?.verify(
+        &signed_data(message),
+        &Signature::from_bytes(&signature_bytes),
+    )?;
+    Ok(())
+}
+
+// Data covered by the per-message signature
+fn signed_data(message: &ChatMessage) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(message.id.to_string().as_bytes());
+    data.extend_from_slice(&message.date.timestamp().to_be_bytes());
+    data.extend_from_slice(message.sender.as_str().as_bytes());
+    data.extend_from_slice(message.recipient.as_str().as_bytes());
+    data.extend_from_slice(message.message.as_bytes());
+    data
+}
+
+fn exported_by(options: &ExportOptions) -> Option<TorServiceId> {
+    options.signing_key.map(|key| key.verifying_key().into())
+}
+
+fn format_date(date: &DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn export_json(
+    contact: &TorServiceId,
+    messages: &[ChatMessage],
+    signatures: &[Option<String>],
+    options: &ExportOptions,
+) -> Result<String> {
+    let transcript = Transcript {
+        contact,
+        exported_at: format_date(&Utc::now()),
+        exported_by: exported_by(options),
+        verification_code: options.verification_code.as_deref(),
+        messages: messages
+            .iter()
+            .zip(signatures)
+            .map(|(message, signature)| TranscriptMessage {
+                id: message.id.to_string(),
+                date: format_date(&message.date),
+                sender: &message.sender,
+                recipient: &message.recipient,
+                message: &message.message,
+                signature: signature.clone(),
+            })
+            .collect(),
+    };
+    Ok(serde_json::to_string_pretty(&transcript)?)
+}
+
+fn export_markdown(
+    contact: &TorServiceId,
+    messages: &[ChatMessage],
+    signatures: &[Option<String>],
+    options: &ExportOptions,
+) -> String {
+    let mut output = String::new();
+    let _ = writeln!(output, "# Conversation with {}\n", contact);
+    let _ = writeln!(output, "Exported {}", format_date(&Utc::now()));
+    if let Some(exported_by) = exported_by(options) {
+        let _ = writeln!(output, "by {}", exported_by);
+    }
+    output.push('\n');
+    if let Some(verification_code) = &options.verification_code {
+        let _ = writeln!(output, "Verification code: `{}`\n", verification_code);
+    }
+    for (message, signature) in messages.iter().zip(signatures) {
+        let _ = writeln!(
+            output,
+            "**{}** ({})\n",
+            message.sender,
+            format_date(&message.date)
+        );
+        for line in message.message.lines() {
+            let _ = writeln!(output, "> {}", line);
+        }
+        output.push('\n');
+        if let Some(signature) = signature {
+            let _ = writeln!(output, "<sub>Signature: `{}`</sub>\n", signature);
+        }
+    }
+    output
+}
+
+fn export_mbox(
+    contact: &TorServiceId,
+    messages: &[ChatMessage],
+    signatures: &[Option<String>],
+    options: &ExportOptions,
+) -> String {
+    let mut output = String::new();
+    for (message, signature) in messages.iter().zip(signatures) {
+        let _ = writeln!(
+            output,
+            "From {} {}",
+            message.sender.onion_hostname(),
+            message.date.format("%a %b %e %H:%M:%S %Y")
+        );
+        let _ = writeln!(output, "From: {}", message.sender.onion_hostname());
+        let _ = writeln!(output, "To: {}", message.recipient.onion_hostname());
+        let _ = writeln!(output, "Date: {}", message.date.to_rfc2822());
+        let _ = writeln!(output, "Subject: Chat with {}", contact);
+        let _ = writeln!(output, "Message-ID: <{}@voynich>", message.id);
+        if let Some(verification_code) = &options.verification_code {
+            let _ = writeln!(output, "X-Voynich-Verification-Code: {}", verification_code);
+        }
+        if let Some(exported_by) = exported_by(options) {
+            let _ = writeln!(output, "X-Voynich-Exported-By: {}", exported_by);
+        }
+        if let Some(signature) = signature {
+            let _ = writeln!(output, "X-Voynich-Signature: {}", signature);
+        }
+        let _ = writeln!(output, "Content-Type: text/plain; charset=utf-8\n");
+        for line in message.message.lines() {
+            // Escape lines which would otherwise look like the start of a new message
+            if line.trim_start_matches('>').starts_with("From ") {
+                output.push('>');
+            }
+            let _ = writeln!(output, "{}", line);
+        }
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chacha20poly1305::aead::OsRng;
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn test_json_export_signatures() -> Result<()> {
+        let signing_key = TorEd25519SigningKey::from(&SigningKey::generate(&mut OsRng));
+        let me: TorServiceId = signing_key.verifying_key().into();
+        let contact = TorServiceId::generate();
+        let message = ChatMessage::new(&me, &contact, "Hello there".to_string());
+        let options = ExportOptions {
+            verification_code: Some("12345 67890 12345".to_string()),
+            signing_key: Some(&signing_key),
+        };
+
+        let export = export_messages(
+            &contact,
+            std::slice::from_ref(&message),
+            ExportFormat::Json,
+            &options,
+        )?;
+        let value: serde_json::Value = serde_json::from_str(&export)?;
+        assert_eq!("12345 67890 12345", value["verification_code"]);
+        assert_eq!(me.as_str(), value["exported_by"]);
+        let signature = value["messages"][0]["signature"].as_str().unwrap();
+        verify_message_signature(&me, &message, signature)?;
+
+        let mut tampered = message.clone();
+        tampered.message = "Goodbye".to_string();
+        assert!(verify_message_signature(&me, &tampered, signature).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mbox_export() -> Result<()> {
+        let me = TorServiceId::generate();
+        let contact = TorServiceId::generate();
+        let message = ChatMessage::new(&contact, &me, "Hi\nFrom me, with love".to_string());
+
+        let export = export_messages(
+            &contact,
+            &[message],
+            ExportFormat::Mbox,
+            &ExportOptions::default(),
+        )?;
+        assert!(export.starts_with(&format!("From {} ", contact.onion_hostname())));
+        assert!(export.contains("\nHi\n>From me, with love\n"));
+        assert!(!export.contains("X-Voynich-Signature"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{
+    onion_service::OnionService,
+    storage::{read_encrypted, write_encrypted, StorageKey},
+    util::create_onion_service_dir,
+};
+use anyhow::{anyhow, Result};
+use chrono::{serde::ts_seconds, DateTime, SubsecRound, Utc};
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tor_client_lib::{TorEd25519SigningKey, TorServiceId};
+
+/// A post to a feed, signed with the publisher's onion service key
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct FeedPost {
+    /// Position of the post in the feed, starting at 1. Subscribers use this as their cursor.
+    pub sequence: u64,
+    #[serde(with = "ts_seconds")]
+    pub date: DateTime<Utc>,
+    pub publisher: TorServiceId,
+    pub content: String,
+    signature: Vec<u8>,
+}
+
+impl FeedPost {
+    fn new(signing_key: &TorEd25519SigningKey, sequence: u64, content: &str) -> Self {
+        let mut post = Self {
+            sequence,
+            date: Utc::now().round_subsecs(0),
+            publisher: signing_key.verifying_key().into(),
+            content: content.to_string(),
+            signature: Vec::new(),
+        };
+        post.signature = signing_key.sign(&post.signed_data()).to_bytes().to_vec();
+        post
+    }
+
+    /// Check that the post was signed by its publisher
+    pub fn verify(&self) -> Result<()> {
+        let signature_bytes: [u8; 64] = match self.signature.as_slice().try_into() {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(anyhow!("Bad signature length")),
+        };
+        self.publisher.verifying_key()?.verify(
+            &self.signed_data(),
+            &Signature::from_bytes(&signature_bytes),
+        )?;
+        Ok(())
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(self.publisher.as_str().as_bytes());
+        data.extend_from_slice(&self.sequence.to_be_bytes());
+        data.extend_from_slice(&self.date.timestamp().to_be_bytes());
+        data.extend_from_slice(self.content.as_bytes());
+        data
+    }
+}
+
+/// Feed traffic between a subscriber and the publisher
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum FeedMessage {
+    /// Send me every post after `since`, and new posts as they're published
+    Subscribe {
+        since: u64,
+    },
+    Unsubscribe,
+    Posts(Vec<FeedPost>),
+}
+
+/// A feed we publish. Subscribers connect to our onion service and get the posts they've
+/// missed, then new posts as they're published. They can't post anything themselves.
+pub struct Feed {
+    storage: Option<(PathBuf, StorageKey)>,
+    posts: Vec<FeedPost>,
+}
+
+impl Feed {
+    /// A feed which only lasts as long as the process
+    pub fn new() -> Self {
+        Self {
+            storage: None,
+            posts: Vec::new(),
+        }
+    }
+
+    /// Open (or create) a feed stored in `path`, encrypted with `key`
+    pub fn open(path: &Path, key: StorageKey) -> Result<Self> {
+        let posts = read_encrypted::<Vec<FeedPost>>(path, &key)?.unwrap_or_default();
+        Ok(Self {
+            storage: Some((path.to_path_buf(), key)),
+            posts,
+        })
+    }
+
+    /// Open the feed stored under the data directory for this onion service
+    pub fn for_onion_service(onion_service: &OnionService) -> Result<Self> {
+        let dir = create_onion_service_dir(onion_service.name())?;
+        Self::open(
+            &Path::new(&dir).join("feed"),
+            StorageKey::for_dir(Path::new(&dir), onion_service.signing_key(), "feed")?,
+        )
+    }
+
+    /// Sign and add a new post
+    pub fn publish(
+        &mut self,
+        signing_key: &TorEd25519SigningKey,
+        content: &str,
+    ) -> Result<FeedPost> {
+        let post = FeedPost::new(signing_key, self.posts.len() as u64 + 1, content);
+        self.posts.push(post.clone());
+        if let Some((path, key)) = &self.storage {
+            write_encrypted(path, key, &self.posts)?;
+        }
+        Ok(post)
+    }
+
+    /// Posts after the cursor `since`, oldest first
+    pub fn since(&self, since: u64) -> &[FeedPost] {
+        let start = (since as usize).min(self.posts.len());
+        &self.posts[start..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.posts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.posts.is_empty()
+    }
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_publish() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let signing_key = TorEd25519SigningKey::from_bytes([3u8; 64]);
+        let storage_key = StorageKey::from_signing_key(&signing_key, "feed")?;
+        let path = dir.path().join("feed");
+
+        let mut feed = Feed::open(&path, storage_key.clone())?;
+        feed.publish(&signing_key, "First")?;
+        feed.publish(&signing_key, "Second")?;
+        let third = feed.publish(&signing_key, "Third")?;
+        assert_eq!(3, third.sequence);
+        third.verify()?;
+
+        let feed = Feed::open(&path, storage_key)?;
+        assert_eq!(
+            vec!["Second", "Third"],
+            feed.since(1)
+                .iter()
+                .map(|post| post.content.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(feed.since(10).is_empty());
+
+        let mut tampered = third.clone();
+        tampered.content = "Fourth".to_string();
+        assert!(tampered.verify().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,682 @@
+use crate::{
+    chat::ChatMessage,
+    clock::{self, HybridTimestamp},
+    crypto::{Cryptor, NONCE_SIZE},
+    logger::Logger,
+    onion_service::OnionService,
+    storage::{read_encrypted, write_encrypted, StorageKey},
+    util::create_onion_service_dir,
+};
+use anyhow::{anyhow, Result};
+use chacha20poly1305::Key as SymmetricKey;
+use ed25519_dalek::{pkcs8::spki::der::zeroize::Zeroize, Signature, Signer, Verifier};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tor_client_lib::{TorEd25519SigningKey, TorServiceId};
+
+/// Unique identifier for a group chat
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub struct GroupId([u8; 16]);
+
+impl GroupId {
+    pub fn generate() -> Self {
+        Self(rand::thread_rng().gen())
+    }
+}
+
+impl fmt::Display for GroupId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum MembershipAction {
+    /// Start a new group, with the author as its only member
+    Create { name: String },
+    /// Add a member. Any member can add someone.
+    Add(TorServiceId),
+    /// Remove a member. Only the group's creator can remove other members, but anyone can
+    /// remove themselves.
+    Remove(TorServiceId),
+}
+
+/// A change to a group's membership, signed with the onion service key of its author
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct MembershipChange {
+    pub group_id: GroupId,
+    pub action: MembershipAction,
+    pub author: TorServiceId,
+    pub timestamp: HybridTimestamp,
+    signature: Vec<u8>,
+}
+
+impl MembershipChange {
+    pub fn new(
+        signing_key: &TorEd25519SigningKey,
+        group_id: &GroupId,
+        action: MembershipAction,
+    ) -> Self {
+        let mut change = Self {
+            group_id: *group_id,
+            action,
+            author: signing_key.verifying_key().into(),
+            timestamp: clock::now(),
+            signature: Vec::new(),
+        };
+        change.signature = signing_key.sign(&change.signed_data()).to_bytes().to_vec();
+        change
+    }
+
+    /// Check that the change was signed by its author
+    pub fn verify(&self) -> Result<()> {
+        let signature_bytes: [u8; 64] = match self.signature.as_slice().try_into() {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(anyhow!("Bad signature length")),
+        };
+        self.author.verifying_key()?.verify(
+            &self.signed_data(),
+            &Signature::from_bytes(&signature_bytes),
+        )?;
+        Ok(())
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.group_id.0);
+        match &self.action {
+            MembershipAction::Create { name } => {
+                data.push(0);
+                data.extend_from_slice(name.as_bytes());
+            }
+            MembershipAction::Add(member) => {
+                data.push(1);
+                data.extend_from_slice(member.as_str().as_bytes());
+            }
+            MembershipAction::Remove(member) => {
+                data.push(2);
+                data.extend_from_slice(member.as_str().as_bytes());
+            }
+        }
+        data.extend_from_slice(self.author.as_str().as_bytes());
+        data.extend_from_slice(&self.timestamp.wall.to_be_bytes());
+        data.extend_from_slice(&self.timestamp.logical.to_be_bytes());
+        data
+    }
+}
+
+// Membership changes are ordered by timestamp, then author, which every member agrees on
+fn change_order(change: &MembershipChange) -> (&HybridTimestamp, &TorServiceId) {
+    (&change.timestamp, &change.author)
+}
+
+/// Symmetric key a member uses to encrypt the messages they send to a group. Each member
+/// has their own, and hands it out to the other members over their pairwise connections.
+/// The generation is bumped every time the key is replaced.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SenderKey {
+    generation: u32,
+    key: [u8; 32],
+}
+
+impl SenderKey {
+    fn generate(generation: u32) -> Self {
+        Self {
+            generation,
+            key: rand::thread_rng().gen(),
+        }
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    fn cryptor(&self) -> Cryptor {
+        let key: SymmetricKey = self.key.into();
+        Cryptor::new(&key)
+    }
+}
+
+impl fmt::Debug for SenderKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SenderKey")
+            .field("generation", &self.generation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for SenderKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// A group message, encrypted with the sender's key
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GroupEnvelope {
+    pub group_id: GroupId,
+    pub sender: TorServiceId,
+    pub generation: u32,
+    ciphertext: Vec<u8>,
+}
+
+/// Group traffic exchanged between two members over their pairwise connection
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum GroupMessage {
+    /// Membership changes for a group, sent in full when someone joins or reconnects
+    Changes(Vec<MembershipChange>),
+    /// The sender's current key for a group
+    SenderKey(GroupId, SenderKey),
+    Message(Box<GroupEnvelope>),
+}
+
+/// A group chat. There's no server - each member keeps their own copy of the group, built
+/// from the signed membership changes, and messages are sent to each member directly.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Group {
+    id: GroupId,
+    name: String,
+    creator: TorServiceId,
+    members: BTreeSet<TorServiceId>,
+    changes: Vec<MembershipChange>,
+    sender_key: SenderKey,
+    member_keys: HashMap<TorServiceId, SenderKey>,
+}
+
+impl Group {
+    /// Create a new group of us and `members`. The other members build their copy of the
+    /// group from `changes()`.
+    pub fn create(
+        signing_key: &TorEd25519SigningKey,
+        name: &str,
+        members: &[TorServiceId],
+    ) -> Result<Self> {
+        let id = GroupId::generate();
+        let mut group = Self::from_create(&MembershipChange::new(
+            signing_key,
+            &id,
+            MembershipAction::Create {
+                name: name.to_string(),
+            },
+        ))?;
+        for member in members {
+            group.apply_change(&MembershipChange::new(
+                signing_key,
+                &id,
+                MembershipAction::Add(member.clone()),
+            ))?;
+        }
+        Ok(group)
+    }
+
+    /// Build a group from its membership changes, the first of which must create the group.
+    /// Invalid changes after that are logged and skipped.
+    pub fn from_changes(changes: &[MembershipChange], logger: &mut dyn Logger) -> Result<Self> {
+        let create = match changes.first() {
+            Some(create) => create,
+            None => return Err(anyhow!("No membership changes for group")),
+        };
+        let mut group = Self::from_create(create)?;
+        group.apply(&changes[1..], logger);
+
+        Ok(group)
+    }
+
+    fn from_create(create: &MembershipChange) -> Result<Self> {
+        let name = match &create.action {
+            MembershipAction::Create { name } => name.clone(),
+            _ => return Err(anyhow!("Group {} has no create event", create.group_id)),
+        };
+        create.verify()?;
+
+        Ok(Self {
+            id: create.group_id,
+            name,
+            creator: create.author.clone(),
+            members: BTreeSet::from([create.author.clone()]),
+            changes: vec![create.clone()],
+            sender_key: SenderKey::generate(0),
+            member_keys: HashMap::new(),
+        })
+    }
+
+    pub fn id(&self) -> &GroupId {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn creator(&self) -> &TorServiceId {
+        &self.creator
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &TorServiceId> {
+        self.members.iter()
+    }
+
+    pub fn is_member(&self, id: &TorServiceId) -> bool {
+        self.members.contains(id)
+    }
+
+    /// All the membership changes applied to the group, starting with the one which created
+    /// it, then in timestamp order. A new member needs all of these to rebuild the group.
+    pub fn changes(&self) -> &[MembershipChange] {
+        &self.changes
+    }
+
+    /// Apply membership changes, ignoring ones we've already seen. Invalid changes are
+    /// logged and skipped, so one bad change doesn't stop the rest being applied. Returns the
+    /// changes which were new.
+    pub fn apply(
+        &mut self,
+        changes: &[MembershipChange],
+        logger: &mut dyn Logger,
+    ) -> Vec<MembershipChange> {
+        let mut applied = Vec::new();
+        for change in changes {
+            match self.apply_change(change) {
+                Ok(true) => applied.push(change.clone()),
+                Ok(false) => {}
+                Err(error) => logger.log_warning(&format!(
+                    "Skipping membership change for group {}: {}",
+                    self.id, error
+                )),
+            }
+        }
+        applied
+    }
+
+    /// Apply a single membership change. Returns whether it was new.
+    ///
+    /// Changes are kept in timestamp order, and the membership is worked out by replaying
+    /// them, so every member ends up with the same membership whatever order the changes
+    /// reach them in. A change has to be allowed at the point it falls in that order. A
+    /// change which falls after one that disallows it, such as an add by a member who's been
+    /// removed, is kept but has no effect.
+    pub fn apply_change(&mut self, change: &MembershipChange) -> Result<bool> {
+        if self.changes.contains(change) {
+            return Ok(false);
+        }
+        if change.group_id != self.id {
+            return Err(anyhow!(
+                "Membership change for group {} applied to group {}",
+                change.group_id,
+                self.id
+            ));
+        }
+        change.verify()?;
+        if let MembershipAction::Create { .. } = change.action {
+            return Err(anyhow!("Group {} already exists", self.id));
+        }
+        // The create always comes first
+        let position = 1 + self.changes[1..]
+            .partition_point(|applied| change_order(applied) < change_order(change));
+        let members = self.replay(&self.changes[1..position]);
+        self.check_allowed(&members, change)?;
+        self.changes.insert(position, change.clone());
+        self.members = self.replay(&self.changes[1..]);
+        let members = &self.members;
+        self.member_keys
+            .retain(|member, _| members.contains(member));
+        Ok(true)
+    }
+
+    // The membership after the create and `changes`. Changes which weren't allowed at their
+    // point in the order are skipped.
+    fn replay(&self, changes: &[MembershipChange]) -> BTreeSet<TorServiceId> {
+        let mut members = BTreeSet::from([self.creator.clone()]);
+        for change in changes {
+            if self.check_allowed(&members, change).is_err() {
+                continue;
+            }
+            match &change.action {
+                MembershipAction::Create { .. } => {}
+                MembershipAction::Add(member) => {
+                    members.insert(member.clone());
+                }
+                MembershipAction::Remove(member) => {
+                    members.remove(member);
+                }
+            }
+        }
+        members
+    }
+
+    fn check_allowed(
+        &self,
+        members: &BTreeSet<TorServiceId>,
+        change: &MembershipChange,
+    ) -> Result<()> {
+        if !members.contains(&change.author) {
+            return Err(anyhow!(
+                "{} isn't a member of group {}",
+                change.author,
+                self.id
+            ));
+        }
+        if let MembershipAction::Remove(member) = &change.action {
+            if change.author != self.creator && change.author != *member {
+                return Err(anyhow!(
+                    "{} isn't allowed to remove {} from group {}",
+                    change.author,
+                    member,
+                    self.id
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Our current sender key
+    pub fn sender_key(&self) -> &SenderKey {
+        &self.sender_key
+    }
+
+    /// Replace our sender key, so that removed members can't read anything we send from now on
+    pub fn rotate_sender_key(&mut self) -> &SenderKey {
+        self.sender_key = SenderKey::generate(self.sender_key.generation + 1);
+        &self.sender_key
+    }
+
+    /// Store a sender key received from another member. Keys older than the one we have
+    /// are ignored.
+    pub fn set_member_key(&mut self, member: &TorServiceId, key: SenderKey) -> Result<()> {
+        if !self.members.contains(member) {
+            return Err(anyhow!("{} isn't a member of group {}", member, self.id));
+        }
+        if self
+            .member_keys
+            .get(member)
+            .is_none_or(|current| current.generation < key.generation)
+        {
+            self.member_keys.insert(member.clone(), key);
+        }
+        Ok(())
+    }
+
+    /// Encrypt a message we're sending to the group
+    pub fn encrypt(&self, message: &ChatMessage) -> Result<GroupEnvelope> {
+        let mut plaintext = serde_cbor::to_vec(&(&self.id, message))?;
+        let ciphertext = self.sender_key.cryptor().encrypt(&plaintext);
+        plaintext.zeroize();
+
+        Ok(GroupEnvelope {
+            group_id: self.id,
+            sender: message.sender.clone(),
+            generation: self.sender_key.generation,
+            ciphertext: ciphertext?,
+        })
+    }
+
+    /// Decrypt a message sent to the group by another member
+    pub fn decrypt(&self, envelope: &GroupEnvelope) -> Result<ChatMessage> {
+        let key = match self.member_keys.get(&envelope.sender) {
+            Some(key) if key.generation == envelope.generation => key,
+            _ => {
+                return Err(anyhow!(
+                    "No key for {} in group {} (generation {})",
+                    envelope.sender,
+                    self.id,
+                    envelope.generation
+                ))
+            }
+        };
+        if envelope.ciphertext.len() < NONCE_SIZE {
+            return Err(anyhow!("Group message is truncated"));
+        }
+        let mut plaintext = key.cryptor().decrypt(&envelope.ciphertext)?;
+        let decoded = serde_cbor::from_slice::<(GroupId, ChatMessage)>(&plaintext);
+        plaintext.zeroize();
+        let (group_id, message) = decoded?;
+        if group_id != self.id || message.sender != envelope.sender {
+            return Err(anyhow!("Group message doesn't match its envelope"));
+        }
+
+        Ok(message)
+    }
+}
+
+/// The groups we're in, optionally stored encrypted on disk
+pub struct Groups {
+    storage: Option<(PathBuf, StorageKey)>,
+    groups: HashMap<GroupId, Group>,
+}
+
+impl Groups {
+    /// Groups which only last as long as the process
+    pub fn new() -> Self {
+        Self {
+            storage: None,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Open (or create) the groups stored in `path`, encrypted with `key`
+    pub fn open(path: &Path, key: StorageKey) -> Result<Self> {
+        let groups = read_encrypted::<Vec<Group>>(path, &key)?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|group| (group.id, group))
+            .collect();
+        Ok(Self {
+            storage: Some((path.to_path_buf(), key)),
+            groups,
+        })
+    }
+
+    /// Open the groups stored under the data directory for this onion service
+    pub fn for_onion_service(onion_service: &OnionService) -> Result<Self> {
+        let dir = create_onion_service_dir(onion_service.name())?;
+        Self::open(
+            &Path::new(&dir).join("groups"),
+            StorageKey::for_dir(Path::new(&dir), onion_service.signing_key(), "groups")?,
+        )
+    }
+
+    pub fn get(&self, group_id: &GroupId) -> Option<&Group> {
+        self.groups.get(group_id)
+    }
+
+    /// Changes made through this aren't stored until `save` is called
+    pub fn get_mut(&mut self, group_id: &GroupId) -> Option<&mut Group> {
+        self.groups.get_mut(group_id)
+    }
+
+    pub fn contains(&self, group_id: &GroupId) -> bool {
+        self.groups.contains_key(group_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Group> {
+        self.groups.values()
+    }
+
+    pub fn insert(&mut self, group: Group) -> Result<()> {
+        self.groups.insert(group.id, group);
+        self.save()
+    }
+
+    pub fn remove(&mut self, group_id: &GroupId) -> Result<()> {
+        self.groups.remove(group_id);
+        self.save()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        match &self.storage {
+            Some((path, key)) => {
+                write_encrypted(path, key, &self.groups.values().collect::<Vec<&Group>>())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for Groups {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::StandardLogger;
+    use anyhow::Result;
+    use chacha20poly1305::aead::OsRng;
+    use ed25519_dalek::SigningKey;
+
+    fn new_identity() -> (TorEd25519SigningKey, TorServiceId) {
+        let signing_key = TorEd25519SigningKey::from(&SigningKey::generate(&mut OsRng));
+        let id = signing_key.verifying_key().into();
+        (signing_key, id)
+    }
+
+    #[test]
+    fn test_membership() -> Result<()> {
+        let (alice_key, alice) = new_identity();
+        let (bob_key, bob) = new_identity();
+        let (carol_key, carol) = new_identity();
+        let mut alice_group = Group::create(&alice_key, "Friends", std::slice::from_ref(&bob))?;
+        let mut logger = StandardLogger::new(100);
+
+        // Bob rebuilds the group from Alice's changes, and adds Carol
+        let mut bob_group = Group::from_changes(alice_group.changes(), &mut logger)?;
+        assert!(bob_group.is_member(&alice) && bob_group.is_member(&bob));
+        let add_carol = MembershipChange::new(
+            &bob_key,
+            alice_group.id(),
+            MembershipAction::Add(carol.clone()),
+        );
+        assert!(bob_group.apply_change(&add_carol)?);
+        assert_eq!(1, alice_group.apply(bob_group.changes(), &mut logger).len());
+        assert!(alice_group.is_member(&carol));
+
+        // Only the creator can remove someone else
+        let remove_bob = MembershipChange::new(
+            &carol_key,
+            alice_group.id(),
+            MembershipAction::Remove(bob.clone()),
+        );
+        assert!(alice_group.apply_change(&remove_bob).is_err());
+        assert!(alice_group.is_member(&bob));
+
+        // Tampered changes are rejected
+        let mut forged = MembershipChange::new(
+            &bob_key,
+            alice_group.id(),
+            MembershipAction::Remove(bob.clone()),
+        );
+        forged.action = MembershipAction::Remove(alice.clone());
+        assert!(alice_group.apply_change(&forged).is_err());
+
+        // Invalid changes in a batch are skipped, and the rest still applied
+        let (dave_key, dave) = new_identity();
+        let add_dave = MembershipChange::new(
+            &dave_key,
+            alice_group.id(),
+            MembershipAction::Add(dave.clone()),
+        );
+        let bob_leaves = MembershipChange::new(
+            &bob_key,
+            alice_group.id(),
+            MembershipAction::Remove(bob.clone()),
+        );
+        let applied = alice_group.apply(&[forged, add_dave, bob_leaves.clone()], &mut logger);
+        assert_eq!(vec![bob_leaves], applied);
+        assert!(!alice_group.is_member(&bob) && !alice_group.is_member(&dave));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sender_keys() -> Result<()> {
+        let (alice_key, alice) = new_identity();
+        let (_, bob) = new_identity();
+        let mut alice_group = Group::create(&alice_key, "Friends", std::slice::from_ref(&bob))?;
+        let mut bob_group =
+            Group::from_changes(alice_group.changes(), &mut StandardLogger::new(100))?;
+
+        let message = ChatMessage::new(&alice, &alice, "Hi all".to_string());
+        let envelope = alice_group.encrypt(&message)?;
+        assert!(bob_group.decrypt(&envelope).is_err());
+
+        bob_group.set_member_key(&alice, alice_group.sender_key().clone())?;
+        assert_eq!(message, bob_group.decrypt(&envelope)?);
+
+        // After rotating, the old key no longer works
+        alice_group.rotate_sender_key();
+        let envelope = alice_group.encrypt(&message)?;
+        assert!(bob_group.decrypt(&envelope).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_groups_storage() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("groups");
+        let key =
+            || StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([7u8; 64]), "groups");
+        let (alice_key, _) = new_identity();
+        let (_, bob) = new_identity();
+
+        let mut groups = Groups::open(&path, key()?)?;
+        let mut group = Group::create(&alice_key, "Friends", std::slice::from_ref(&bob))?;
+        let group_id = *group.id();
+        group.rotate_sender_key();
+        groups.insert(group)?;
+
+        let mut groups = Groups::open(&path, key()?)?;
+        let group = groups.get(&group_id).unwrap();
+        assert_eq!("Friends", group.name());
+        assert!(group.is_member(&bob));
+        assert_eq!(1, group.sender_key().generation());
+
+        groups.remove(&group_id)?;
+        assert!(!Groups::open(&path, key()?)?.contains(&group_id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_changes() -> Result<()> {
+        let (alice_key, _) = new_identity();
+        let (bob_key, bob) = new_identity();
+        let (_, dave) = new_identity();
+        let mut logger = StandardLogger::new(100);
+        let group = Group::create(&alice_key, "Friends", std::slice::from_ref(&bob))?;
+
+        // Alice removes Bob while Bob adds Dave. Whichever was made first, everyone ends up
+        // with the same members, whatever order the changes reach them in.
+        let add_dave =
+            || MembershipChange::new(&bob_key, group.id(), MembershipAction::Add(dave.clone()));
+        let remove_bob = || {
+            MembershipChange::new(
+                &alice_key,
+                group.id(),
+                MembershipAction::Remove(bob.clone()),
+            )
+        };
+        let add_first = add_dave();
+        let remove_after = remove_bob();
+        let remove_first = remove_bob();
+        let add_after = add_dave();
+        for (first, second) in [(add_first, remove_after), (remove_first, add_after)] {
+            let mut in_order = Group::from_changes(group.changes(), &mut logger)?;
+            in_order.apply(&[first.clone(), second.clone()], &mut logger);
+            let mut reversed = Group::from_changes(group.changes(), &mut logger)?;
+            reversed.apply(&[second, first], &mut logger);
+            assert!(!in_order.is_member(&bob));
+            assert_eq!(
+                in_order.members().collect::<Vec<_>>(),
+                reversed.members().collect::<Vec<_>>()
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,641 @@
+use crate::{
+    chat::{ChatMessage, ConversationTtl, MessageId},
+    onion_service::OnionService,
+    search::{tokenize, SearchIndex, SearchQuery, SearchResult},
+    storage::{
+        create_storage_dir, read_encrypted, read_or_create_salt, secure_delete, write_encrypted,
+        StorageKey,
+    },
+    util::{create_onion_service_dir, parse_service_id},
+};
+use anyhow::Result;
+use chrono::{serde::ts_seconds, DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_dir, remove_dir};
+use std::path::{Path, PathBuf};
+use tor_client_lib::TorServiceId;
+
+/// Number of messages stored in each history segment file
+const SEGMENT_SIZE: usize = 100;
+
+/// Name of the per-conversation index file
+const INDEX_FILE: &str = "index";
+
+/// Name of the file holding the disappearing-message timers for all conversations
+const TTL_FILE: &str = "ttls";
+
+/// Extension of the search index file kept for each segment
+const SEARCH_INDEX_EXTENSION: &str = "search";
+
+/// How long stored messages are kept. The default keeps everything.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RetentionPolicy {
+    /// Maximum number of messages kept per conversation
+    pub max_messages: Option<usize>,
+    /// Maximum age of kept messages
+    pub max_age: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct SegmentInfo {
+    number: u64,
+    len: usize,
+    /// Latest message date in the segment. Dates come from the sender, so the messages
+    /// aren't in date order.
+    #[serde(with = "ts_seconds")]
+    newest: DateTime<Utc>,
+    /// Earliest message date in the segment, or earlier. Indexes written before this was
+    /// kept get the epoch, so their segments are always checked.
+    #[serde(default, with = "ts_seconds")]
+    oldest: DateTime<Utc>,
+    /// Stored in a file of its own, next to the segment
+    #[serde(skip)]
+    search_index: SearchIndex,
+}
+
+/// Encrypted, on-disk chat history, stored per conversation as a series of segment files
+pub struct HistoryStore {
+    dir: PathBuf,
+    key: StorageKey,
+    retention: RetentionPolicy,
+    indexes: HashMap<TorServiceId, Vec<SegmentInfo>>,
+    ttls: HashMap<TorServiceId, ConversationTtl>,
+}
+
+impl HistoryStore {
+    /// Open (or create) a history store in `dir`, encrypted with `key`
+    pub fn open(dir: &Path, key: StorageKey, retention: RetentionPolicy) -> Result<Self> {
+        create_storage_dir(dir)?;
+        let mut indexes = HashMap::new();
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let id = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => match parse_service_id(name) {
+                    Some(id) => id,
+                    None => continue,
+                },
+                None => continue,
+            };
+            if let Some(index) = read_encrypted::<Vec<SegmentInfo>>(&path.join(INDEX_FILE), &key)? {
+                indexes.insert(id, index);
+            }
+        }
+
+        let ttls = read_encrypted(&dir.join(TTL_FILE), &key)?.unwrap_or_default();
+
+        let mut store = Self {
+            dir: dir.to_path_buf(),
+            key,
+            retention,
+            indexes,
+            ttls,
+        };
+        store.load_search_indexes()?;
+        store.apply_retention()?;
+
+        Ok(store)
+    }
+
+    /// Open the history stored under the data directory for this onion service. If no
+    /// passphrase is given, the encryption key is derived from the onion service key.
+    pub fn for_onion_service(
+        onion_service: &OnionService,
+        passphrase: Option<&str>,
+        retention: RetentionPolicy,
+    ) -> Result<Self> {
+        let service_dir = create_onion_service_dir(onion_service.name())?;
+        let dir = Path::new(&service_dir).join("history");
+        create_storage_dir(&dir)?;
+        let key = match passphrase {
+            Some(passphrase) => {
+                StorageKey::from_passphrase(passphrase, &read_or_create_salt(&dir)?)?
+            }
+            None => StorageKey::for_dir(
+                Path::new(&service_dir),
+                onion_service.signing_key(),
+                "history",
+            )?,
+        };
+        Self::open(&dir, key, retention)
+    }
+
+    pub fn retention(&self) -> &RetentionPolicy {
+        &self.retention
+    }
+
+    pub fn set_retention(&mut self, retention: RetentionPolicy) -> Result<()> {
+        self.retention = retention;
+        self.apply_retention()
+    }
+
+    /// Disappearing-message timers, kept here so messages keep expiring across restarts
+    pub fn conversation_ttls(&self) -> &HashMap<TorServiceId, ConversationTtl> {
+        &self.ttls
+    }
+
+    pub fn set_conversation_ttl(&mut self, id: &TorServiceId, ttl: &ConversationTtl) -> Result<()> {
+        self.ttls.insert(id.clone(), ttl.clone());
+        write_encrypted(&self.dir.join(TTL_FILE), &self.key, &self.ttls)
+    }
+
+    pub fn remove_conversation_ttl(&mut self, id: &TorServiceId) -> Result<()> {
+        if self.ttls.remove(id).is_some() {
+            write_encrypted(&self.dir.join(TTL_FILE), &self.key, &self.ttls)?;
+        }
+        Ok(())
+    }
+
+    /// Conversations with stored history
+    pub fn conversations(&self) -> impl Iterator<Item = &TorServiceId> {
+        self.indexes.keys()
+    }
+
+    /// Number of stored messages in the conversation with `id`
+    pub fn len(&self, id: &TorServiceId) -> usize {
+        match self.indexes.get(id) {
+            Some(index) => index.iter().map(|info| info.len).sum(),
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self, id: &TorServiceId) -> bool {
+        self.len(id) == 0
+    }
+
+    /// Append a message to the conversation with `id`
+    pub fn append(&mut self, id: &TorServiceId, message: &ChatMessage) -> Result<()> {
+        create_storage_dir(&self.conversation_dir(id))?;
+        let mut index = self.indexes.remove(id).unwrap_or_default();
+        let mut segment = match index.last() {
+            Some(info) if info.len < SEGMENT_SIZE => self.read_segment(id, info.number)?,
+            last => {
+                let number = last.map(|info| info.number + 1).unwrap_or(0);
+                index.push(SegmentInfo {
+                    number,
+                    len: 0,
+                    newest: message.date,
+                    oldest: message.date,
+                    search_index: SearchIndex::default(),
+                });
+                Vec::new()
+            }
+        };
+        segment.push(message.clone());
+
+        let info = index.last_mut().unwrap();
+        info.len = segment.len();
+        info.newest = info.newest.max(message.date);
+        info.oldest = info.oldest.min(message.date);
+        info.search_index.add(message);
+        self.write_segment(id, info, &segment)?;
+
+        self.update_index(id, index)
+    }
+
+    /// Load up to `count` messages from the conversation with `id`, skipping the `skip` most
+    /// recent ones. Messages are returned oldest first.
+    pub fn load_page(
+        &self,
+        id: &TorServiceId,
+        skip: usize,
+        count: usize,
+    ) -> Result<Vec<ChatMessage>> {
+        let index = match self.indexes.get(id) {
+            Some(index) => index,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut page = Vec::new();
+        let mut to_skip = skip;
+        for info in index.iter().rev() {
+            if page.len() >= count {
+                break;
+            }
+            if to_skip >= info.len {
+                to_skip -= info.len;
+                continue;
+            }
+            let segment = self.read_segment(id, info.number)?;
+            page.extend(
+                segment
+                    .into_iter()
+                    .rev()
+                    .skip(to_skip)
+                    .take(count - page.len()),
+            );
+            to_skip = 0;
+        }
+        page.reverse();
+
+        Ok(page)
+    }
+
+    /// Search stored messages. Results are returned newest first.
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<SearchResult>> {
+        let terms = match &query.text {
+            Some(text) => tokenize(text),
+            None => Vec::new(),
+        };
+
+        let mut results = Vec::new();
+        for (id, index) in self.indexes.iter() {
+            if query.contact.as_ref().is_some_and(|contact| contact != id) {
+                continue;
+            }
+
+            for info in index.iter() {
+                if query.from.is_some_and(|from| info.newest < from) {
+                    continue;
+                }
+                // Use the search index to skip segments without any matches
+                let wanted = if terms.is_empty() {
+                    None
+                } else {
+                    let candidates = info.search_index.candidates(&terms);
+                    if candidates.is_empty() {
+                        continue;
+                    }
+                    Some(candidates)
+                };
+                for message in self.read_segment(id, info.number)? {
+                    if wanted
+                        .as_ref()
+                        .is_some_and(|ids| !ids.contains(&message.id))
+                    {
+                        continue;
+                    }
+                    if query.matches_date(&message.date) {
+                        results.push(SearchResult::new(id, &message, &terms));
+                    }
+                }
+            }
+        }
+
+        results.sort_by_key(|result| Reverse(result.date));
+        if let Some(limit) = query.limit {
+            results.truncate(limit);
+        }
+
+        Ok(results)
+    }
+
+    /// Securely delete all stored history for the conversation with `id`
+    pub fn purge(&mut self, id: &TorServiceId) -> Result<()> {
+        if let Some(index) = self.indexes.remove(id) {
+            for info in index {
+                self.delete_segment(id, info.number)?;
+            }
+        }
+        let conversation_dir = self.conversation_dir(id);
+        secure_delete(&conversation_dir.join(INDEX_FILE))?;
+        if conversation_dir.exists() {
+            remove_dir(&conversation_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Securely delete all messages in the conversation with `id` sent before `cutoff`,
+    /// returning their IDs
+    pub fn expire(
+        &mut self,
+        id: &TorServiceId,
+        cutoff: DateTime<Utc>,
+    ) -> Result<HashSet<MessageId>> {
+        let mut index = match self.indexes.remove(id) {
+            Some(index) => index,
+            None => return Ok(HashSet::new()),
+        };
+        let removed = self.remove_before(id, &mut index, cutoff)?;
+        self.update_index(id, index)?;
+
+        Ok(removed)
+    }
+
+    /// Drop any messages which fall outside the retention policy
+    pub fn apply_retention(&mut self) -> Result<()> {
+        let ids = self.indexes.keys().cloned().collect::<Vec<TorServiceId>>();
+        for id in ids {
+            let index = self.indexes.remove(&id).unwrap();
+            self.update_index(&id, index)?;
+        }
+
+        Ok(())
+    }
+
+    // Read each segment's search index, rebuilding any which are missing
+    fn load_search_indexes(&mut self) -> Result<()> {
+        let mut indexes = std::mem::take(&mut self.indexes);
+        for (id, index) in indexes.iter_mut() {
+            for info in index.iter_mut() {
+                info.search_index =
+                    match read_encrypted(&self.search_index_path(id, info.number), &self.key)? {
+                        Some(search_index) => search_index,
+                        None => {
+                            let mut search_index = SearchIndex::default();
+                            for message in self.read_segment(id, info.number)? {
+                                search_index.add(&message);
+                            }
+                            search_index
+                        }
+                    };
+            }
+        }
+        self.indexes = indexes;
+        Ok(())
+    }
+
+    fn retain(&self, id: &TorServiceId, mut index: Vec<SegmentInfo>) -> Result<Vec<SegmentInfo>> {
+        if let Some(max_messages) = self.retention.max_messages {
+            let mut excess = index
+                .iter()
+                .map(|info| info.len)
+                .sum::<usize>()
+                .saturating_sub(max_messages);
+            while excess > 0 {
+                let first = &mut index[0];
+                if first.len <= excess {
+                    excess -= first.len;
+                    self.delete_segment(id, first.number)?;
+                    index.remove(0);
+                } else {
+                    let mut segment = self.read_segment(id, first.number)?;
+                    let removed = segment.drain(..excess).map(|message| message.id).collect();
+                    first.search_index.remove_messages(&removed);
+                    first.len = segment.len();
+                    self.write_segment(id, first, &segment)?;
+                    excess = 0;
+                }
+            }
+        }
+
+        if let Some(max_age) = self.retention.max_age {
+            self.remove_before(id, &mut index, Utc::now() - max_age)?;
+        }
+
+        Ok(index)
+    }
+
+    // Remove all messages sent before `cutoff`, returning their IDs
+    fn remove_before(
+        &self,
+        id: &TorServiceId,
+        index: &mut Vec<SegmentInfo>,
+        cutoff: DateTime<Utc>,
+    ) -> Result<HashSet<MessageId>> {
+        // Message dates come from the sender, so expired messages can be in any segment
+        let mut removed = HashSet::new();
+        let mut kept = Vec::with_capacity(index.len());
+        for mut info in index.drain(..) {
+            if info.newest < cutoff {
+                self.delete_segment(id, info.number)?;
+                removed.extend(info.search_index.messages());
+                continue;
+            }
+            if info.oldest < cutoff {
+                let mut segment = self.read_segment(id, info.number)?;
+                let expired: HashSet<_> = segment
+                    .iter()
+                    .filter(|message| message.date < cutoff)
+                    .map(|message| message.id)
+                    .collect();
+                info.oldest = cutoff;
+                if !expired.is_empty() {
+                    segment.retain(|message| message.date >= cutoff);
+                    info.search_index.remove_messages(&expired);
+                    info.len = segment.len();
+                    removed.extend(expired);
+                    if segment.is_empty() {
+                        self.delete_segment(id, info.number)?;
+                        continue;
+                    }
+                    self.write_segment(id, &info, &segment)?;
+                }
+            }
+            kept.push(info);
+        }
+        *index = kept;
+
+        Ok(removed)
+    }
+
+    fn update_index(&mut self, id: &TorServiceId, index: Vec<SegmentInfo>) -> Result<()> {
+        let index = self.retain(id, index)?;
+        if index.is_empty() {
+            self.purge(id)
+        } else {
+            let conversation_dir = self.conversation_dir(id);
+            write_encrypted(&conversation_dir.join(INDEX_FILE), &self.key, &index)?;
+            self.indexes.insert(id.clone(), index);
+            Ok(())
+        }
+    }
+
+    fn conversation_dir(&self, id: &TorServiceId) -> PathBuf {
+        self.dir.join(id.as_str())
+    }
+
+    fn segment_path(&self, id: &TorServiceId, number: u64) -> PathBuf {
+        self.conversation_dir(id).join(format!("{:08}", number))
+    }
+
+    fn search_index_path(&self, id: &TorServiceId, number: u64) -> PathBuf {
+        self.segment_path(id, number)
+            .with_extension(SEARCH_INDEX_EXTENSION)
+    }
+
+    fn read_segment(&self, id: &TorServiceId, number: u64) -> Result<Vec<ChatMessage>> {
+        Ok(read_encrypted(&self.segment_path(id, number), &self.key)?.unwrap_or_default())
+    }
+
+    // Write a segment along with its search index
+    fn write_segment(
+        &self,
+        id: &TorServiceId,
+        info: &SegmentInfo,
+        segment: &[ChatMessage],
+    ) -> Result<()> {
+        write_encrypted(&self.segment_path(id, info.number), &self.key, &segment)?;
+        write_encrypted(
+            &self.search_index_path(id, info.number),
+            &self.key,
+            &info.search_index,
+        )
+    }
+
+    fn delete_segment(&self, id: &TorServiceId, number: u64) -> Result<()> {
+        secure_delete(&self.segment_path(id, number))?;
+        secure_delete(&self.search_index_path(id, number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tor_client_lib::TorEd25519SigningKey;
+
+    fn test_key() -> StorageKey {
+        StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([7u8; 64]), "history")
+            .unwrap()
+    }
+
+    fn add_messages(
+        store: &mut HistoryStore,
+        id: &TorServiceId,
+        count: usize,
+    ) -> Result<Vec<ChatMessage>> {
+        let me = TorServiceId::generate();
+        let mut messages = Vec::new();
+        for i in 0..count {
+            let message = ChatMessage::new(id, &me, format!("message {}", i));
+            store.append(id, &message)?;
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    #[test]
+    fn test_load_pages() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = TorServiceId::generate();
+        let mut store = HistoryStore::open(dir.path(), test_key(), RetentionPolicy::default())?;
+        let messages = add_messages(&mut store, &id, 250)?;
+
+        let store = HistoryStore::open(dir.path(), test_key(), RetentionPolicy::default())?;
+        assert_eq!(250, store.len(&id));
+        assert_eq!(messages[200..], store.load_page(&id, 0, 50)?);
+        assert_eq!(messages[80..180], store.load_page(&id, 70, 100)?);
+        assert_eq!(messages[..10], store.load_page(&id, 240, 100)?);
+        assert!(store.load_page(&id, 250, 100)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention_max_messages() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = TorServiceId::generate();
+        let retention = RetentionPolicy {
+            max_messages: Some(150),
+            max_age: None,
+        };
+        let mut store = HistoryStore::open(dir.path(), test_key(), retention)?;
+        let messages = add_messages(&mut store, &id, 220)?;
+
+        assert_eq!(150, store.len(&id));
+        assert_eq!(messages[70..], store.load_page(&id, 0, 1000)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expire() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = TorServiceId::generate();
+        let mut store = HistoryStore::open(dir.path(), test_key(), RetentionPolicy::default())?;
+        let mut messages = Vec::new();
+        for i in 0..150 {
+            let mut message = ChatMessage::new(&id, &id, format!("message {}", i));
+            message.date -= Duration::minutes(150 - i);
+            store.append(&id, &message)?;
+            messages.push(message);
+        }
+
+        let cutoff = Utc::now() - Duration::minutes(30) - Duration::seconds(30);
+        let expired = store.expire(&id, cutoff)?;
+        assert_eq!(120, expired.len());
+        assert!(messages[..120]
+            .iter()
+            .all(|message| expired.contains(&message.id)));
+        assert_eq!(messages[120..], store.load_page(&id, 0, 1000)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = TorServiceId::generate();
+        let mut store = HistoryStore::open(dir.path(), test_key(), RetentionPolicy::default())?;
+        add_messages(&mut store, &id, 120)?;
+        store.purge(&id)?;
+
+        assert!(store.is_empty(&id));
+        assert!(!dir.path().join(id.as_str()).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_key() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = TorServiceId::generate();
+        let key = StorageKey::from_passphrase("correct horse battery staple", b"0123456789abcdef")?;
+        let mut store = HistoryStore::open(dir.path(), key, RetentionPolicy::default())?;
+        add_messages(&mut store, &id, 1)?;
+
+        let wrong_key = StorageKey::from_passphrase("hunter2", b"0123456789abcdef")?;
+        assert!(HistoryStore::open(dir.path(), wrong_key, RetentionPolicy::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expire_out_of_order() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = TorServiceId::generate();
+        let mut store = HistoryStore::open(dir.path(), test_key(), RetentionPolicy::default())?;
+        // Senders pick the dates, so old messages turn up in every segment
+        let mut messages = Vec::new();
+        for i in 0..250 {
+            let mut message = ChatMessage::new(&id, &id, format!("message {}", i));
+            if i % 10 == 5 {
+                message.date -= Duration::hours(2);
+            }
+            store.append(&id, &message)?;
+            messages.push(message);
+        }
+
+        let expired = store.expire(&id, Utc::now() - Duration::hours(1))?;
+        assert_eq!(25, expired.len());
+        let (old, recent): (Vec<_>, Vec<_>) = messages
+            .into_iter()
+            .partition(|message| expired.contains(&message.id));
+        assert!(old.iter().all(|message| message.message.ends_with('5')));
+        assert_eq!(recent, store.load_page(&id, 0, 1000)?);
+
+        let store = HistoryStore::open(dir.path(), test_key(), RetentionPolicy::default())?;
+        assert_eq!(recent, store.load_page(&id, 0, 1000)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_segment_newest() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = TorServiceId::generate();
+        let mut store = HistoryStore::open(dir.path(), test_key(), RetentionPolicy::default())?;
+        let recent = add_messages(&mut store, &id, 10)?;
+        let mut old = ChatMessage::new(&id, &id, "old".to_string());
+        old.date -= Duration::hours(2);
+        store.append(&id, &old)?;
+
+        // The segment's last message is old, but the rest aren't
+        let expired = store.expire(&id, Utc::now() - Duration::hours(1))?;
+        assert_eq!(HashSet::from([old.id]), expired);
+        assert_eq!(recent, store.load_page(&id, 0, 1000)?);
+
+        // Directories which aren't conversations are skipped
+        std::fs::create_dir(dir.path().join("abc"))?;
+        let store = HistoryStore::open(dir.path(), test_key(), RetentionPolicy::default())?;
+        assert_eq!(recent, store.load_page(&id, 0, 1000)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{
+    burner,
+    contacts::Contacts,
+    control_connection::{create_onion_service, create_persistent_onion_service},
+    onion_service::{OnionService, OnionType},
+};
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use std::str::FromStr;
+use tor_client_lib::control_connection::{
+    OnionAddress, OnionServiceListener, TorControlConnection, TorSocketAddr,
+};
+use tor_client_lib::TorServiceId;
+
+/// Create a persistent onion service to use only with `contact`, so that contacts comparing
+/// notes can't tell they're talking to the same person. The identity is recorded against the
+/// contact. The returned service should be passed to `Engine::add_identity`, and the
+/// listener's connections to `Engine::handle_incoming_connection_for`. If anything fails
+/// once the service has been created, it's taken down again and its key wiped.
+///
+/// Groups, rooms, feeds and profiles would give away our main identity, so the engine won't
+/// use them with the contact once they have a pairwise identity.
+///
+/// Each identity listens on `127.0.0.1:<service_port>`, so needs a port of its own.
+pub async fn create_pairwise_identity(
+    control_connection: &mut TorControlConnection,
+    contacts: &mut Contacts,
+    contact: &TorServiceId,
+    service_port: u16,
+) -> Result<(OnionService, OnionAddress, OnionServiceListener)> {
+    if contacts.get(contact).is_none() {
+        return Err(anyhow!("Unknown contact {}", contact));
+    }
+    let name = format!(
+        "pairwise-{}",
+        hex::encode(rand::thread_rng().gen::<[u8; 8]>())
+    );
+    let listen_address = TorSocketAddr::from_str(&format!("127.0.0.1:{}", service_port))?;
+    let onion_service =
+        create_persistent_onion_service(control_connection, &name, service_port, &listen_address)
+            .await?;
+    let result = async {
+        let listener = OnionServiceListener::bind(listen_address).await?;
+        let onion_address = onion_service.onion_address(service_port)?;
+        contacts.set_identity(contact, Some(&name))?;
+        Ok((onion_address, listener))
+    }
+    .await;
+    match result {
+        Ok((onion_address, listener)) => Ok((onion_service, onion_address, listener)),
+        Err(error) => {
+            // Don't leave an identity behind which no contact uses
+            let _ = burner::burn(control_connection, &onion_service).await;
+            Err(error)
+        }
+    }
+}
+
+/// Start the pairwise identities recorded in our contacts
+pub async fn load_pairwise_identities(
+    control_connection: &mut TorControlConnection,
+    contacts: &Contacts,
+) -> Result<Vec<(OnionService, OnionAddress, OnionServiceListener)>> {
+    let mut identities = Vec::new();
+    for name in contacts
+        .iter()
+        .filter_map(|contact| contact.identity.as_ref())
+    {
+        identities.push(
+            create_onion_service(
+                control_connection,
+                OnionType::existing_persistent(name),
+                None,
+                None,
+            )
+            .await?,
+        );
+    }
+    Ok(identities)
+}
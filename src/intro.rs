@@ -0,0 +1,157 @@
+use crate::{
+    control_connection::create_onion_service,
+    invite::InviteUri,
+    onion_service::{OnionService, OnionType},
+    util::address_service_id,
+};
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use tor_client_lib::{
+    control_connection::{
+        OnionAddress, OnionServiceListener, OnionServiceStream, TorControlConnection, TorSocketAddr,
+    },
+    TorEd25519SigningKey, TorServiceId,
+};
+
+/// A transient onion service for first contact, which can be posted publicly without
+/// linking it to our permanent identity. It accepts one authenticated connection, over which
+/// both sides swap `IdentityCard`s, and is then discarded.
+///
+/// `Engine::create_intro_service` creates one, accepts its connections and takes it down
+/// once `NetworkEvent::IntroductionComplete` is sent.
+pub struct IntroService {
+    onion_service: OnionService,
+    onion_address: OnionAddress,
+    listener: OnionServiceListener,
+}
+
+impl IntroService {
+    pub async fn create(
+        control_connection: &mut TorControlConnection,
+        service_port: u16,
+    ) -> Result<Self> {
+        let (onion_service, onion_address, listener) = create_onion_service(
+            control_connection,
+            OnionType::Transient,
+            Some(service_port),
+            None,
+        )
+        .await?;
+        Ok(Self {
+            onion_service,
+            onion_address,
+            listener,
+        })
+    }
+
+    pub fn id(&self) -> &TorServiceId {
+        self.onion_service.service_id()
+    }
+
+    pub fn onion_service(&self) -> &OnionService {
+        &self.onion_service
+    }
+
+    /// Invite URI to post wherever we want people to find us
+    pub fn invite_uri(&self) -> InviteUri {
+        InviteUri::from(&self.onion_address)
+    }
+
+    pub async fn accept(&self) -> Result<(OnionServiceStream, TorSocketAddr)> {
+        Ok(self.listener.accept().await?)
+    }
+
+    /// Take the service down
+    pub async fn close(self, control_connection: &mut TorControlConnection) -> Result<()> {
+        if let Err(error) = control_connection
+            .delete_onion_service(self.id().as_str())
+            .await
+        {
+            return Err(anyhow!("Error removing intro service: {}", error));
+        }
+        Ok(())
+    }
+}
+
+/// Our permanent address, given to a peer over a one-time intro service and signed with our
+/// permanent key. It names the intro service, so it can't be passed off as coming from
+/// anywhere else.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct IdentityCard {
+    pub owner: TorServiceId,
+    /// `host:port` address to reach the owner at
+    pub address: String,
+    pub intro: TorServiceId,
+    signature: Vec<u8>,
+}
+
+impl IdentityCard {
+    pub fn new(
+        signing_key: &TorEd25519SigningKey,
+        address: &OnionAddress,
+        intro: &TorServiceId,
+    ) -> Self {
+        let mut card = Self {
+            owner: signing_key.verifying_key().into(),
+            address: address.to_string(),
+            intro: intro.clone(),
+            signature: Vec::new(),
+        };
+        card.signature = signing_key.sign(&card.signed_data()).to_bytes().to_vec();
+        card
+    }
+
+    /// Check the address belongs to the owner, and that the owner signed the card
+    pub fn verify(&self) -> Result<()> {
+        if address_service_id(&self.address).as_ref() != Some(&self.owner) {
+            return Err(anyhow!("Address {} isn't the owner's", self.address));
+        }
+        let signature_bytes: [u8; 64] = match self.signature.as_slice().try_into() {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(anyhow!("Bad signature length")),
+        };
+        self.owner.verifying_key()?.verify(
+            &self.signed_data(),
+            &Signature::from_bytes(&signature_bytes),
+        )?;
+        Ok(())
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = b"voynich-identity".to_vec();
+        data.extend_from_slice(self.owner.as_str().as_bytes());
+        data.extend_from_slice(self.intro.as_str().as_bytes());
+        data.extend_from_slice(self.address.as_bytes());
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_identity_card() -> Result<()> {
+        let signing_key = TorEd25519SigningKey::from_bytes([6u8; 64]);
+        let owner: TorServiceId = signing_key.verifying_key().into();
+        let intro = TorServiceId::generate();
+        let card = IdentityCard::new(
+            &signing_key,
+            &OnionAddress::new(owner.clone(), 3000),
+            &intro,
+        );
+        card.verify()?;
+
+        let mut tampered = card.clone();
+        tampered.intro = TorServiceId::generate();
+        assert!(tampered.verify().is_err());
+
+        let mut tampered = card.clone();
+        tampered.address = format!("{}:3000", TorServiceId::generate().onion_hostname());
+        assert!(tampered.verify().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use crate::util::parse_service_id;
+use anyhow::{anyhow, Result};
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use tor_client_lib::{control_connection::OnionAddress, TorServiceId};
+
+/// URI scheme for invites
+const SCHEME: &str = "voynich:";
+
+/// Number of bytes of the SHA-256 hash used as the checksum
+const CHECKSUM_LENGTH: usize = 4;
+
+/// How to draw a QR code in the terminal
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum QrStyle {
+    /// Unicode half-blocks, drawn for light text on a dark background
+    #[default]
+    Unicode,
+    /// ANSI background colours, which look the same whatever the terminal's colours are
+    Ansi,
+}
+
+/// An invite to connect to an onion service, shared as a URI of the form
+/// `voynich:<onion service ID>:<port>?nick=<nickname>&token=<token>&check=<checksum>`.
+///
+/// The nickname and token are optional. The checksum is the first 4 bytes of the SHA-256
+/// hash of everything before it, in hex, and catches typos when the URI is copied by hand.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InviteUri {
+    pub id: TorServiceId,
+    pub port: u16,
+    /// The nickname the sender suggests we save them under
+    pub nickname: Option<String>,
+    /// Token the sender can use to recognise who they gave this invite to
+    pub token: Option<String>,
+}
+
+impl InviteUri {
+    pub fn new(id: &TorServiceId, port: u16) -> Self {
+        Self {
+            id: id.clone(),
+            port,
+            nickname: None,
+            token: None,
+        }
+    }
+
+    pub fn with_nickname(mut self, nickname: &str) -> Self {
+        self.nickname = Some(nickname.to_string());
+        self
+    }
+
+    /// Add a new random token
+    pub fn with_one_time_token(mut self) -> Self {
+        self.token = Some(hex::encode(rand::thread_rng().gen::<[u8; 16]>()));
+        self
+    }
+
+    /// Address to connect to, as `<onion hostname>:<port>`
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.id.onion_hostname(), self.port)
+    }
+
+    /// Render the URI as a QR code, for scanning from the terminal
+    pub fn to_qr_code(&self, style: QrStyle) -> Result<String> {
+        let code = QrCode::new(self.to_string())?;
+        Ok(match style {
+            QrStyle::Unicode => code
+                .render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .build(),
+            QrStyle::Ansi => code
+                .render::<&str>()
+                .dark_color("\x1b[40m  \x1b[0m")
+                .light_color("\x1b[47m  \x1b[0m")
+                .build(),
+        })
+    }
+
+    // Everything but the checksum
+    fn body(&self) -> String {
+        let mut body = format!("{}{}:{}", SCHEME, self.id, self.port);
+        let mut separator = '?';
+        for (name, value) in [("nick", &self.nickname), ("token", &self.token)] {
+            if let Some(value) = value {
+                body.push_str(&format!("{}{}={}", separator, name, percent_encode(value)));
+                separator = '&';
+            }
+        }
+        body
+    }
+}
+
+impl From<&OnionAddress> for InviteUri {
+    fn from(address: &OnionAddress) -> Self {
+        Self::new(address.service_id(), address.service_port())
+    }
+}
+
+impl fmt::Display for InviteUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let body = self.body();
+        let separator = if body.contains('?') { '&' } else { '?' };
+        write!(f, "{}{}check={}", body, separator, checksum(&body))
+    }
+}
+
+impl FromStr for InviteUri {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if !s.starts_with(SCHEME) {
+            return Err(anyhow!("Not a voynich: URI"));
+        }
+
+        let (body, check) = match s.rsplit_once(['?', '&']) {
+            Some((body, param)) => match param.strip_prefix("check=") {
+                Some(check) => (body, check),
+                None => return Err(anyhow!("Missing checksum")),
+            },
+            None => return Err(anyhow!("Missing checksum")),
+        };
+        if !check.eq_ignore_ascii_case(&checksum(body)) {
+            return Err(anyhow!("Bad checksum, check the URI was copied correctly"));
+        }
+
+        let (address, query) = match body[SCHEME.len()..].split_once('?') {
+            Some((address, query)) => (address, Some(query)),
+            None => (&body[SCHEME.len()..], None),
+        };
+        let (id, port) = match address.rsplit_once(':') {
+            Some((id, port)) => (id, port),
+            None => return Err(anyhow!("Missing port")),
+        };
+        let mut invite = Self::new(
+            &parse_service_id(id).ok_or_else(|| anyhow!("Invalid onion service ID"))?,
+            port.parse().map_err(|_| anyhow!("Invalid port {}", port))?,
+        );
+
+        for param in query.into_iter().flat_map(|query| query.split('&')) {
+            match param.split_once('=') {
+                Some(("nick", value)) => invite.nickname = Some(percent_decode(value)?),
+                Some(("token", value)) => invite.token = Some(percent_decode(value)?),
+                // Ignore anything we don't know about, for forward compatibility
+                _ => {}
+            }
+        }
+
+        Ok(invite)
+    }
+}
+
+fn checksum(body: &str) -> String {
+    hex::encode(&Sha256::digest(body.as_bytes())[..CHECKSUM_LENGTH])
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn percent_decode(value: &str) -> Result<String> {
+    let mut bytes = Vec::new();
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [
+                iter.next().unwrap_or_default(),
+                iter.next().unwrap_or_default(),
+            ];
+            bytes.extend(hex::decode(hex).map_err(|_| anyhow!("Bad percent-encoding"))?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let id = TorServiceId::generate();
+        let invite = InviteUri::new(&id, 3000)
+            .with_nickname("Zoë & co")
+            .with_one_time_token();
+        let uri = invite.to_string();
+        assert!(uri.starts_with(&format!(
+            "voynich:{}:3000?nick=Zo%C3%AB%20%26%20co&token=",
+            id
+        )));
+        assert_eq!(invite, uri.parse::<InviteUri>()?);
+        assert_eq!(format!("{}:3000", id.onion_hostname()), invite.address());
+
+        let bare = InviteUri::new(&id, 80);
+        assert_eq!(bare, bare.to_string().parse::<InviteUri>()?);
+        assert!(!bare.to_qr_code(QrStyle::Unicode)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_uris() {
+        let id = TorServiceId::generate();
+        let uri = InviteUri::new(&id, 3000).to_string();
+
+        assert!(uri.replace(":3000", ":3001").parse::<InviteUri>().is_err());
+        assert!(uri
+            .replace("voynich:", "http:")
+            .parse::<InviteUri>()
+            .is_err());
+        assert!(format!("voynich:{}:3000", id).parse::<InviteUri>().is_err());
+        let short = "voynich:abc:3000";
+        assert!(format!("{}?check={}", short, checksum(short))
+            .parse::<InviteUri>()
+            .is_err());
+    }
+}
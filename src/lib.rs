@@ -69,12 +69,30 @@
 //! }
 //! ```
 
+/// Audit log of security-relevant events
+pub mod audit;
+
+/// Restricting who can connect to us
+pub mod authorized_keys;
+
+/// Identities which destroy themselves after a while
+pub mod burner;
+
 /// Chat message structs
 pub mod chat;
 
+/// Tor onion service client authorization
+pub mod client_auth;
+
+/// Hybrid logical clock for ordering messages
+pub mod clock;
+
 /// Configuration files
 pub mod config;
 
+/// Address book
+pub mod contacts;
+
 /// Connection to peer
 pub mod connection;
 
@@ -87,16 +105,61 @@ mod crypto;
 /// Engine
 pub mod engine;
 
+/// Exporting conversations
+pub mod export;
+
+/// Publish/subscribe feeds
+pub mod feed;
+
+/// Group chats
+pub mod group;
+
+/// Encrypted chat history
+pub mod history;
+
+/// Pairwise onion identities, one per contact
+pub mod identity;
+
+/// One-time onion services for first contact
+pub mod intro;
+
+/// Invite URIs and QR codes
+pub mod invite;
+
 /// Logging
 pub mod logger;
 
 /// Onion service struct
 pub mod onion_service;
 
+/// Store-and-forward queue for offline contacts
+pub mod outbox;
+
+/// Proof-of-work puzzles for unknown peers
+pub mod pow;
+
+/// Peer profiles
+pub mod profile;
+
+/// Limits on incoming handshakes
+pub mod rate_limit;
+
+/// Chat rooms hosted on an onion service
+pub mod room;
+
+/// Search over chat history
+pub mod search;
+
+/// Encrypted on-disk storage
+pub mod storage;
+
 /// Utility functions
 pub mod util;
 
 pub use config::get_config;
-pub use control_connection::{connect_to_tor, create_onion_service};
+pub use control_connection::{
+    connect_to_tor, create_burner_onion_service, create_onion_service,
+    create_private_onion_service, rotate_persistent_onion_service,
+};
 pub use engine::Engine;
 pub use util::test_onion_service_connection;
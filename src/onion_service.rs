@@ -49,6 +49,14 @@ impl OnionService {
         &self.name
     }
 
+    /// The same service, saved under a different name
+    pub fn with_name(&self, name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            service: self.service.clone(),
+        }
+    }
+
     pub fn ports(&self) -> &Vec<OnionServiceMapping> {
         self.service.ports()
     }
@@ -0,0 +1,246 @@
+use crate::{
+    chat::{ChatMessage, MessageId},
+    onion_service::OnionService,
+    storage::{create_storage_dir, read_encrypted, secure_delete, write_encrypted, StorageKey},
+    util::{create_onion_service_dir, parse_service_id},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use tor_client_lib::TorServiceId;
+
+/// Name of the file holding the IDs of messages we've received
+const RECEIVED_FILE: &str = "received";
+
+/// Number of received message IDs remembered for deduplication
+const RECEIVED_ID_CAPACITY: usize = 1000;
+
+/// Status of an outgoing message
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DeliveryStatus {
+    /// Peer isn't connected, message is waiting in the outbox
+    Queued,
+    /// Message has been written to the peer's connection
+    Sent,
+    /// Peer has acknowledged receipt of the message
+    Delivered,
+}
+
+/// Encrypted, on-disk queue of messages waiting to be delivered, one file per contact.
+///
+/// Messages stay in the outbox until the recipient acknowledges them, so they survive
+/// disconnects and restarts, and are redelivered in order on the next connection. The IDs of
+/// the most recent messages we've received are kept too, so that messages redelivered by a
+/// peer's outbox aren't shown twice, even after a restart.
+pub struct Outbox {
+    dir: PathBuf,
+    key: StorageKey,
+    queues: HashMap<TorServiceId, Vec<ChatMessage>>,
+    received: VecDeque<MessageId>,
+}
+
+impl Outbox {
+    /// Open (or create) an outbox in `dir`, encrypted with `key`
+    pub fn open(dir: &Path, key: StorageKey) -> Result<Self> {
+        create_storage_dir(dir)?;
+        let mut queues = HashMap::new();
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            let id = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => match parse_service_id(name) {
+                    Some(id) => id,
+                    None => continue,
+                },
+                None => continue,
+            };
+            if let Some(messages) = read_encrypted::<Vec<ChatMessage>>(&path, &key)? {
+                if !messages.is_empty() {
+                    queues.insert(id, messages);
+                }
+            }
+        }
+        let received = read_encrypted(&dir.join(RECEIVED_FILE), &key)?.unwrap_or_default();
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            key,
+            queues,
+            received,
+        })
+    }
+
+    /// Open the outbox stored under the data directory for this onion service,
+    /// encrypted with a key derived from the onion service key
+    pub fn for_onion_service(onion_service: &OnionService) -> Result<Self> {
+        let dir = create_onion_service_dir(onion_service.name())?;
+        Self::open(
+            &Path::new(&dir).join("outbox"),
+            StorageKey::for_dir(Path::new(&dir), onion_service.signing_key(), "outbox")?,
+        )
+    }
+
+    /// Add a message to the end of the recipient's queue
+    pub fn push(&mut self, message: ChatMessage) -> Result<()> {
+        let recipient = message.recipient.clone();
+        let queue = self.queues.entry(recipient.clone()).or_default();
+        if !queue.iter().any(|queued| queued.id == message.id) {
+            queue.push(message);
+        }
+        self.save(&recipient)
+    }
+
+    /// Messages waiting for `id`, oldest first
+    pub fn pending(&self, id: &TorServiceId) -> &[ChatMessage] {
+        match self.queues.get(id) {
+            Some(queue) => queue,
+            None => &[],
+        }
+    }
+
+    /// Contacts with messages waiting for them
+    pub fn recipients(&self) -> impl Iterator<Item = &TorServiceId> {
+        self.queues.keys()
+    }
+
+    /// Remove a message once it has been delivered
+    pub fn remove(
+        &mut self,
+        id: &TorServiceId,
+        message_id: &MessageId,
+    ) -> Result<Option<ChatMessage>> {
+        let removed = self.queues.get_mut(id).and_then(|queue| {
+            queue
+                .iter()
+                .position(|message| message.id == *message_id)
+                .map(|index| queue.remove(index))
+        });
+        if removed.is_some() {
+            self.save(id)?;
+        }
+        Ok(removed)
+    }
+
+    /// Drop any messages for `id` sent before `cutoff`, returning their IDs
+    pub fn expire(
+        &mut self,
+        id: &TorServiceId,
+        cutoff: DateTime<Utc>,
+    ) -> Result<HashSet<MessageId>> {
+        let expired = match self.queues.get_mut(id) {
+            Some(queue) => {
+                let expired = queue
+                    .iter()
+                    .filter(|message| message.date < cutoff)
+                    .map(|message| message.id)
+                    .collect::<HashSet<MessageId>>();
+                queue.retain(|message| message.date >= cutoff);
+                expired
+            }
+            None => HashSet::new(),
+        };
+        if !expired.is_empty() {
+            self.save(id)?;
+        }
+        Ok(expired)
+    }
+
+    /// Record that we've received the message with `message_id`. Returns false if we'd
+    /// already received it.
+    pub fn record_received(&mut self, message_id: &MessageId) -> Result<bool> {
+        if self.received.contains(message_id) {
+            return Ok(false);
+        }
+        if self.received.len() >= RECEIVED_ID_CAPACITY {
+            self.received.pop_front();
+        }
+        self.received.push_back(*message_id);
+        write_encrypted(&self.dir.join(RECEIVED_FILE), &self.key, &self.received)?;
+        Ok(true)
+    }
+
+    fn path(&self, id: &TorServiceId) -> PathBuf {
+        self.dir.join(id.as_str())
+    }
+
+    fn save(&mut self, id: &TorServiceId) -> Result<()> {
+        let path = self.path(id);
+        match self.queues.get(id) {
+            Some(queue) if !queue.is_empty() => write_encrypted(&path, &self.key, queue),
+            _ => {
+                self.queues.remove(id);
+                secure_delete(&path)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tor_client_lib::TorEd25519SigningKey;
+
+    fn test_key() -> StorageKey {
+        StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([7u8; 64]), "outbox")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_outbox_persists_in_order() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sender = TorServiceId::generate();
+        let recipient = TorServiceId::generate();
+        let first = ChatMessage::new(&sender, &recipient, "first".to_string());
+        let second = ChatMessage::new(&sender, &recipient, "second".to_string());
+
+        let mut outbox = Outbox::open(dir.path(), test_key())?;
+        outbox.push(first.clone())?;
+        outbox.push(second.clone())?;
+        outbox.push(first.clone())?;
+
+        let outbox = Outbox::open(dir.path(), test_key())?;
+        assert_eq!(vec![first, second], outbox.pending(&recipient).to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_outbox_remove() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sender = TorServiceId::generate();
+        let recipient = TorServiceId::generate();
+        let message = ChatMessage::new(&sender, &recipient, "hello".to_string());
+
+        let mut outbox = Outbox::open(dir.path(), test_key())?;
+        outbox.push(message.clone())?;
+        assert_eq!(
+            Some(message.clone()),
+            outbox.remove(&recipient, &message.id)?
+        );
+        assert_eq!(None, outbox.remove(&recipient, &message.id)?);
+        assert!(!dir.path().join(recipient.as_str()).exists());
+
+        let outbox = Outbox::open(dir.path(), test_key())?;
+        assert!(outbox.pending(&recipient).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_outbox_received() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let message_id = MessageId::generate();
+
+        let mut outbox = Outbox::open(dir.path(), test_key())?;
+        assert!(outbox.record_received(&message_id)?);
+        assert!(!outbox.record_received(&message_id)?);
+
+        let mut outbox = Outbox::open(dir.path(), test_key())?;
+        assert!(!outbox.record_received(&message_id)?);
+        assert!(outbox.record_received(&MessageId::generate())?);
+
+        Ok(())
+    }
+}
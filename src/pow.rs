@@ -0,0 +1,81 @@
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Hardest puzzle we're willing to solve, so a peer can't make us spin forever. At 24 bits
+/// that's around 16 million hashes, which takes a few seconds.
+pub const MAX_DIFFICULTY: u8 = 24;
+
+/// Hashcash-style puzzle we set peers we don't know before doing any expensive work for
+/// them. The peer has to find a number which, hashed with the challenge nonce, gives a
+/// hash starting with `difficulty` zero bits.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Challenge {
+    nonce: [u8; 16],
+    pub difficulty: u8,
+}
+
+impl Challenge {
+    pub fn new(difficulty: u8) -> Self {
+        Self {
+            nonce: rand::thread_rng().gen(),
+            difficulty: difficulty.min(MAX_DIFFICULTY),
+        }
+    }
+
+    /// Find a solution. This takes around 2^difficulty hashes, so should be run off the
+    /// async runtime.
+    pub fn solve(&self) -> Result<u64> {
+        if self.difficulty > MAX_DIFFICULTY {
+            return Err(anyhow!(
+                "Proof-of-work difficulty {} is more than the maximum of {}",
+                self.difficulty,
+                MAX_DIFFICULTY
+            ));
+        }
+        (0..=u64::MAX)
+            .find(|solution| self.verify(*solution))
+            .ok_or_else(|| anyhow!("No proof-of-work solution found"))
+    }
+
+    pub fn verify(&self, solution: u64) -> bool {
+        let mut hasher = Sha256::new();
+        hasher.update(b"voynich-pow");
+        hasher.update(self.nonce);
+        hasher.update(solution.to_be_bytes());
+        leading_zero_bits(&hasher.finalize()) >= self.difficulty as u32
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_solve() -> Result<()> {
+        let challenge = Challenge::new(12);
+        let solution = challenge.solve()?;
+        assert!(challenge.verify(solution));
+
+        // We find the first solution, so nothing before it works
+        assert!((0..solution).all(|guess| !challenge.verify(guess)));
+
+        assert_eq!(12, leading_zero_bits(&[0, 0x08, 0xff]));
+        assert_eq!(MAX_DIFFICULTY, Challenge::new(200).difficulty);
+
+        Ok(())
+    }
+}
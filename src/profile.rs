@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tor_client_lib::{TorEd25519SigningKey, TorServiceId};
+
+/// Largest avatar image we'll send or accept
+pub const MAX_AVATAR_SIZE: usize = 64 * 1024;
+
+/// Longest display name or status line we'll accept, in characters
+const MAX_TEXT_LENGTH: usize = 256;
+
+/// A small profile picture
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Avatar {
+    /// MIME type of the image, e.g. `image/png`
+    pub mime_type: String,
+    pub data: Vec<u8>,
+    hash: [u8; 32],
+}
+
+impl Avatar {
+    pub fn new(mime_type: &str, data: Vec<u8>) -> Result<Self> {
+        if data.len() > MAX_AVATAR_SIZE {
+            return Err(anyhow!(
+                "Avatar is {} bytes, the maximum is {}",
+                data.len(),
+                MAX_AVATAR_SIZE
+            ));
+        }
+        Ok(Self {
+            mime_type: mime_type.to_string(),
+            hash: Sha256::digest(&data).into(),
+            data,
+        })
+    }
+
+    /// SHA-256 hash of the image, so UIs can tell whether it's changed
+    pub fn hash(&self) -> &[u8; 32] {
+        &self.hash
+    }
+}
+
+/// What a peer tells us about themselves, signed with their onion service key
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Profile {
+    pub owner: TorServiceId,
+    /// Increases each time the profile changes, so peers know when to replace their copy
+    pub version: u64,
+    pub display_name: String,
+    pub status: String,
+    pub avatar: Option<Avatar>,
+    signature: Vec<u8>,
+}
+
+impl Profile {
+    pub fn new(
+        signing_key: &TorEd25519SigningKey,
+        display_name: &str,
+        status: &str,
+        avatar: Option<Avatar>,
+    ) -> Self {
+        let mut profile = Self {
+            owner: signing_key.verifying_key().into(),
+            version: Utc::now().timestamp_millis() as u64,
+            display_name: display_name.to_string(),
+            status: status.to_string(),
+            avatar,
+            signature: Vec::new(),
+        };
+        profile.signature = signing_key.sign(&profile.signed_data()).to_bytes().to_vec();
+        profile
+    }
+
+    /// Check the profile was signed by its owner, and isn't too big
+    pub fn verify(&self) -> Result<()> {
+        if self.display_name.chars().count() > MAX_TEXT_LENGTH
+            || self.status.chars().count() > MAX_TEXT_LENGTH
+        {
+            return Err(anyhow!("Profile text is too long"));
+        }
+        if let Some(avatar) = &self.avatar {
+            if avatar.data.len() > MAX_AVATAR_SIZE {
+                return Err(anyhow!("Avatar is too big"));
+            }
+            if Sha256::digest(&avatar.data).as_slice() != avatar.hash {
+                return Err(anyhow!("Avatar doesn't match its hash"));
+            }
+        }
+        let signature_bytes: [u8; 64] = match self.signature.as_slice().try_into() {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(anyhow!("Bad signature length")),
+        };
+        self.owner.verifying_key()?.verify(
+            &self.signed_data(),
+            &Signature::from_bytes(&signature_bytes),
+        )?;
+        Ok(())
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = b"voynich-profile".to_vec();
+        data.extend_from_slice(self.owner.as_str().as_bytes());
+        data.extend_from_slice(&self.version.to_be_bytes());
+        for text in [&self.display_name, &self.status] {
+            data.extend_from_slice(&(text.len() as u64).to_be_bytes());
+            data.extend_from_slice(text.as_bytes());
+        }
+        if let Some(avatar) = &self.avatar {
+            data.extend_from_slice(avatar.mime_type.as_bytes());
+            data.extend_from_slice(&avatar.hash);
+        }
+        data
+    }
+}
+
+/// Profile traffic, exchanged once a connection is authorized
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ProfileMessage {
+    /// The version of your profile we have, or 0 if we don't have it, so you only send it if
+    /// it's changed
+    Have(u64),
+    Profile(Box<Profile>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_profile() -> Result<()> {
+        let signing_key = TorEd25519SigningKey::from_bytes([4u8; 64]);
+        let avatar = Avatar::new("image/png", vec![1, 2, 3])?;
+        let profile = Profile::new(&signing_key, "Alice", "Around", Some(avatar));
+        profile.verify()?;
+
+        let mut tampered = profile.clone();
+        tampered.avatar.as_mut().unwrap().data = vec![4, 5, 6];
+        assert!(tampered.verify().is_err());
+
+        let mut tampered = profile.clone();
+        tampered.status = "Away".to_string();
+        assert!(tampered.verify().is_err());
+
+        assert!(Avatar::new("image/png", vec![0; MAX_AVATAR_SIZE + 1]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+use crate::{
+    onion_service::OnionService,
+    storage::{read_encrypted, write_encrypted, StorageKey},
+    util::create_onion_service_dir,
+};
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tor_client_lib::TorServiceId;
+
+/// Window over which handshake attempts are counted
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Limits on incoming handshakes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HandshakeLimits {
+    /// Handshakes in progress at once, from anyone
+    pub max_concurrent: usize,
+    /// Handshakes started per minute, from anyone
+    pub max_per_minute: usize,
+    /// Handshakes in progress at once from a single peer
+    pub max_concurrent_per_peer: usize,
+    /// Handshakes started per minute by a single peer
+    pub max_per_peer_per_minute: usize,
+}
+
+impl Default for HandshakeLimits {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 16,
+            max_per_minute: 60,
+            max_concurrent_per_peer: 2,
+            max_per_peer_per_minute: 6,
+        }
+    }
+}
+
+/// Peers whose connections we refuse, optionally stored encrypted on disk
+#[derive(Default)]
+pub struct Blocklist {
+    storage: Option<(PathBuf, StorageKey)>,
+    blocked: HashSet<TorServiceId>,
+}
+
+impl Blocklist {
+    /// A blocklist which only lasts as long as the process
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open (or create) a blocklist stored in `path`, encrypted with `key`
+    pub fn open(path: &Path, key: StorageKey) -> Result<Self> {
+        let blocked = read_encrypted(path, &key)?.unwrap_or_default();
+        Ok(Self {
+            storage: Some((path.to_path_buf(), key)),
+            blocked,
+        })
+    }
+
+    /// Open the blocklist stored under the data directory for this onion service
+    pub fn for_onion_service(onion_service: &OnionService) -> Result<Self> {
+        let dir = create_onion_service_dir(onion_service.name())?;
+        Self::open(
+            &Path::new(&dir).join("blocklist"),
+            StorageKey::for_dir(Path::new(&dir), onion_service.signing_key(), "blocklist")?,
+        )
+    }
+
+    pub fn insert(&mut self, id: &TorServiceId) -> Result<()> {
+        if self.blocked.insert(id.clone()) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &TorServiceId) -> Result<()> {
+        if self.blocked.remove(id) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, id: &TorServiceId) -> bool {
+        self.blocked.contains(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TorServiceId> {
+        self.blocked.iter()
+    }
+
+    fn save(&self) -> Result<()> {
+        match &self.storage {
+            Some((path, key)) => write_encrypted(path, key, &self.blocked),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Keeps track of incoming handshakes, and turns away blocked peers and anyone over the limits.
+///
+/// We don't know who's connecting until they send their auth message, which comes after the
+/// key exchange, so the global limits are checked as soon as the connection arrives. The
+/// blocklist is checked as soon as the peer has said who they are. The per-peer limits wait
+/// until their signature has been verified, before we sign anything ourselves, so nobody can
+/// use up someone else's limits by claiming to be them.
+#[derive(Default)]
+pub struct HandshakeLimiter {
+    limits: HandshakeLimits,
+    blocklist: Blocklist,
+    in_progress: usize,
+    in_progress_by_peer: HashMap<TorServiceId, usize>,
+    attempts: VecDeque<Instant>,
+    attempts_by_peer: HashMap<TorServiceId, VecDeque<Instant>>,
+}
+
+impl HandshakeLimiter {
+    pub fn new(limits: HandshakeLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: HandshakeLimits) {
+        self.limits = limits;
+    }
+
+    pub fn set_blocklist(&mut self, blocklist: Blocklist) {
+        self.blocklist = blocklist;
+    }
+
+    pub fn block(&mut self, id: &TorServiceId) -> Result<()> {
+        self.blocklist.insert(id)
+    }
+
+    pub fn unblock(&mut self, id: &TorServiceId) -> Result<()> {
+        self.blocklist.remove(id)
+    }
+
+    pub fn is_blocked(&self, id: &TorServiceId) -> bool {
+        self.blocklist.contains(id)
+    }
+
+    pub fn blocklist(&self) -> impl Iterator<Item = &TorServiceId> {
+        self.blocklist.iter()
+    }
+
+    /// Start a handshake, if we're under the global limits. The handshake lasts as long as
+    /// the returned permit.
+    pub fn start(limiter: &Arc<Mutex<Self>>) -> Result<HandshakePermit> {
+        limiter.lock().unwrap().start_at(Instant::now())?;
+        Ok(HandshakePermit {
+            limiter: limiter.clone(),
+            peer: None,
+        })
+    }
+
+    fn start_at(&mut self, now: Instant) -> Result<()> {
+        prune(&mut self.attempts, now);
+        if self.in_progress >= self.limits.max_concurrent {
+            return Err(anyhow!("Too many handshakes in progress"));
+        }
+        if self.attempts.len() >= self.limits.max_per_minute {
+            return Err(anyhow!("Too many handshakes in the last minute"));
+        }
+        self.attempts.push_back(now);
+        self.in_progress += 1;
+        Ok(())
+    }
+
+    fn check_blocked(&self, peer: &TorServiceId) -> Result<()> {
+        if self.blocklist.contains(peer) {
+            return Err(anyhow!("{} is blocked", peer));
+        }
+        Ok(())
+    }
+
+    fn verified_at(&mut self, peer: &TorServiceId, now: Instant) -> Result<()> {
+        self.check_blocked(peer)?;
+        let attempts = self.attempts_by_peer.entry(peer.clone()).or_default();
+        prune(attempts, now);
+        if attempts.len() >= self.limits.max_per_peer_per_minute {
+            return Err(anyhow!(
+                "Too many handshakes from {} in the last minute",
+                peer
+            ));
+        }
+        let in_progress = self.in_progress_by_peer.entry(peer.clone()).or_default();
+        if *in_progress >= self.limits.max_concurrent_per_peer {
+            return Err(anyhow!("Too many handshakes in progress from {}", peer));
+        }
+        attempts.push_back(now);
+        *in_progress += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self, peer: Option<&TorServiceId>) {
+        self.in_progress = self.in_progress.saturating_sub(1);
+        if let Some(peer) = peer {
+            if let Some(in_progress) = self.in_progress_by_peer.get_mut(peer) {
+                *in_progress = in_progress.saturating_sub(1);
+                if *in_progress == 0 {
+                    self.in_progress_by_peer.remove(peer);
+                }
+            }
+        }
+        let now = Instant::now();
+        self.attempts_by_peer.retain(|_, attempts| {
+            prune(attempts, now);
+            !attempts.is_empty()
+        });
+    }
+}
+
+// Drop attempts which are outside the rate window
+fn prune(attempts: &mut VecDeque<Instant>, now: Instant) {
+    while attempts
+        .front()
+        .is_some_and(|attempt| now.duration_since(*attempt) >= RATE_WINDOW)
+    {
+        attempts.pop_front();
+    }
+}
+
+/// An incoming handshake in progress, which is counted against the limits until dropped
+pub struct HandshakePermit {
+    limiter: Arc<Mutex<HandshakeLimiter>>,
+    peer: Option<TorServiceId>,
+}
+
+impl HandshakePermit {
+    /// The peer has told us who they are, but hasn't proved it yet. Fails if they're blocked.
+    pub fn identify(&self, peer: &TorServiceId) -> Result<()> {
+        self.limiter.lock().unwrap().check_blocked(peer)
+    }
+
+    /// The peer has proved who they are. Fails if they're blocked or over their limits.
+    pub fn verified(&mut self, peer: &TorServiceId) -> Result<()> {
+        self.limiter
+            .lock()
+            .unwrap()
+            .verified_at(peer, Instant::now())?;
+        self.peer = Some(peer.clone());
+        Ok(())
+    }
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        if let Ok(mut limiter) = self.limiter.lock() {
+            limiter.finish(self.peer.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tor_client_lib::TorEd25519SigningKey;
+
+    #[test]
+    fn test_global_limits() {
+        let mut limiter = HandshakeLimiter::new(HandshakeLimits {
+            max_concurrent: 2,
+            max_per_minute: 3,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert!(limiter.start_at(now).is_ok());
+        assert!(limiter.start_at(now).is_ok());
+        assert!(limiter.start_at(now).is_err());
+
+        limiter.finish(None);
+        assert!(limiter.start_at(now).is_ok());
+        limiter.finish(None);
+        assert!(limiter.start_at(now).is_err());
+
+        // Once the earlier attempts are over a minute old, we accept more
+        assert!(limiter.start_at(now + RATE_WINDOW).is_ok());
+    }
+
+    #[test]
+    fn test_peer_limits() -> Result<()> {
+        let mut limiter = HandshakeLimiter::new(HandshakeLimits {
+            max_concurrent_per_peer: 1,
+            max_per_peer_per_minute: 2,
+            ..Default::default()
+        });
+        let alice = TorServiceId::generate();
+        let bob = TorServiceId::generate();
+        let now = Instant::now();
+
+        assert!(limiter.verified_at(&alice, now).is_ok());
+        assert!(limiter.verified_at(&alice, now).is_err());
+        assert!(limiter.verified_at(&bob, now).is_ok());
+        limiter.finish(Some(&alice));
+        assert!(limiter.verified_at(&alice, now).is_ok());
+        limiter.finish(Some(&alice));
+        assert!(limiter.verified_at(&alice, now).is_err());
+
+        limiter.block(&bob)?;
+        limiter.finish(Some(&bob));
+        assert!(limiter.verified_at(&bob, now).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocklist_storage() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("blocklist");
+        let key = || {
+            StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([7u8; 64]), "blocklist")
+        };
+        let alice = TorServiceId::generate();
+        let bob = TorServiceId::generate();
+
+        let mut blocklist = Blocklist::open(&path, key()?)?;
+        blocklist.insert(&alice)?;
+        blocklist.insert(&bob)?;
+        blocklist.remove(&bob)?;
+
+        let blocklist = Blocklist::open(&path, key()?)?;
+        assert!(blocklist.contains(&alice));
+        assert!(!blocklist.contains(&bob));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unverified_claims() -> Result<()> {
+        let limiter = Arc::new(Mutex::new(HandshakeLimiter::new(HandshakeLimits {
+            max_per_peer_per_minute: 1,
+            ..Default::default()
+        })));
+        let alice = TorServiceId::generate();
+
+        // Claiming to be alice doesn't use up her limits
+        for _ in 0..3 {
+            let permit = HandshakeLimiter::start(&limiter)?;
+            permit.identify(&alice)?;
+        }
+        let mut permit = HandshakeLimiter::start(&limiter)?;
+        permit.verified(&alice)?;
+        drop(permit);
+        let mut permit = HandshakeLimiter::start(&limiter)?;
+        assert!(permit.verified(&alice).is_err());
+
+        limiter.lock().unwrap().block(&alice)?;
+        assert!(permit.identify(&alice).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+use crate::chat::ChatMessage;
+use anyhow::{anyhow, Result};
+use circular_queue::CircularQueue;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use tor_client_lib::TorServiceId;
+
+/// Number of messages a room keeps to replay to members who join later
+const ROOM_HISTORY_CAPACITY: usize = 100;
+
+/// Unique identifier for a hosted room
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub struct RoomId([u8; 16]);
+
+impl RoomId {
+    pub fn generate() -> Self {
+        Self(rand::thread_rng().gen())
+    }
+}
+
+impl fmt::Display for RoomId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ModerationAction {
+    /// Allow someone to join an invite-only room
+    Invite(TorServiceId),
+    /// Remove someone from the room. They can join again.
+    Kick(TorServiceId),
+    /// Remove someone from the room, and stop them from joining again
+    Ban(TorServiceId),
+    Unban(TorServiceId),
+    /// Stop someone from posting
+    Mute(TorServiceId),
+    Unmute(TorServiceId),
+    /// Make someone a moderator. Only the owner can do this.
+    Promote(TorServiceId),
+    Demote(TorServiceId),
+}
+
+impl ModerationAction {
+    pub fn target(&self) -> &TorServiceId {
+        match self {
+            Self::Invite(id)
+            | Self::Kick(id)
+            | Self::Ban(id)
+            | Self::Unban(id)
+            | Self::Mute(id)
+            | Self::Unmute(id)
+            | Self::Promote(id)
+            | Self::Demote(id) => id,
+        }
+    }
+}
+
+/// Requests sent by a room member to the room's host
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum RoomRequest {
+    Join(RoomId),
+    Leave(RoomId),
+    Post {
+        room_id: RoomId,
+        message: Box<ChatMessage>,
+    },
+    Moderate {
+        room_id: RoomId,
+        action: ModerationAction,
+    },
+}
+
+impl RoomRequest {
+    pub fn room_id(&self) -> &RoomId {
+        match self {
+            Self::Join(room_id) | Self::Leave(room_id) => room_id,
+            Self::Post { room_id, .. } | Self::Moderate { room_id, .. } => room_id,
+        }
+    }
+}
+
+/// Updates sent by a room's host to its members
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum RoomUpdate {
+    /// We've joined the room. Includes the current members and recent messages.
+    Joined {
+        room_id: RoomId,
+        name: String,
+        members: Vec<TorServiceId>,
+        history: Vec<ChatMessage>,
+    },
+    /// We've been invited to join the room
+    Invited { room_id: RoomId, name: String },
+    Message {
+        room_id: RoomId,
+        message: Box<ChatMessage>,
+    },
+    MemberJoined {
+        room_id: RoomId,
+        member: TorServiceId,
+    },
+    MemberLeft {
+        room_id: RoomId,
+        member: TorServiceId,
+    },
+    Moderated {
+        room_id: RoomId,
+        by: TorServiceId,
+        action: ModerationAction,
+    },
+    /// A request we made was refused
+    Denied { room_id: RoomId, reason: String },
+    /// The host has closed the room
+    Closed(RoomId),
+}
+
+/// Room traffic between a member and the host
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum RoomMessage {
+    Request(RoomRequest),
+    Update(RoomUpdate),
+}
+
+/// A chat room hosted on our onion service. Members connect to us, and we relay their
+/// messages to everyone else in the room, so they don't need to connect to each other.
+///
+/// Note that the host sees everything posted to the room.
+#[derive(Clone, Debug)]
+pub struct Room {
+    id: RoomId,
+    name: String,
+    owner: TorServiceId,
+    invite_only: bool,
+    members: BTreeSet<TorServiceId>,
+    moderators: HashSet<TorServiceId>,
+    invited: HashSet<TorServiceId>,
+    banned: HashSet<TorServiceId>,
+    muted: HashSet<TorServiceId>,
+    history: CircularQueue<ChatMessage>,
+}
+
+impl Room {
+    pub fn new(owner: &TorServiceId, name: &str, invite_only: bool) -> Self {
+        Self {
+            id: RoomId::generate(),
+            name: name.to_string(),
+            owner: owner.clone(),
+            invite_only,
+            members: BTreeSet::from([owner.clone()]),
+            moderators: HashSet::new(),
+            invited: HashSet::new(),
+            banned: HashSet::new(),
+            muted: HashSet::new(),
+            history: CircularQueue::with_capacity(ROOM_HISTORY_CAPACITY),
+        }
+    }
+
+    pub fn id(&self) -> &RoomId {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn owner(&self) -> &TorServiceId {
+        &self.owner
+    }
+
+    pub fn invite_only(&self) -> bool {
+        self.invite_only
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &TorServiceId> {
+        self.members.iter()
+    }
+
+    pub fn is_member(&self, id: &TorServiceId) -> bool {
+        self.members.contains(id)
+    }
+
+    pub fn is_moderator(&self, id: &TorServiceId) -> bool {
+        *id == self.owner || self.moderators.contains(id)
+    }
+
+    pub fn is_banned(&self, id: &TorServiceId) -> bool {
+        self.banned.contains(id)
+    }
+
+    pub fn is_muted(&self, id: &TorServiceId) -> bool {
+        self.muted.contains(id)
+    }
+
+    /// Recent messages, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &ChatMessage> {
+        self.history.asc_iter()
+    }
+
+    pub fn join(&mut self, member: &TorServiceId) -> Result<()> {
+        if self.banned.contains(member) {
+            return Err(anyhow!("You are banned from {}", self.name));
+        }
+        if self.invite_only && !self.invited.contains(member) && !self.is_moderator(member) {
+            return Err(anyhow!("{} is invite-only", self.name));
+        }
+        self.members.insert(member.clone());
+        Ok(())
+    }
+
+    /// Returns whether they were a member
+    pub fn leave(&mut self, member: &TorServiceId) -> bool {
+        *member != self.owner && self.members.remove(member)
+    }
+
+    pub fn post(&mut self, message: ChatMessage) -> Result<()> {
+        if !self.members.contains(&message.sender) {
+            return Err(anyhow!("You aren't a member of {}", self.name));
+        }
+        if self.muted.contains(&message.sender) {
+            return Err(anyhow!("You are muted in {}", self.name));
+        }
+        self.history.push(message);
+        Ok(())
+    }
+
+    pub fn moderate(&mut self, by: &TorServiceId, action: &ModerationAction) -> Result<()> {
+        let target = action.target();
+        let allowed = match action {
+            ModerationAction::Promote(_) | ModerationAction::Demote(_) => *by == self.owner,
+            // Moderators can't act against each other, only the owner can
+            _ => self.is_moderator(by) && (*by == self.owner || !self.is_moderator(target)),
+        };
+        if !allowed || *target == self.owner {
+            return Err(anyhow!("Not allowed to do that in {}", self.name));
+        }
+        match action {
+            ModerationAction::Invite(id) => {
+                self.invited.insert(id.clone());
+            }
+            ModerationAction::Kick(id) => {
+                self.members.remove(id);
+                self.invited.remove(id);
+            }
+            ModerationAction::Ban(id) => {
+                self.members.remove(id);
+                self.invited.remove(id);
+                self.banned.insert(id.clone());
+            }
+            ModerationAction::Unban(id) => {
+                self.banned.remove(id);
+            }
+            ModerationAction::Mute(id) => {
+                self.muted.insert(id.clone());
+            }
+            ModerationAction::Unmute(id) => {
+                self.muted.remove(id);
+            }
+            ModerationAction::Promote(id) => {
+                self.moderators.insert(id.clone());
+            }
+            ModerationAction::Demote(id) => {
+                self.moderators.remove(id);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_invite_only() -> Result<()> {
+        let owner = TorServiceId::generate();
+        let alice = TorServiceId::generate();
+        let bob = TorServiceId::generate();
+        let mut room = Room::new(&owner, "Secret", true);
+
+        assert!(room.join(&alice).is_err());
+        room.moderate(&owner, &ModerationAction::Invite(alice.clone()))?;
+        room.join(&alice)?;
+
+        // Ordinary members can't invite
+        assert!(room
+            .moderate(&alice, &ModerationAction::Invite(bob.clone()))
+            .is_err());
+        room.moderate(&owner, &ModerationAction::Promote(alice.clone()))?;
+        room.moderate(&alice, &ModerationAction::Invite(bob.clone()))?;
+        room.join(&bob)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_moderation() -> Result<()> {
+        let owner = TorServiceId::generate();
+        let moderator = TorServiceId::generate();
+        let alice = TorServiceId::generate();
+        let mut room = Room::new(&owner, "Lobby", false);
+        room.join(&moderator)?;
+        room.join(&alice)?;
+        room.moderate(&owner, &ModerationAction::Promote(moderator.clone()))?;
+
+        room.moderate(&moderator, &ModerationAction::Mute(alice.clone()))?;
+        assert!(room
+            .post(ChatMessage::new(&alice, &owner, "Hello".to_string()))
+            .is_err());
+        room.moderate(&moderator, &ModerationAction::Unmute(alice.clone()))?;
+        room.post(ChatMessage::new(&alice, &owner, "Hello".to_string()))?;
+        assert_eq!(1, room.history().count());
+
+        room.moderate(&moderator, &ModerationAction::Ban(alice.clone()))?;
+        assert!(!room.is_member(&alice));
+        assert!(room.join(&alice).is_err());
+
+        // Moderators can't act against the owner or each other
+        assert!(room
+            .moderate(&moderator, &ModerationAction::Kick(owner.clone()))
+            .is_err());
+        assert!(room
+            .moderate(&moderator, &ModerationAction::Kick(moderator.clone()))
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use crate::chat::{ChatMessage, MessageId};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use tor_client_lib::TorServiceId;
+
+/// Number of characters of context shown on either side of a match in a snippet
+const SNIPPET_CONTEXT: usize = 30;
+
+/// Search criteria for stored history. Empty fields match everything.
+#[derive(Clone, Debug, Default)]
+pub struct SearchQuery {
+    /// Only search the conversation with this contact
+    pub contact: Option<TorServiceId>,
+    /// Only match messages sent at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only match messages sent at or before this time
+    pub to: Option<DateTime<Utc>>,
+    /// Words which must all appear in the message; each word matches as a prefix
+    pub text: Option<String>,
+    /// Maximum number of results to return
+    pub limit: Option<usize>,
+}
+
+impl SearchQuery {
+    pub(crate) fn matches_date(&self, date: &DateTime<Utc>) -> bool {
+        self.from.is_none_or(|from| *date >= from) && self.to.is_none_or(|to| *date <= to)
+    }
+}
+
+/// Reference to a stored message matching a search
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SearchResult {
+    /// Conversation the message belongs to
+    pub contact: TorServiceId,
+    pub message_id: MessageId,
+    pub sender: TorServiceId,
+    pub date: DateTime<Utc>,
+    /// Part of the message around the first match
+    pub snippet: String,
+}
+
+impl SearchResult {
+    pub(crate) fn new(contact: &TorServiceId, message: &ChatMessage, terms: &[String]) -> Self {
+        Self {
+            contact: contact.clone(),
+            message_id: message.id,
+            sender: message.sender.clone(),
+            date: message.date,
+            snippet: snippet(&message.message, terms),
+        }
+    }
+}
+
+/// Inverted index of the words in one history segment, mapping each word to the messages
+/// containing it. Each segment has its own index, so adding a message only rewrites the index
+/// of the segment it's stored in.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct SearchIndex {
+    messages: HashSet<MessageId>,
+    terms: BTreeMap<String, HashSet<MessageId>>,
+}
+
+impl SearchIndex {
+    pub(crate) fn add(&mut self, message: &ChatMessage) {
+        for term in tokenize(&message.message) {
+            self.terms.entry(term).or_default().insert(message.id);
+        }
+        self.messages.insert(message.id);
+    }
+
+    pub(crate) fn remove_messages(&mut self, ids: &HashSet<MessageId>) {
+        if ids.is_empty() {
+            return;
+        }
+        self.messages.retain(|id| !ids.contains(id));
+        self.terms.retain(|_, messages| {
+            messages.retain(|id| !ids.contains(id));
+            !messages.is_empty()
+        });
+    }
+
+    /// IDs of all the messages in the segment
+    pub(crate) fn messages(&self) -> &HashSet<MessageId> {
+        &self.messages
+    }
+
+    /// Messages containing all of `terms` (as prefixes)
+    pub(crate) fn candidates(&self, terms: &[String]) -> HashSet<MessageId> {
+        let mut matching: Option<HashSet<MessageId>> = None;
+        for term in terms {
+            let ids = self
+                .terms
+                .range(term.clone()..)
+                .take_while(|(indexed, _)| indexed.starts_with(term.as_str()))
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect::<HashSet<MessageId>>();
+            matching = Some(match matching {
+                Some(matching) => matching.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+        matching.unwrap_or_default()
+    }
+}
+
+/// Split text into lowercase words
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        if !word.is_empty() {
+            let term = word.to_lowercase();
+            if !terms.contains(&term) {
+                terms.push(term);
+            }
+        }
+    }
+    terms
+}
+
+fn snippet(text: &str, terms: &[String]) -> String {
+    // Lowercasing can change the length of characters, so remember where each lowercased
+    // character came from in the original text
+    let mut lowercase = String::new();
+    let mut offsets = Vec::new();
+    for (index, c) in text.char_indices() {
+        for lower in c.to_lowercase() {
+            offsets.push((lowercase.len(), index));
+            lowercase.push(lower);
+        }
+    }
+    let position = terms
+        .iter()
+        .filter_map(|term| lowercase.find(term.as_str()))
+        .min()
+        .map_or(0, |found| {
+            offsets[offsets.partition_point(|(lower, _)| *lower <= found) - 1].1
+        });
+
+    let start = text[..position]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT - 1)
+        .map_or(0, |(index, _)| index);
+    let end = text[position..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT * 2)
+        .map_or(text.len(), |(index, _)| position + index);
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(&text[start..end]);
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{HistoryStore, RetentionPolicy};
+    use crate::storage::StorageKey;
+    use anyhow::Result;
+    use chrono::Duration;
+    use tor_client_lib::TorEd25519SigningKey;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            vec!["meet", "at", "the", "café", "7pm"],
+            tokenize("Meet at the Café, at 7pm!")
+        );
+    }
+
+    #[test]
+    fn test_snippet() {
+        let text =
+            "The quick brown fox jumped over the lazy dog and then ran all the way home to bed";
+        assert_eq!(
+            "…ick brown fox jumped over the lazy dog and then ran all the way home to bed",
+            snippet(text, &["lazy".to_string()])
+        );
+        assert_eq!("Short message", snippet("Short message", &[]));
+        assert_eq!("ẞİ", snippet("ẞİ", &["i".to_string()]));
+        assert_eq!(
+            "…İİİİİİİİİİİİİİİİİİİİİİİİİİİİİ dog",
+            snippet(&format!("{} dog", "İ".repeat(40)), &["dog".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_search() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key =
+            StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([7u8; 64]), "history")?;
+        let mut store = HistoryStore::open(dir.path(), key, RetentionPolicy::default())?;
+        let me = TorServiceId::generate();
+        let alice = TorServiceId::generate();
+        let bob = TorServiceId::generate();
+
+        let mut old = ChatMessage::new(&alice, &me, "Dinner tomorrow?".to_string());
+        old.date -= Duration::days(10);
+        store.append(&alice, &old)?;
+        let recent = ChatMessage::new(&alice, &me, "Dinner tonight at eight".to_string());
+        store.append(&alice, &recent)?;
+        store.append(
+            &bob,
+            &ChatMessage::new(&bob, &me, "Dinner was great".to_string()),
+        )?;
+
+        let results = store.search(&SearchQuery {
+            text: Some("dinn".to_string()),
+            ..Default::default()
+        })?;
+        assert_eq!(3, results.len());
+
+        let key =
+            StorageKey::from_signing_key(&TorEd25519SigningKey::from_bytes([7u8; 64]), "history")?;
+        let store = HistoryStore::open(dir.path(), key, RetentionPolicy::default())?;
+        let results = store.search(&SearchQuery {
+            text: Some("tonight".to_string()),
+            ..Default::default()
+        })?;
+        assert_eq!(1, results.len());
+
+        let results = store.search(&SearchQuery {
+            contact: Some(alice.clone()),
+            text: Some("DINNER tonight".to_string()),
+            ..Default::default()
+        })?;
+        assert_eq!(1, results.len());
+        assert_eq!(recent.id, results[0].message_id);
+        assert_eq!("Dinner tonight at eight", results[0].snippet);
+
+        let results = store.search(&SearchQuery {
+            contact: Some(alice.clone()),
+            to: Some(Utc::now() - Duration::days(1)),
+            ..Default::default()
+        })?;
+        assert_eq!(
+            vec![old.id],
+            results.iter().map(|r| r.message_id).collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+}
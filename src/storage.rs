@@ -0,0 +1,240 @@
+use crate::crypto::Cryptor;
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use chacha20poly1305::Key as SymmetricKey;
+use ed25519_dalek::pkcs8::spki::der::zeroize::Zeroize;
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+use std::fs::{
+    create_dir_all, read, remove_file, rename, set_permissions, write, File, OpenOptions,
+    Permissions,
+};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tor_client_lib::TorEd25519SigningKey;
+
+/// Salt used when deriving storage keys from the onion service key
+const STORAGE_KEY_SALT: &[u8] = b"voynich-storage";
+
+/// Size of the random salt used when deriving storage keys from a passphrase
+const PASSPHRASE_SALT_SIZE: usize = 16;
+
+/// File holding the secret that storage keys for an onion service's data are derived from
+const STORAGE_SECRET_FILE: &str = "storage_secret";
+
+/// Size of the storage secret
+const STORAGE_SECRET_SIZE: usize = 32;
+
+/// Symmetric key used to encrypt data at rest
+#[derive(Clone)]
+pub struct StorageKey {
+    cryptor: Cryptor,
+}
+
+impl StorageKey {
+    /// Derive a storage key from the onion service signing key. The `purpose` string keeps
+    /// keys for different stores (outbox, history, etc) independent of each other.
+    pub fn from_signing_key(signing_key: &TorEd25519SigningKey, purpose: &str) -> Result<Self> {
+        let mut secret = storage_secret(signing_key);
+        let key = Self::from_storage_secret(&secret, purpose);
+        secret.zeroize();
+        key
+    }
+
+    /// Derive a storage key for the data kept in `dir`, from a secret stored alongside it.
+    /// The secret is made from `signing_key` the first time, so the keys are the same as
+    /// `from_signing_key` gives, but it stays put when the onion service key is rotated, so
+    /// the data can still be read afterwards.
+    pub fn for_dir(dir: &Path, signing_key: &TorEd25519SigningKey, purpose: &str) -> Result<Self> {
+        let mut secret = read_or_create_storage_secret(dir, signing_key)?;
+        let key = Self::from_storage_secret(&secret, purpose);
+        secret.zeroize();
+        key
+    }
+
+    fn from_storage_secret(secret: &[u8], purpose: &str) -> Result<Self> {
+        let hkdf = match Hkdf::<Sha256>::from_prk(secret) {
+            Ok(hkdf) => hkdf,
+            Err(_) => return Err(anyhow!("Invalid storage secret length")),
+        };
+        let mut output = [0u8; 32];
+        if let Err(hkdf::InvalidLength) = hkdf.expand(purpose.as_bytes(), &mut output) {
+            return Err(anyhow!("Invalid length"));
+        }
+        let key: SymmetricKey = output.into();
+        output.zeroize();
+
+        Ok(Self {
+            cryptor: Cryptor::new(&key),
+        })
+    }
+
+    /// Derive a storage key from a passphrase, using Argon2
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Result<Self> {
+        let mut output = [0u8; 32];
+        if let Err(error) =
+            Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut output)
+        {
+            return Err(anyhow!("Error deriving key from passphrase: {}", error));
+        }
+        let key: SymmetricKey = output.into();
+        output.zeroize();
+
+        Ok(Self {
+            cryptor: Cryptor::new(&key),
+        })
+    }
+
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.cryptor.encrypt(plaintext)
+    }
+
+    pub(crate) fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        self.cryptor.decrypt(ciphertext)
+    }
+}
+
+/// Create a directory (and its parents) readable only by the owner
+pub(crate) fn create_storage_dir(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        if !dir.is_dir() {
+            return Err(anyhow!("{} is not a directory", dir.display()));
+        }
+    } else {
+        create_dir_all(dir)?;
+        set_permissions(dir, Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+// The pseudorandom key HKDF extracts from the signing key. The signing key can't be
+// recovered from it.
+fn storage_secret(signing_key: &TorEd25519SigningKey) -> [u8; STORAGE_SECRET_SIZE] {
+    let mut key_bytes = signing_key.to_bytes();
+    let (prk, _) = Hkdf::<Sha256>::extract(Some(STORAGE_KEY_SALT), &key_bytes);
+    key_bytes.zeroize();
+    prk.into()
+}
+
+/// Read the storage secret kept in `dir`, making one from `signing_key` if there isn't one.
+/// Call this before rotating the key, so that data written under the old key stays readable.
+pub(crate) fn read_or_create_storage_secret(
+    dir: &Path,
+    signing_key: &TorEd25519SigningKey,
+) -> Result<[u8; STORAGE_SECRET_SIZE]> {
+    let path = dir.join(STORAGE_SECRET_FILE);
+    match read(&path) {
+        Ok(mut data) => {
+            let secret = data.as_slice().try_into();
+            data.zeroize();
+            match secret {
+                Ok(secret) => Ok(secret),
+                Err(_) => Err(anyhow!("Bad storage secret length in {}", path.display())),
+            }
+        }
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            let secret = storage_secret(signing_key);
+            write_atomically(&path, &secret)?;
+            Ok(secret)
+        }
+        Err(error) => Err(error)?,
+    }
+}
+
+/// Read the passphrase salt stored in `dir`, generating and saving a new one if there isn't one
+pub(crate) fn read_or_create_salt(dir: &Path) -> Result<Vec<u8>> {
+    let path = dir.join("salt");
+    match read(&path) {
+        Ok(salt) if salt.len() == PASSPHRASE_SALT_SIZE => Ok(salt),
+        Ok(_) => Err(anyhow!("Bad salt length in {}", path.display())),
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            let mut salt = vec![0u8; PASSPHRASE_SALT_SIZE];
+            rand::thread_rng().fill_bytes(&mut salt);
+            write(&path, &salt)?;
+            set_permissions(&path, Permissions::from_mode(0o600))?;
+            Ok(salt)
+        }
+        Err(error) => Err(error)?,
+    }
+}
+
+/// Read and decrypt a CBOR-encoded value from `path`. Returns `None` if the file doesn't exist.
+pub(crate) fn read_encrypted<D: DeserializeOwned>(
+    path: &Path,
+    key: &StorageKey,
+) -> Result<Option<D>> {
+    let ciphertext = match read(path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => Err(error)?,
+    };
+    let mode = path.metadata()?.permissions().mode();
+    if mode & 0o777 != 0o600 {
+        return Err(anyhow!(
+            "Permissions on {} are too permissive!",
+            path.display()
+        ));
+    }
+    if ciphertext.len() < crate::crypto::NONCE_SIZE {
+        return Err(anyhow!("{} is truncated", path.display()));
+    }
+    let mut plaintext = key.decrypt(&ciphertext)?;
+    let value = serde_cbor::from_slice(&plaintext);
+    plaintext.zeroize();
+
+    Ok(Some(value?))
+}
+
+/// Encrypt and write a CBOR-encoded value to `path`. The data is written to a temporary file
+/// and synced to disk before it's renamed into place, so a crash or power loss never leaves
+/// a half-written file behind.
+pub(crate) fn write_encrypted<S: Serialize>(
+    path: &Path,
+    key: &StorageKey,
+    value: &S,
+) -> Result<()> {
+    let mut plaintext = serde_cbor::to_vec(value)?;
+    let ciphertext = key.encrypt(&plaintext);
+    plaintext.zeroize();
+    let ciphertext = ciphertext?;
+
+    write_atomically(path, &ciphertext)
+}
+
+// Write `data` to a temporary file readable only by the owner, sync it, and rename it over
+// `path`. The directory is synced too, so the rename itself survives a power loss.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    set_permissions(&tmp_path, Permissions::from_mode(0o600))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    rename(&tmp_path, path)?;
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+/// Overwrite a file with random data before removing it. Missing files are ignored.
+pub(crate) fn secure_delete(path: &Path) -> Result<()> {
+    let len = match path.metadata() {
+        Ok(metadata) => metadata.len() as usize,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(error) => Err(error)?,
+    };
+    let mut noise = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut noise);
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&noise)?;
+    file.sync_all()?;
+    remove_file(path)?;
+
+    Ok(())
+}
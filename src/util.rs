@@ -14,9 +14,12 @@ use tor_client_lib::{
         OnionAddress, OnionService as TorClientOnionService, OnionServiceListener,
         OnionServiceMapping, TorSocketAddr,
     },
-    TorEd25519SigningKey,
+    TorEd25519SigningKey, TorServiceId,
 };
 
+/// Length of a v3 onion service ID
+const SERVICE_ID_LENGTH: usize = 56;
+
 lazy_static! {
     pub static ref HOME: String = match env::var("HOME") {
         Ok(value) => value,
@@ -150,7 +153,7 @@ fn get_onion_service_dir(name: &str) -> Result<String> {
     Ok(onion_service_dir)
 }
 
-fn create_onion_service_dir(name: &str) -> Result<String> {
+pub(crate) fn create_onion_service_dir(name: &str) -> Result<String> {
     create_secure_dir(&DATA_DIR)?;
     let path_string = format!("{}/{}", *DATA_DIR, name);
     create_secure_dir(&path_string)?;
@@ -202,3 +205,21 @@ pub async fn test_onion_service_connection(
 
     Ok(handle.await??)
 }
+
+/// Parse an onion service ID, returning `None` if it isn't valid
+pub(crate) fn parse_service_id(id: &str) -> Option<TorServiceId> {
+    // Check the length first, TorServiceId::from_str assumes it's right
+    if id.len() != SERVICE_ID_LENGTH {
+        return None;
+    }
+    TorServiceId::from_str(id).ok()
+}
+
+/// The service ID of a `host.onion:port` address
+pub(crate) fn address_service_id(address: &str) -> Option<TorServiceId> {
+    address
+        .split(':')
+        .next()
+        .and_then(|host| host.strip_suffix(".onion"))
+        .and_then(parse_service_id)
+}